@@ -1,14 +1,18 @@
 use std::fs::File;
 use std::sync::mpsc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::thread;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::os::unix::prelude::*;
 use std::process as pr;
 use std::io;
 use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::env;
+use std::mem;
+use std::ptr;
+use std::time::Instant;
+
+use libc;
 
 use failure::Error;
 use os_pipe;
@@ -25,6 +29,9 @@ use protocol::{
     Ids,
     GenericPipe,
     PipeMessage,
+    ResourceUsage,
+    FailedRequest,
+    RequestError,
 };
 
 use machine::{Machine, Task, Status};
@@ -36,7 +43,7 @@ pub struct RunCmd {
 
 enum ProcessState {
     Running {
-        handle: Arc<Mutex<pr::Child>>,
+        pid: libc::pid_t,
     },
     AwaitingEdit,
 }
@@ -46,6 +53,151 @@ enum RunResult {
     AlreadyDone(i64),
 }
 
+/// The most read from a pipe at once.
+const READ_BUFFER: usize = 32 * 1024;
+
+fn millis(t: libc::timeval) -> u64 {
+    t.tv_sec as u64 * 1000 + t.tv_usec as u64 / 1000
+}
+
+/// Reap the child `pid` if it's exited, returning its exit code (-1 if it was killed) and what it
+/// used along the way.
+fn try_wait_with_usage(pid: libc::pid_t, started: Instant) -> io::Result<Option<(i64, ResourceUsage)>> {
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+    loop {
+        let res = unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut rusage) };
+        if res == 0 {
+            return Ok(None);
+        }
+        if res > 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    let exit_code = unsafe {
+        if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) as i64 } else { -1 }
+    };
+
+    let wall = started.elapsed();
+    // Linux reports kilobytes; macOS, bytes.
+    let max_rss_kb = if cfg!(target_os = "macos") { rusage.ru_maxrss / 1024 } else { rusage.ru_maxrss };
+
+    Ok(Some((exit_code, ResourceUsage {
+        wall_ms: wall.as_secs() * 1000 + wall.subsec_millis() as u64,
+        user_ms: millis(rusage.ru_utime),
+        sys_ms: millis(rusage.ru_stime),
+        max_rss_kb: max_rss_kb as u64,
+        blocks_in: rusage.ru_inblock as u64,
+        blocks_out: rusage.ru_oublock as u64,
+    })))
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Keeps `fd` from leaking into the commands we run.
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Where `on_sigchld` writes, to wake the event loop.
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(target_os = "linux")]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn errno() -> *mut libc::c_int {
+    libc::__error()
+}
+
+extern "C" fn on_sigchld(_: libc::c_int) {
+    let fd = WAKE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe {
+            // The interrupted code may be about to look at errno.
+            let saved = *errno();
+            libc::write(fd, b"c".as_ptr() as *const libc::c_void, 1);
+            *errno() = saved;
+        }
+    }
+}
+
+/// A pipe the event loop polls for something to do: anything sent to `Exec`, or a child exiting.
+struct Waker {
+    read: File,
+    write: File,
+}
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let waker = unsafe {
+            Waker {
+                read: File::from_raw_fd(fds[0]),
+                write: File::from_raw_fd(fds[1]),
+            }
+        };
+        for &fd in &fds {
+            set_cloexec(fd)?;
+            set_nonblocking(fd)?;
+        }
+        Ok(waker)
+    }
+
+    /// Wakes the loop on SIGCHLD.  Only one `Waker` per process can do this.
+    fn wake_on_sigchld(&self) -> io::Result<()> {
+        WAKE_FD.store(self.write.as_raw_fd(), Ordering::SeqCst);
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_sigchld as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGCHLD, &action, ptr::null_mut()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn wake(&self) {
+        // If the pipe's full, the loop has plenty to wake it already.
+        let _ = (&self.write).write(b"w");
+    }
+
+    fn drain(&self) {
+        let mut buf = [0u8; 256];
+        while let Ok(n) = (&self.read).read(&mut buf) {
+            if n < buf.len() {
+                break;
+            }
+        }
+    }
+}
+
 struct OutputPipe {
     handle: File,
 }
@@ -96,72 +248,280 @@ impl InputPipe {
 
 enum ExecEvent {
     Enqueue(ProcessId, RunCmd, HashMap<ProcessId, Condition>),
-    Completed(ProcessId, i64),
     OpenOutputFile(WritePipe, String),
     OpenInputFile(ReadPipe, String),
     CancelExec(ProcessId),
-    PipeClosed(GenericPipe, u64),
-    PipeOutput(GenericPipe, Vec<u8>, u64),
     PipeMessage(GenericPipe, PipeMessage),
     EditComplete(usize, Vec<u8>),
 }
 
+/// A pipe being read for the frontend.  It's only polled while the frontend has said, with
+/// `PipeMessage::Read`, that more may be read; a slow frontend ends up blocking the process
+/// writing to it.
+struct Reader {
+    input: InputPipe,
+    offset: u64,
+    limit: u64,
+}
+
+impl Reader {
+    fn new(input: InputPipe) -> Reader {
+        Reader {
+            input,
+            offset: 0,
+            limit: 0,
+        }
+    }
+
+    fn grant(&mut self, read_up_to: u64) {
+        self.limit = self.limit.max(read_up_to);
+    }
+
+    /// How much can be read now.
+    fn allowed(&self) -> usize {
+        self.limit.saturating_sub(self.offset).min(READ_BUFFER as u64) as usize
+    }
+}
+
+/// Output nak itself is writing to a pipe, which is closed once it's all written.
+struct Writer {
+    output: OutputPipe,
+    data: Vec<u8>,
+    written: usize,
+}
+
 struct Pair {
     read: Option<InputPipe>,
     write: Option<OutputPipe>,
 }
 
+/// What each fd passed to `poll` is for.
+#[derive(Copy, Clone)]
+enum Source {
+    Wake,
+    Read(GenericPipe),
+    Write(GenericPipe),
+}
+
 struct ExecInternal {
     edit_ids: Ids,
     handler: Box<dyn Handler>,
-    sender: mpsc::Sender<ExecEvent>,
     receiver: mpsc::Receiver<ExecEvent>,
+    waker: Waker,
     machine: Machine<ProcessId, RunCmd, ProcessState>,
+    /// Processes that haven't been reaped yet, and when they started.
+    children: HashMap<ProcessId, (libc::pid_t, Instant)>,
     open_handles: HashMap<GenericPipe, Pair>,
-    actively_reading: HashMap<GenericPipe, thread::JoinHandle<()>>,
+    actively_reading: HashMap<GenericPipe, Reader>,
+    /// Pipes that have been read to the end, whose credit may still be in flight.
+    finished_reading: HashSet<GenericPipe>,
+    writing: HashMap<GenericPipe, Writer>,
+    buffer: Vec<u8>,
     waiting_edits: HashMap<usize, (ProcessId, String)>,
+    /// Why redirects that couldn't be opened failed, for the command that uses them.
+    failed_opens: HashMap<GenericPipe, String>,
 }
 
 impl ExecInternal {
+    /// Runs everything from one thread: requests from `Exec`, reading and writing pipes, and
+    /// reaping children.  Returns once `Exec` has gone.
     fn run_handler(&mut self) {
+        let mut sources = Vec::new();
+        let mut fds = Vec::new();
+        loop {
+            sources.clear();
+            fds.clear();
+            sources.push(Source::Wake);
+            fds.push(self.waker.read.as_raw_fd());
+            for (&pipe, reader) in &self.actively_reading {
+                if reader.allowed() > 0 {
+                    sources.push(Source::Read(pipe));
+                    fds.push(reader.input.handle.as_raw_fd());
+                }
+            }
+            for (&pipe, writer) in &self.writing {
+                sources.push(Source::Write(pipe));
+                fds.push(writer.output.handle.as_raw_fd());
+            }
+
+            let mut polled: Vec<libc::pollfd> = sources.iter().zip(&fds).map(|(source, &fd)| libc::pollfd {
+                fd,
+                events: match *source {
+                    Source::Write(_) => libc::POLLOUT,
+                    _ => libc::POLLIN,
+                },
+                revents: 0,
+            }).collect();
+            if unsafe { libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // Nothing here can be waited on any more, so stop: requests sent after this
+                // fail with "the command runner has stopped" rather than hanging.
+                self.report(FailedRequest::Other, Err(format_err!("poll failed: {}", err)));
+                return;
+            }
+
+            // Handling one source can finish with another, so each is looked up again and
+            // skipped once it's gone.
+            for (&source, fd) in sources.iter().zip(&polled) {
+                if fd.revents == 0 {
+                    continue;
+                }
+                match source {
+                    Source::Wake => {
+                        self.waker.drain();
+                        self.reap();
+                        if !self.handle_events() {
+                            return;
+                        }
+                    }
+                    Source::Read(pipe) => {
+                        let result = self.read_some(pipe);
+                        self.report(FailedRequest::Pipe(pipe), result);
+                    }
+                    Source::Write(pipe) => self.write_some(pipe),
+                }
+            }
+        }
+    }
+
+    /// Handles everything sent to `Exec` so far, returning false once it's gone.
+    fn handle_events(&mut self) -> bool {
         loop {
-            let cmd = match self.receiver.recv() {
+            let cmd = match self.receiver.try_recv() {
                 Ok(cmd) => cmd,
-                Err(e) => panic!("{:#?}", e),
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
             };
 
-            match cmd {
+            let (request, result) = match cmd {
                 ExecEvent::Enqueue(pid, cmd, block_for) => {
-                    self.enqueue(pid, cmd, block_for).unwrap();
-                }
-                ExecEvent::Completed(pid, exit_status) => {
-                    self.completed(pid, exit_status).unwrap();
+                    (FailedRequest::Command(pid), self.enqueue(pid, cmd, block_for))
                 }
                 ExecEvent::OpenOutputFile(pipe, path) => {
-                    self.open_output_file(pipe, path).unwrap();
+                    (FailedRequest::Pipe(pipe.to_generic()), self.open_output_file(pipe, path))
                 }
                 ExecEvent::OpenInputFile(pipe, path) => {
-                    self.open_input_file(pipe, path).unwrap();
+                    (FailedRequest::Pipe(pipe.to_generic()), self.open_input_file(pipe, path))
                 }
                 ExecEvent::CancelExec(pid) => {
-                    self.cancel(pid).unwrap();
-                }
-                ExecEvent::PipeClosed(pipe, end_offset) => {
-                    self.handler.pipe_closed(pipe, end_offset).unwrap();
-                }
-                ExecEvent::PipeOutput(pipe, data, end_offset) => {
-                    self.handler.pipe_output(pipe, data, end_offset).unwrap();
+                    (FailedRequest::Command(pid), self.cancel(pid))
                 }
                 ExecEvent::PipeMessage(pipe, msg) => {
-                    self.pipe_message(pipe, msg).unwrap();
+                    (FailedRequest::Pipe(pipe), self.pipe_message(pipe, msg))
                 }
                 ExecEvent::EditComplete(edit_id, data) => {
-                    self.finish_edit(edit_id, data).unwrap();
+                    (FailedRequest::Edit(edit_id), self.finish_edit(edit_id, data))
                 }
+            };
+            self.report(request, result);
+        }
+    }
+
+    fn report(&mut self, request: FailedRequest, result: Result<(), Error>) {
+        if let Err(e) = result {
+            eprintln!("error: {:?}: {}", request, e);
+            if let Err(e) = self.handler.request_failed(request, &e) {
+                eprintln!("error: couldn't report that: {}", e);
             }
         }
     }
 
+    /// Collects any children that have exited.  SIGCHLD doesn't say which, and other threads
+    /// have children of their own, so each of ours is checked.
+    fn reap(&mut self) {
+        let mut exited = Vec::new();
+        for (&pid, &(child, started)) in &self.children {
+            match try_wait_with_usage(child, started) {
+                Ok(Some((exit_code, usage))) => exited.push((pid, exit_code, Some(usage))),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("{:?} wait failed: {}", pid, e);
+                    exited.push((pid, -1, None));
+                }
+            }
+        }
+        for (pid, exit_code, usage) in exited {
+            eprintln!("{:?} exit {}", pid, exit_code);
+            self.children.remove(&pid);
+            let result = self.completed(pid, exit_code, usage);
+            self.report(FailedRequest::Command(pid), result);
+        }
+    }
+
+    fn read_some(&mut self, pipe: GenericPipe) -> Result<(), Error> {
+        let read = {
+            let reader = match self.actively_reading.get_mut(&pipe) {
+                Some(reader) => reader,
+                None => return Ok(()),
+            };
+            let allowed = reader.allowed();
+            self.buffer.resize(READ_BUFFER, 0);
+            match reader.input.handle.read(&mut self.buffer[..allowed]) {
+                Ok(0) => None,
+                Ok(len) => {
+                    reader.offset += len as u64;
+                    Some((len, reader.offset))
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("read {:?} failed: {}", pipe, e);
+                    None
+                }
+            }
+        };
+
+        match read {
+            Some((len, offset)) => self.handler.pipe_output(pipe, self.buffer[..len].to_vec(), offset),
+            None => {
+                eprintln!("eof {:?}", pipe);
+                let reader = self.actively_reading.remove(&pipe).unwrap();
+                self.finished_reading.insert(pipe);
+                self.handler.pipe_closed(pipe, reader.offset)
+            }
+        }
+    }
+
+    fn write_some(&mut self, pipe: GenericPipe) {
+        let done = {
+            let writer = match self.writing.get_mut(&pipe) {
+                Some(writer) => writer,
+                None => return,
+            };
+            match writer.output.handle.write(&writer.data[writer.written..]) {
+                Ok(len) => {
+                    writer.written += len;
+                    writer.written == writer.data.len()
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => false,
+                Err(e) => {
+                    eprintln!("writing {:?} failed: {}", pipe, e);
+                    true
+                }
+            }
+        };
+        if done {
+            eprintln!("closing {:?}", pipe);
+            self.writing.remove(&pipe);
+        }
+    }
+
+    /// Writes `data` to `output` in the background, then closes it.
+    fn write_and_close(&mut self, pipe: WritePipe, output: OutputPipe, data: Vec<u8>) -> Result<(), Error> {
+        if !data.is_empty() {
+            set_nonblocking(output.handle.as_raw_fd())?;
+            self.writing.insert(pipe.to_generic(), Writer {
+                output,
+                data,
+                written: 0,
+            });
+        }
+        Ok(())
+    }
 
     fn process_tasks(&mut self, mut tasks: Vec<Task<ProcessId, RunCmd>>) -> Result<(), Error> {
         loop {
@@ -176,19 +536,21 @@ impl ExecInternal {
                             Ok(RunResult::AlreadyDone(exit_code)) => {
                                 new_tasks.extend(
                                     self.machine.start_completed(pid, ExitStatus::from_exit_code(exit_code)));
-                                self.handler.command_result(pid, 0).unwrap();
+                                self.handler.command_result(pid, exit_code, None)?;
                             }
                             Err(e) => {
                                 new_tasks.extend(
                                     self.machine.start_completed(pid, ExitStatus::Failure));
                                 // TODO: perhaps this should go back on a custom error stream (rather than stderr?)
-                                self.pipe_output_and_close(pipes, vec![], format!("{:?}", e).into_bytes())?;
-                                self.handler.command_result(pid, 1)?;
+                                if let Err(e) = self.pipe_output_and_close(pipes, vec![], format!("nak: {}\n", e).into_bytes()) {
+                                    eprintln!("{:?} couldn't report its failure: {}", pid, e);
+                                }
+                                self.handler.command_result(pid, 1, None)?;
                             }
                         }
                     }
                     Task::ConditionFailed(pid, _) => {
-                        self.handler.command_result(pid, 1).unwrap();
+                        self.handler.command_result(pid, 1, None)?;
                     }
                 }
             }
@@ -208,45 +570,49 @@ impl ExecInternal {
         self.process_tasks(tasks)
     }
 
-    fn completed(&mut self, pid: ProcessId, exit_code: i64) -> Result<(), Error> {
-        self.handler.command_result(pid, exit_code).unwrap();
+    fn completed(&mut self, pid: ProcessId, exit_code: i64, usage: Option<ResourceUsage>) -> Result<(), Error> {
+        self.handler.command_result(pid, exit_code, usage)?;
         let tasks = self.machine.completed(pid, ExitStatus::from_exit_code(exit_code));
         self.process_tasks(tasks)
     }
 
-    fn read_end(&mut self, pipe: ReadPipe) -> InputPipe {
-        if self.open_handles.contains_key(&pipe.to_generic()) {
-            let handle = self.open_handles.get_mut(&pipe.to_generic()).expect("already checked!?!?!");
-            handle.read.take().expect("read side taken")
-        } else {
-            let (r, w) = os_pipe::pipe().expect("alloc pipe failed");
-            self.open_handles.insert(pipe.to_generic(), Pair {
-                read: None,
-                write: Some(OutputPipe::from_pipe(w)),
-            });
-            InputPipe::from_pipe(r)
+    fn read_end(&mut self, pipe: ReadPipe) -> Result<InputPipe, Error> {
+        if let Some(message) = self.failed_opens.remove(&pipe.to_generic()) {
+            return Err(format_err!("{}", message));
         }
+        if let Some(handle) = self.open_handles.get_mut(&pipe.to_generic()) {
+            return handle.read.take()
+                .ok_or_else(|| RequestError::invalid(format!("{:?} is already being read", pipe)));
+        }
+        let (r, w) = os_pipe::pipe()?;
+        self.open_handles.insert(pipe.to_generic(), Pair {
+            read: None,
+            write: Some(OutputPipe::from_pipe(w)),
+        });
+        Ok(InputPipe::from_pipe(r))
     }
 
-    fn write_end(&mut self, pipe: WritePipe) -> OutputPipe {
-        if self.open_handles.contains_key(&pipe.to_generic()) {
-            let handle = self.open_handles.get_mut(&pipe.to_generic()).expect("already checked!?!?!");
-            handle.write.take().expect("write side taken")
-        } else {
-            let (r, w) = os_pipe::pipe().expect("alloc pipe failed");
-            self.open_handles.insert(pipe.to_generic(), Pair {
-                read: Some(InputPipe::from_pipe(r)),
-                write: None,
-            });
-            OutputPipe::from_pipe(w)
+    fn write_end(&mut self, pipe: WritePipe) -> Result<OutputPipe, Error> {
+        if let Some(message) = self.failed_opens.remove(&pipe.to_generic()) {
+            return Err(format_err!("{}", message));
+        }
+        if let Some(handle) = self.open_handles.get_mut(&pipe.to_generic()) {
+            return handle.write.take()
+                .ok_or_else(|| RequestError::invalid(format!("{:?} is already being written", pipe)));
         }
+        let (r, w) = os_pipe::pipe()?;
+        self.open_handles.insert(pipe.to_generic(), Pair {
+            read: Some(InputPipe::from_pipe(r)),
+            write: None,
+        });
+        Ok(OutputPipe::from_pipe(w))
     }
 
     fn run(&mut self, pid: ProcessId, c: RunCmd) -> Result<RunResult, Error> {
         eprintln!("{:?} running {:?}", pid, c.cmd);
         match c.cmd {
             Command::Unknown(path, args) => {
-                let mut cmd = pr::Command::new(path);
+                let mut cmd = pr::Command::new(&path);
                 cmd.args(&args);
 
                 // let command_key = random_key();
@@ -256,40 +622,42 @@ impl ExecInternal {
                 //     id,
                 // });
 
-                self.read_end(c.pipes.stdin).assign_stdin(&mut cmd);
-                self.write_end(c.pipes.stdout).assign_stdout(&mut cmd);
-                self.write_end(c.pipes.stderr).assign_stderr(&mut cmd);
-
-                let handle = Arc::new(Mutex::new(cmd.spawn()?));
-                let cancel_handle = handle.clone();
-
+                let stdin = self.read_end(c.pipes.stdin)?;
+                let stdout = self.write_end(c.pipes.stdout)?;
+                let stderr = self.write_end(c.pipes.stderr)?;
+                // To say why, if the command can't be started.
+                let error_output = OutputPipe::from_file(stderr.handle.try_clone()?);
+                stdin.assign_stdin(&mut cmd);
+                stdout.assign_stdout(&mut cmd);
+                stderr.assign_stderr(&mut cmd);
+
+                let child = match cmd.spawn() {
+                    Ok(child) => child,
+                    Err(e) => {
+                        drop(cmd);
+                        let message = format!("nak: {}: {}\n", path, e);
+                        self.write_and_close(c.pipes.stderr, error_output, message.into_bytes())?;
+                        let exit_code = if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 };
+                        return Ok(RunResult::AlreadyDone(exit_code));
+                    }
+                };
+                drop(error_output);
                 drop(cmd);
 
-                let is_running = Arc::new(AtomicBool::new(true));
-
-                let sender = self.sender.clone();
-                let is_running_clone = is_running.clone();
-                // let backtraffic = self.backtraffic.clone();
-                // let running_commands = self.running_commands.clone();
-                thread::spawn(move || {
-                    let exit_code = cancel_handle.lock().unwrap().wait().unwrap().code().unwrap_or(-1);
-                    eprintln!("{:?} exit {}", pid, exit_code);
-                    sender.send(ExecEvent::Completed(pid, exit_code.into())).unwrap();
-                    is_running_clone.store(false, Ordering::SeqCst);
-
-                    // running_commands.lock().unwrap().remove(&command_key);
-                });
+                // Reaped by `reap`, rather than through the `Child`.
+                let child_pid = child.id() as libc::pid_t;
+                self.children.insert(pid, (child_pid, Instant::now()));
+                // In case it exited before SIGCHLD was watched for it.
+                self.waker.wake();
 
                 Ok(RunResult::Process(ProcessState::Running {
-                    handle,
+                    pid: child_pid,
                 }))
             }
             Command::SetDirectory(dir) => {
                 match env::set_current_dir(dir) {
-                    Ok(o) => {
-                        self.pipe_output_and_close(c.pipes,
-                            format!("Success: {:?}", o).into_bytes(),
-                            vec![])?;
+                    Ok(()) => {
+                        self.pipe_output_and_close(c.pipes, vec![], vec![])?;
                         Ok(RunResult::AlreadyDone(0))
                     }
                     Err(e) => {
@@ -300,6 +668,24 @@ impl ExecInternal {
                     }
                 }
             }
+            Command::SetEnvironment(key, value) => {
+                // Everything started from here on inherits it.
+                env::set_var(key, value);
+                self.pipe_output_and_close(c.pipes, vec![], vec![])?;
+                Ok(RunResult::AlreadyDone(0))
+            }
+            Command::SetCompression(on) => {
+                match self.handler.set_compression(on) {
+                    Ok(()) => {
+                        self.pipe_output_and_close(c.pipes, vec![], vec![])?;
+                        Ok(RunResult::AlreadyDone(0))
+                    }
+                    Err(e) => {
+                        self.pipe_output_and_close(c.pipes, vec![], format!("nak: {}\n", e).into_bytes())?;
+                        Ok(RunResult::AlreadyDone(1))
+                    }
+                }
+            }
             Command::GetDirectory => {
                 match env::current_dir() {
                     Ok(dir) => {
@@ -332,21 +718,43 @@ impl ExecInternal {
         }
     }
 
+    /// If the file can't be opened, the command using the pipe fails instead, as in a shell.
     fn open_output_file(&mut self, pipe: WritePipe, path: String) -> Result<(), Error> {
-        assert!(!self.open_handles.contains_key(&pipe.to_generic()));
-        self.open_handles.insert(pipe.to_generic(), Pair {
-            read: None,
-            write: Some(OutputPipe::from_file(File::create(path)?)),
-        });
+        self.check_unused(pipe.to_generic())?;
+        match File::create(&path) {
+            Ok(file) => {
+                self.open_handles.insert(pipe.to_generic(), Pair {
+                    read: None,
+                    write: Some(OutputPipe::from_file(file)),
+                });
+            }
+            Err(e) => {
+                self.failed_opens.insert(pipe.to_generic(), format!("{}: {}", path, e));
+            }
+        }
         Ok(())
     }
 
     fn open_input_file(&mut self, pipe: ReadPipe, path: String) -> Result<(), Error> {
-        assert!(!self.open_handles.contains_key(&pipe.to_generic()));
-        self.open_handles.insert(pipe.to_generic(), Pair {
-            read: Some(InputPipe::from_file(File::open(path)?)),
-            write: None,
-        });
+        self.check_unused(pipe.to_generic())?;
+        match File::open(&path) {
+            Ok(file) => {
+                self.open_handles.insert(pipe.to_generic(), Pair {
+                    read: Some(InputPipe::from_file(file)),
+                    write: None,
+                });
+            }
+            Err(e) => {
+                self.failed_opens.insert(pipe.to_generic(), format!("{}: {}", path, e));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_unused(&self, pipe: GenericPipe) -> Result<(), Error> {
+        if self.open_handles.contains_key(&pipe) || self.failed_opens.contains_key(&pipe) {
+            return Err(RequestError::invalid(format!("{:?} is already open", pipe)));
+        }
         Ok(())
     }
 
@@ -354,60 +762,30 @@ impl ExecInternal {
         eprintln!("got pipe message {:?} {:?}", pipe, msg);
         match msg {
             PipeMessage::BeginRead => {
-                let mut handle = self.read_end(pipe.to_read());
-                let sender = self.sender.clone();
-                let reader = thread::spawn(move || {
-                    let mut buf = [0u8; 1024];
-                    loop {
-                        match handle.handle.read(&mut buf) {
-                            Ok(0) => break,
-                            Ok(len) => {
-                                eprintln!("read {:?} {:?}", pipe, len);
-                                sender.send(ExecEvent::PipeOutput(
-                                    pipe,
-                                    buf[..len].to_owned(),
-                                    0 // TODO!!!!!!
-                                )).expect("sending file read");
-                            }
-                            Err(e) => {
-                                panic!("{:?}", e);
-                            }
-                        }
-                    }
-                    eprintln!("eof {:?}", pipe);
-                    sender.send(ExecEvent::PipeClosed(
-                        pipe,
-                        0 // TODO!!!!!
-                    )).expect("sending buffer read");
-                });
-
-                self.actively_reading.insert(pipe, reader);
+                let input = self.read_end(pipe.to_read())?;
+                set_nonblocking(input.handle.as_raw_fd())?;
+                self.actively_reading.insert(pipe, Reader::new(input));
             }
-            PipeMessage::Read { read_up_to: _ } => {
-                // we don't support limiting reads at this point.  The data just keeps flowing.
+            PipeMessage::Read { read_up_to } => {
+                match self.actively_reading.get_mut(&pipe) {
+                    Some(reader) => reader.grant(read_up_to),
+                    None if self.finished_reading.contains(&pipe) => {}
+                    None => return Err(RequestError::invalid(format!("{:?} isn't being read", pipe))),
+                }
             }
             PipeMessage::Closed { .. } |
-            PipeMessage::Data { .. } => panic!()
+            PipeMessage::Data { .. } => {
+                return Err(RequestError::invalid(format!("backends don't take {:?} for {:?}", msg, pipe)));
+            }
         }
         Ok(())
     }
 
     fn pipe_output_and_close(&mut self, pipes: WritePipes, stdout: Vec<u8>, stderr: Vec<u8>) -> Result<(), Error> {
-        fn pipe_and_close(pipe: WritePipe, mut handle: OutputPipe, data: Vec<u8>) -> Result<(), Error> {
-
-            if data.len() > 0 {
-                thread::spawn(move || {
-                    handle.handle.write_all(&data).expect("write all input buffer");
-                    eprintln!("closing {:?} {:?}", pipe, data.len());
-                });
-            } else {
-                drop(handle);
-            }
-            Ok(())
-        }
-
-        pipe_and_close(pipes.stdout, self.write_end(pipes.stdout), stdout)?;
-        pipe_and_close(pipes.stderr, self.write_end(pipes.stderr), stderr)?;
+        let output = self.write_end(pipes.stdout)?;
+        self.write_and_close(pipes.stdout, output, stdout)?;
+        let output = self.write_end(pipes.stderr)?;
+        self.write_and_close(pipes.stderr, output, stderr)?;
         Ok(())
     }
 
@@ -415,10 +793,14 @@ impl ExecInternal {
         match self.machine.status(pid) {
             Status::Running(state) => {
                 match state {
-                    ProcessState::Running { handle } => {
-                        handle.lock().unwrap().kill()?;
+                    &ProcessState::Running { pid } => {
+                        if unsafe { libc::kill(pid, libc::SIGKILL) } < 0 {
+                            return Err(io::Error::last_os_error().into());
+                        }
+                    }
+                    &ProcessState::AwaitingEdit => {
+                        return Err(RequestError::invalid(format!("{:?} is waiting on an edit", pid)));
                     }
-                    _ => panic!(),
                 }
             }
             Status::Exited(_) => {
@@ -436,9 +818,10 @@ impl ExecInternal {
     }
 
     fn finish_edit(&mut self, edit_id: usize, data: Vec<u8>) -> Result<(), Error> {
-        let (pid, path) = self.waiting_edits.remove(&edit_id).unwrap();
-        File::create(path)?.write_all(&data).unwrap();
-        self.completed(pid, 0)?;
+        let (pid, path) = self.waiting_edits.remove(&edit_id)
+            .ok_or_else(|| RequestError::invalid(format!("no edit {}", edit_id)))?;
+        File::create(path)?.write_all(&data)?;
+        self.completed(pid, 0, None)?;
         Ok(())
     }
 }
@@ -446,65 +829,112 @@ impl ExecInternal {
 pub trait Handler: Send + 'static {
     fn pipe_output(&mut self, pipe: GenericPipe, data: Vec<u8>, end_offset: u64) -> Result<(), Error>;
     fn pipe_closed(&mut self, pipe: GenericPipe, end_offset: u64) -> Result<(), Error>;
-    fn command_result(&mut self, pid: ProcessId, exit_code: i64) -> Result<(), Error>;
+    fn command_result(&mut self, pid: ProcessId, exit_code: i64, usage: Option<ResourceUsage>) -> Result<(), Error>;
     fn edit_request(&mut self, pid: ProcessId, edit_id: usize, name: String, data: Vec<u8>) -> Result<(), Error>;
+    fn request_failed(&mut self, request: FailedRequest, error: &Error) -> Result<(), Error>;
+    /// Turns compression of pipe data on the link back to the frontend on or off.
+    fn set_compression(&mut self, on: bool) -> Result<(), Error>;
 }
 
+/// Runs commands for the backend, on a thread of its own.  There can only be one per process,
+/// since it takes over SIGCHLD.
 pub struct Exec {
-    // thread: thread::JoinHandle<()>,
     sender: mpsc::Sender<ExecEvent>,
+    waker: File,
 }
 
 impl Exec {
-    pub fn new(handler: Box<dyn Handler>) -> Exec {
+    pub fn new(handler: Box<dyn Handler>) -> Result<Exec, Error> {
         let (sender, receiver) = mpsc::channel();
+        let waker = Waker::new()?;
+        waker.wake_on_sigchld()?;
+        let wake = waker.write.try_clone()?;
 
         let mut intern = ExecInternal {
             edit_ids: Ids::new(),
-            sender: sender.clone(),
             receiver,
+            waker,
             machine: Machine::new(),
+            children: HashMap::new(),
             open_handles: HashMap::new(),
             actively_reading: HashMap::new(),
+            finished_reading: HashSet::new(),
+            writing: HashMap::new(),
+            buffer: Vec::new(),
             handler,
             waiting_edits: HashMap::new(),
+            failed_opens: HashMap::new(),
         };
 
         thread::spawn(move || intern.run_handler());
 
-        Exec {
+        Ok(Exec {
             sender,
-        }
+            waker: wake,
+        })
     }
 
-    pub fn enqueue(&self, pid: ProcessId, cmd: RunCmd, block_for: HashMap<ProcessId, Condition>) -> Result<(), Error> {
-        self.sender.send(ExecEvent::Enqueue(pid, cmd, block_for)).unwrap();
+    fn send(&self, event: ExecEvent) -> Result<(), Error> {
+        self.sender.send(event).map_err(|_| format_err!("the command runner has stopped"))?;
+        // If the pipe's full, the loop has plenty to wake it already.
+        let _ = (&self.waker).write(b"w");
         Ok(())
     }
 
+    pub fn enqueue(&self, pid: ProcessId, cmd: RunCmd, block_for: HashMap<ProcessId, Condition>) -> Result<(), Error> {
+        self.send(ExecEvent::Enqueue(pid, cmd, block_for))
+    }
+
     pub fn cancel(&self, pid: ProcessId) -> Result<(), Error> {
-        self.sender.send(ExecEvent::CancelExec(pid)).unwrap();
-        Ok(())
+        self.send(ExecEvent::CancelExec(pid))
     }
 
     pub fn open_output_file(&self, pipe: WritePipe, path: String) -> Result<(), Error> {
-        self.sender.send(ExecEvent::OpenOutputFile(pipe, path)).unwrap();
-        Ok(())
+        self.send(ExecEvent::OpenOutputFile(pipe, path))
     }
 
     pub fn open_input_file(&self, pipe: ReadPipe, path: String) -> Result<(), Error> {
-        self.sender.send(ExecEvent::OpenInputFile(pipe, path)).unwrap();
-        Ok(())
+        self.send(ExecEvent::OpenInputFile(pipe, path))
     }
 
     pub fn pipe(&self, id: GenericPipe, msg: PipeMessage) -> Result<(), Error> {
-        self.sender.send(ExecEvent::PipeMessage(id, msg)).unwrap();
-        Ok(())
+        self.send(ExecEvent::PipeMessage(id, msg))
     }
 
     pub fn finish_edit(&self, edit_id: usize, data: Vec<u8>) -> Result<(), Error> {
-        self.sender.send(ExecEvent::EditComplete(edit_id, data)).unwrap();
-        Ok(())
+        self.send(ExecEvent::EditComplete(edit_id, data))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credit() {
+        let (r, _w) = os_pipe::pipe().unwrap();
+        let mut reader = Reader::new(InputPipe::from_pipe(r));
+        assert_eq!(reader.allowed(), 0);
+        reader.grant(10);
+        reader.offset = 4;
+        assert_eq!(reader.allowed(), 6);
+
+        // Grants never shrink.
+        reader.grant(5);
+        assert_eq!(reader.allowed(), 6);
+
+        reader.grant(1 << 30);
+        assert_eq!(reader.allowed(), READ_BUFFER);
+    }
+
+    #[test]
+    fn waker() {
+        let waker = Waker::new().unwrap();
+        for _ in 0..100_000 {
+            waker.wake();
+        }
+        waker.drain();
+        let mut buf = [0u8; 1];
+        assert_eq!((&waker.read).read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -15,14 +15,17 @@ extern crate tokio;
 extern crate hostname;
 
 #[cfg(unix)] extern crate ctrlc;
+#[cfg(unix)] extern crate libc;
 #[cfg(unix)] extern crate unix_socket;
 
 mod machine;
 mod exec;
+mod outbound;
 
 use std::collections::HashMap;
 use std::io::{Write, Read, BufRead, BufReader, Seek, SeekFrom};
-use std::{io, env, fs, thread};
+use std::{io, env, fs, mem, thread};
+use std::ffi::CStr;
 use std::fs::OpenOptions;
 use std::sync::mpsc;
 use std::sync::Mutex;
@@ -54,11 +57,18 @@ use protocol::{
     ProcessId,
     Backend,
     RemoteInfo,
+    ResourceUsage,
+    CAPABILITIES,
+    Hello,
+    FailedRequest,
+    RequestError,
+    ErrorKind,
     GenericPipe,
     PipeMessage,
 };
 
 use exec::{Exec, RunCmd};
+use outbound::Outbound;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EditRequest {
@@ -77,26 +87,31 @@ pub struct BackendRemote {
     input: PipeWriter,
 }
 
+/// The backends started from this one, shared with the threads relaying their output.
+#[derive(Default)]
+pub struct SubBackends {
+    remotes: HashMap<usize, BackendRemote>,
+    /// Which of `remotes` leads to each remote started further down.  Remote ids come from the
+    /// frontend, so they're the same at every hop.
+    routes: HashMap<usize, usize>,
+}
+
+impl SubBackends {
+    /// Takes out remote `id` and forgets everything reached through it.
+    fn remove(&mut self, id: usize) -> Option<BackendRemote> {
+        self.routes.retain(|_, via| *via != id);
+        self.remotes.remove(&id)
+    }
+}
+
 #[derive(Clone)]
 pub struct CommandInfo {
     remote_id: usize,
     id: ProcessId,
 }
 
-#[derive(Default)]
-pub struct StdoutTransport;
-
-impl Transport for StdoutTransport {
-    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
-        let mut stdout = io::stdout();
-        stdout.write(data)?;
-        stdout.flush()?;
-        Ok(())
-    }
-}
-
 pub struct ExecHandler {
-    backtraffic: Arc<Mutex<Backend<StdoutTransport>>>,
+    backtraffic: Arc<Mutex<Backend<Outbound>>>,
 }
 
 impl exec::Handler for ExecHandler {
@@ -108,42 +123,50 @@ impl exec::Handler for ExecHandler {
         self.backtraffic.lock().unwrap().pipe_closed(pipe.to_write(), end_offset)
     }
 
-    fn command_result(&mut self, pid: ProcessId, exit_code: i64) -> Result<(), Error> {
-        self.backtraffic.lock().unwrap().command_done(pid, exit_code)
+    fn command_result(&mut self, pid: ProcessId, exit_code: i64, usage: Option<ResourceUsage>) -> Result<(), Error> {
+        self.backtraffic.lock().unwrap().command_done(pid, exit_code, usage)
     }
 
     fn edit_request(&mut self, pid: ProcessId, edit_id: usize, path: String, data: Vec<u8>) -> Result<(), Error> {
         self.backtraffic.lock().unwrap().edit_request(pid, edit_id, path, data)
     }
+
+    fn request_failed(&mut self, request: FailedRequest, error: &Error) -> Result<(), Error> {
+        self.backtraffic.lock().unwrap().error(request, error)
+    }
+
+    fn set_compression(&mut self, on: bool) -> Result<(), Error> {
+        self.backtraffic.lock().unwrap().set_compression(on)
+    }
 }
 
 pub struct AsyncBackendHandler {
-    backtraffic: Arc<Mutex<Backend<StdoutTransport>>>,
+    backtraffic: Arc<Mutex<Backend<Outbound>>>,
     running_commands: Arc<Mutex<HashMap<String, CommandInfo>>>,
     waiting_edits: Arc<Mutex<HashMap<usize, mpsc::Sender<Vec<u8>>>>>,
-    subbackends: HashMap<usize, BackendRemote>,
+    subbackends: Arc<Mutex<SubBackends>>,
     exec: Exec,
 }
 
 impl AsyncBackendHandler {
-    fn new() -> AsyncBackendHandler {
-        let backtraffic = Arc::new(Mutex::new(Backend::new(StdoutTransport)));
+    fn new() -> Result<AsyncBackendHandler, Error> {
+        let backtraffic = Arc::new(Mutex::new(Backend::new(Outbound::new(io::stdout(), outbound::QUEUE_FRAMES))));
         let exec = Exec::new(Box::new(ExecHandler {
             backtraffic: backtraffic.clone(),
-        }));
-        AsyncBackendHandler {
+        }))?;
+        Ok(AsyncBackendHandler {
             backtraffic,
             running_commands: Default::default(),
             waiting_edits: Default::default(),
             subbackends: Default::default(),
             exec,
-        }
+        })
     }
 
     fn begin_remote(&mut self, id: usize, c: Command) -> Result<(), Error> {
         match c {
             Command::Unknown(path, args) => {
-                let mut cmd = pr::Command::new(path);
+                let mut cmd = pr::Command::new(&path);
                 cmd.args(&args);
 
                 let (output_reader, output_writer) = os_pipe::pipe()?;
@@ -152,18 +175,35 @@ impl AsyncBackendHandler {
                 cmd.stdout(output_writer.into_stdio());
                 cmd.stdin(input_reader.into_stdio());
 
-                let handle = cmd.spawn()?;
+                let mut handle = cmd.spawn()
+                    .map_err(|e| RequestError::error(ErrorKind::from(&e), format!("couldn't run {}: {}", path, e)))?;
 
                 drop(cmd);
 
                 let mut output = BufReader::new(output_reader);
+                let mut input_writer = input_writer;
 
                 let mut input = String::new();
                 output.read_line(&mut input)?;
-                assert_eq!(input, "nxQh6wsIiiFomXWE+7HQhQ==\n");
+                if let Err(e) = Hello::expect(&input, &format!("the backend started by `{}`", path)) {
+                    let _ = handle.kill();
+                    let _ = handle.wait();
+                    return Err(e);
+                }
+                input_writer.write_all(our_hello().to_line().as_bytes())?;
+                input_writer.flush()?;
 
                 let shutting_down = Arc::new(AtomicBool::new(false));
                 let shutting_down_clone = shutting_down.clone();
+                let backtraffic = self.backtraffic.clone();
+                let subbackends = self.subbackends.clone();
+
+                // Listed before anything's relayed, so going away early still finds it.
+                subbackends.lock().unwrap().remotes.insert(id, BackendRemote {
+                    shutting_down,
+                    handle,
+                    input: input_writer,
+                });
 
                 thread::spawn(move || {
                     loop {
@@ -174,36 +214,58 @@ impl AsyncBackendHandler {
                                     break;
                                 }
 
-                                let mut rpc: Response = serde_json::from_str(&input).unwrap();
-                                rpc.remote_id = id;
-
-                                write!(io::stdout(), "{}\n", serde_json::to_string(&rpc).unwrap()).unwrap();
+                                let mut rpc: Response = match serde_json::from_str(&input) {
+                                    Ok(rpc) => rpc,
+                                    Err(e) => {
+                                        eprintln!("error: bad response from remote {}: {}", id, e);
+                                        continue;
+                                    }
+                                };
+                                // Responses from further down already name their remote.
+                                if rpc.remote_id == 0 {
+                                    rpc.remote_id = id;
+                                }
+                                if backtraffic.lock().unwrap().forward(rpc).is_err() {
+                                    break;
+                                }
                             }
                             Err(error) => {
                                 if !shutting_down_clone.load(Ordering::SeqCst) {
-                                    eprintln!("error: {}", error)
+                                    eprintln!("error: remote {}: {}", id, error)
                                 }
+                                break;
                             }
                         }
                     }
-                });
 
-                self.subbackends.insert(id, BackendRemote {
-                    shutting_down,
-                    handle,
-                    input: input_writer,
+                    // Still listed unless `end_remote` took it, in which case nobody's waiting on it.
+                    // Otherwise the frontend would wait on it forever, so it's told, but only once
+                    // nothing more can be passed on to it.
+                    let removed = subbackends.lock().unwrap().remove(id);
+                    if let Some(mut backend) = removed {
+                        let _ = backend.handle.kill();
+                        let _ = backend.handle.wait();
+                        let error = RequestError::error(ErrorKind::Io, format!("the connection through `{}` closed", path));
+                        let _ = backtraffic.lock().unwrap().remote_lost(id, &error);
+                    }
                 });
 
                 Ok(())
             }
-            _ => panic!(),
+            c => Err(RequestError::invalid(format!("can't start a remote with {:?}", c))),
         }
     }
 
     fn end_remote(&mut self, id: usize) -> Result<(), Error> {
-        let mut backend = self.subbackends.remove(&id).unwrap();
-        backend.shutting_down.store(true, Ordering::SeqCst);
+        let mut backend = {
+            let mut subbackends = self.subbackends.lock().unwrap();
+            let backend = subbackends.remove(id)
+                .ok_or_else(|| RequestError::invalid(format!("no remote {}", id)))?;
+            backend.shutting_down.store(true, Ordering::SeqCst);
+            backend
+        };
         backend.handle.kill()?;
+        backend.handle.wait()?;
         Ok(())
     }
 }
@@ -239,9 +301,11 @@ impl BackendHandler for AsyncBackendHandler {
     }
 
     fn list_directory(&mut self, id: usize, path: String) -> Result<(), Error> {
-        let items = fs::read_dir(path).unwrap().map(|i| {
-            i.unwrap().file_name().into_string().unwrap()
-        }).collect();
+        let items = fs::read_dir(&path)
+            .map_err(|e| RequestError::error(ErrorKind::from(&e), format!("{}: {}", path, e)))?
+            .filter_map(|i| i.ok())
+            .filter_map(|i| i.file_name().into_string().ok())
+            .collect();
 
         self.backtraffic.lock().unwrap().directory_listing(id, items)?;
         Ok(())
@@ -256,6 +320,10 @@ impl BackendHandler for AsyncBackendHandler {
         self.exec.pipe(id, msg)
     }
 
+    fn ping(&mut self, id: usize) -> Result<(), Error> {
+        self.backtraffic.lock().unwrap().pong(id)
+    }
+
 }
 
 fn random_key() -> String {
@@ -267,11 +335,12 @@ fn random_key() -> String {
 #[cfg(unix)]
 fn setup_editback_socket(
     socket_path: &str, 
-    backtraffic: Arc<Mutex<Backend<StdoutTransport>>>,
+    backtraffic: Arc<Mutex<Backend<Outbound>>>,
     running_commands: Arc<Mutex<HashMap<String, CommandInfo>>>,
     waiting_edits: Arc<Mutex<HashMap<usize, mpsc::Sender<Vec<u8>>>>>) -> Result<(), Error>
 {
-    let listener = UnixListener::bind(socket_path).expect("bind listen socket");
+    let listener = UnixListener::bind(socket_path)
+        .map_err(|e| format_err!("couldn't listen on {}: {}", socket_path, e))?;
 
     thread::spawn(move || {
         for stream in listener.incoming() {
@@ -326,7 +395,7 @@ fn setup_editback_socket(
 #[cfg(not(unix))]
 fn setup_editback_socket(
     socket_path: &str, 
-    backtraffic: Arc<Mutex<Backend<StdoutTransport>>>,
+    backtraffic: Arc<Mutex<Backend<Outbound>>>,
     running_commands: Arc<Mutex<HashMap<String, CommandInfo>>>,
     waiting_edits: Arc<Mutex<HashMap<usize, mpsc::Sender<Vec<u8>>>>>) -> Result<(), Error>
 {
@@ -346,15 +415,106 @@ fn setup_ctrlc_handler() {
 
 }
 
+fn c_string(s: *const libc::c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+    }
+}
+
+/// Who and where we're running, from the password database rather than the environment where
+/// possible (`sudo` and `su` don't always update `$USER` or `$HOME`).
+fn user_info() -> (u32, Option<String>, Option<String>, Option<String>) {
+    let uid = unsafe { libc::getuid() };
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+        return (uid, None, None, None);
+    }
+    let pw = unsafe { &*pw };
+    (uid, c_string(pw.pw_name), c_string(pw.pw_dir), c_string(pw.pw_shell))
+}
+
+fn kernel_release() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    c_string(uts.release.as_ptr())
+}
+
+fn local_info() -> Result<RemoteInfo, Error> {
+    let (uid, name, home_dir, shell) = user_info();
+
+    let username = name
+        .or_else(|| env::var("USER").ok())
+        .or_else(|| env::var("LOGNAME").ok())
+        .unwrap_or_else(|| String::from("unknown"));
+    let home_dir = home_dir.or_else(|| env::var("HOME").ok());
+    let path = env::var_os("PATH")
+        .map(|p| env::split_paths(&p).filter_map(|d| d.to_str().map(|d| d.to_string())).collect())
+        .unwrap_or_default();
+
+    let hostname = hostname::get_hostname()
+        .ok_or_else(|| format_err!("couldn't get the hostname"))?;
+    let working_dir = env::current_dir()?;
+    let working_dir = working_dir.to_str()
+        .ok_or_else(|| format_err!("the working directory {:?} isn't valid UTF-8", working_dir))?
+        .to_string();
+
+    Ok(RemoteInfo {
+        hostname,
+        username,
+        working_dir,
+        uid: Some(uid),
+        home_dir,
+        os: Some(env::consts::OS.to_string()),
+        kernel: kernel_release(),
+        arch: Some(env::consts::ARCH.to_string()),
+        shell,
+        path,
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    })
+}
+
+/// Pass `rpc` on towards the sub-backend it's for.
+fn forward(backend: &mut AsyncBackendHandler, rpc: Request) -> Result<(), Error> {
+    let remote_id = rpc.remote_id;
+    let mut subbackends = backend.subbackends.lock().unwrap();
+    let child = if subbackends.remotes.contains_key(&remote_id) {
+        remote_id
+    } else {
+        *subbackends.routes.get(&remote_id)
+            .ok_or_else(|| RequestError::invalid(format!("no remote {}", remote_id)))?
+    };
+    if let Some(new_remote) = rpc.new_remote() {
+        subbackends.routes.insert(new_remote, child);
+    }
+    let input = serde_json::to_string(&Request {
+        remote_id: if child == remote_id { 0 } else { remote_id },
+        message: rpc.message,
+    })?;
+    let pipe = &mut subbackends.remotes.get_mut(&child)
+        .ok_or_else(|| RequestError::invalid(format!("no remote {}", child)))?
+        .input;
+    writeln!(pipe, "{}", input)?;
+    pipe.flush()?;
+    Ok(())
+}
+
+fn our_hello() -> Hello {
+    Hello::ours(env!("CARGO_PKG_VERSION"))
+}
+
 fn run_backend() -> Result<(), Error> {
 
-    let mut backend = AsyncBackendHandler::new();
+    let mut backend = AsyncBackendHandler::new()?;
 
     setup_ctrlc_handler();
 
     // eprintln!("spawn_self");
-    write!(io::stdout(), "nxQh6wsIiiFomXWE+7HQhQ==\n").unwrap();
-    io::stdout().flush().unwrap();
+    backend.backtraffic.lock().unwrap().trans.send(our_hello().to_line().as_bytes())?;
 
     let socket_path = format!("/tmp/nak-backend-{}", random_key());
 
@@ -369,16 +529,9 @@ fn run_backend() -> Result<(), Error> {
         backend.running_commands.clone(),
         backend.waiting_edits.clone())?;
 
-    let hostname = hostname::get_hostname().unwrap();
-    let username = String::from("dummyuser");
-    let working_dir = env::current_dir().unwrap().to_str().unwrap().to_string();
-
-    backend.backtraffic.lock().unwrap().remote_ready(RemoteInfo {
-        hostname,
-        username,
-        working_dir,
-    })?;
+    backend.backtraffic.lock().unwrap().remote_ready(local_info()?)?;
 
+    let mut first_line = true;
     loop {
         let mut input = String::new();
         match io::stdin().read_line(&mut input) {
@@ -388,22 +541,41 @@ fn run_backend() -> Result<(), Error> {
                 }
                 // eprintln!("part {}", input);
 
-                let rpc: Request = serde_json::from_str(&input)?;
+                if first_line {
+                    first_line = false;
+                    let hello = Hello::from_line(&input)
+                        .ok_or_else(|| format_err!("the frontend didn't say hello; it said {:?}", input.trim_end()))
+                        .and_then(|hello| hello.check("the frontend").map(|()| hello));
+                    match hello {
+                        Ok(hello) => backend.backtraffic.lock().unwrap().hello(&our_hello(), &hello),
+                        Err(e) => {
+                            // Say why before going, in case the frontend can read that much.
+                            let error = RequestError::invalid(e.to_string());
+                            backend.backtraffic.lock().unwrap().error(FailedRequest::Other, &error)?;
+                            return Err(e);
+                        }
+                    }
+                    continue;
+                }
+
+                let rpc: Request = match serde_json::from_str(&input) {
+                    Ok(rpc) => rpc,
+                    Err(e) => {
+                        let error = RequestError::invalid(format!("couldn't read request: {}", e));
+                        backend.backtraffic.lock().unwrap().error(FailedRequest::Other, &error)?;
+                        continue;
+                    }
+                };
 
-                if rpc.remote_id == 0 {
-                    rpc.route(&mut backend).unwrap();
+                let request = rpc.failed_request();
+                let result = if rpc.remote_id == 0 {
+                    rpc.route(&mut backend)
                 } else {
-                    let input = serde_json::to_string(&Request {
-                        remote_id: 0,
-                        message: rpc.message,
-                    }).unwrap();
-                    // eprintln!("write {} {}", rpc.remote_id - 1, input);
-                    let pipe = &mut backend.subbackends.get_mut(&rpc.remote_id).unwrap().input;
-                    write!(
-                        pipe,
-                        "{}\n",
-                        input).unwrap();
-                    pipe.flush().unwrap();
+                    forward(&mut backend, rpc)
+                };
+                if let Err(e) = result {
+                    eprintln!("error: {:?}: {}", request, e);
+                    backend.backtraffic.lock().unwrap().error(request, &e)?;
                 }
             }
             Err(error) => {
@@ -485,7 +657,6 @@ fn main() -> Result<(), Error> {
     } else if args.len() == 3 && args[1] == "editor" {
         run_editor(&args[2])
     } else {
-        assert!(false);
-        Err(format_err!("o.O?"))
+        Err(format_err!("usage: {} [pager | editor FILE]", args[0]))
     }
 }
@@ -0,0 +1,112 @@
+//! The one place frames are written to the frontend.
+//!
+//! Everything the backend says -- responses of its own, and those relayed from remotes it
+//! started -- is queued here whole and written out by a single thread, so frames can't be torn
+//! by two writers.  The queue is bounded: once the frontend stops keeping up, senders wait
+//! rather than buffering without limit.
+
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use failure::Error;
+
+use protocol::Transport;
+
+/// How many frames can wait to be written before senders block.  Pipe data frames are at most
+/// a read buffer's worth of bytes, so this bounds the queue to a few megabytes.
+pub const QUEUE_FRAMES: usize = 64;
+
+#[derive(Clone)]
+pub struct Outbound {
+    frames: SyncSender<Vec<u8>>,
+}
+
+impl Outbound {
+    /// Starts the writer thread, which runs until every `Outbound` is dropped or writing fails.
+    pub fn new<W: Write + Send + 'static>(out: W, capacity: usize) -> Outbound {
+        let (frames, receiver) = mpsc::sync_channel(capacity);
+        thread::spawn(move || {
+            if let Err(e) = write_frames(out, receiver) {
+                eprintln!("error: writing to the frontend failed: {}", e);
+            }
+        });
+        Outbound { frames }
+    }
+}
+
+fn write_frames<W: Write>(mut out: W, frames: Receiver<Vec<u8>>) -> Result<(), Error> {
+    while let Ok(frame) = frames.recv() {
+        out.write_all(&frame)?;
+        // Only flush once there's a lull, so a burst goes out in as few writes as possible.
+        while let Ok(frame) = frames.try_recv() {
+            out.write_all(&frame)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+impl Transport for Outbound {
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.frames.send(data.to_vec())
+            .map_err(|_| format_err!("can't send to the frontend; writing to it failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            // Short writes, so a torn frame would show.
+            let n = data.len().min(7);
+            self.0.lock().unwrap().extend_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_stay_whole() {
+        let written = Shared::default();
+        let outbound = Outbound::new(written.clone(), 4);
+
+        let senders: Vec<_> = (0..8).map(|t| {
+            let mut outbound = outbound.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    outbound.send(format!("{} {} {}\n", t, i, "x".repeat(i % 40)).as_bytes()).unwrap();
+                }
+            })
+        }).collect();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        drop(outbound);
+
+        // Dropping the last sender lets the writer drain and stop.
+        while Arc::strong_count(&written.0) > 1 {
+            thread::yield_now();
+        }
+        let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        let mut next = vec![0; 8];
+        for line in written.lines() {
+            let words: Vec<&str> = line.split(' ').collect();
+            let (t, i): (usize, usize) = (words[0].parse().unwrap(), words[1].parse().unwrap());
+            assert_eq!(i, next[t], "{:?}", line);
+            assert_eq!(words[2], "x".repeat(i % 40));
+            next[t] += 1;
+        }
+        assert_eq!(next, vec![500; 8]);
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::Write;
+
+use failure::Error;
+
+use crate::prefs::{Prefs, Rule};
+
+// `args` without a leading `--save`, and whether it was there.
+fn strip_save(args: &str) -> (bool, &str) {
+    let args = args.trim();
+    if args == "--save" || args.starts_with("--save ") {
+        (true, args["--save".len()..].trim_start())
+    } else {
+        (false, args)
+    }
+}
+
+fn alias(prefs: &mut Prefs, args: &str, out: &mut impl Write) -> Result<(), Error> {
+    // Everything else (e.g. `--anywhere`) is part of the rule itself.
+    let (save, spec) = strip_save(args);
+
+    if !spec.contains('=') {
+        let rules: Vec<&Rule> = prefs.aliases().iter()
+            .filter(|r| spec.is_empty() || r.starts_with(spec))
+            .collect();
+        if rules.is_empty() && !spec.is_empty() {
+            return Err(format_err!("no alias for `{}`", spec));
+        }
+        for rule in rules {
+            writeln!(out, "alias {}", rule)?;
+        }
+        return Ok(());
+    }
+
+    let rule = Rule::parse_alias(spec)?;
+    if save {
+        prefs.save_aliases(&[rule.clone()], &[])?;
+    }
+    prefs.add_alias(rule);
+
+    Ok(())
+}
+
+fn unalias(prefs: &mut Prefs, args: &str) -> Result<(), Error> {
+    let (save, pattern) = strip_save(args);
+
+    if save && prefs.section_has_alias(pattern) {
+        // Which remotes to take it away from is more than a one-liner can say.
+        return Err(format_err!("`{}` is also set in a `[...]` section, so edit the prefs file to remove it", pattern));
+    }
+    let removed = prefs.remove_aliases(pattern);
+    if removed.is_empty() {
+        return Err(format_err!("no alias matches `{}`", pattern));
+    }
+    if save {
+        prefs.save_aliases(&[], &removed)?;
+    }
+
+    Ok(())
+}
+
+fn reload(prefs: &mut Prefs, out: &mut impl Write) -> Result<(), Error> {
+    prefs.reload()?;
+    writeln!(out, "prefs reloaded")?;
+    Ok(())
+}
+
+/// Run `input` if it's one of the builtins that only touch the frontend's own state, and so
+/// never need to go through a `Plan`.  Returns `None` for anything else.
+///
+/// These are picked out before the input is parsed as a command, since an alias can contain
+/// `|` or `>` without meaning a pipeline or redirect.
+pub fn run(prefs: &mut Prefs, input: &str, out: &mut impl Write) -> Option<Result<(), Error>> {
+    let input = input.trim();
+    let (head, args) = input.split_at(input.find(char::is_whitespace).unwrap_or(input.len()));
+
+    Some(match head {
+        "alias" => alias(prefs, args, out),
+        "unalias" => unalias(prefs, args),
+        "reload" => reload(prefs, out),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_are_not_parsed_as_commands() {
+        let mut prefs = Prefs::parse("alias g ...=git ...\n[host box*]\nalias g ...=hg ...\n").unwrap();
+        let mut out = Vec::new();
+
+        run(&mut prefs, "alias --abbr L=| less", &mut out).unwrap().unwrap();
+        assert_eq!(prefs.aliases().last().unwrap().to_string(), "--abbr L=| less");
+        assert!(run(&mut prefs, "ls | less", &mut out).is_none());
+
+        // Only the top-level one could be taken out of the file.
+        assert!(run(&mut prefs, "unalias --save g", &mut out).unwrap().is_err());
+        assert!(prefs.aliases().iter().any(|r| r.starts_with("g")));
+        run(&mut prefs, "unalias g", &mut out).unwrap().unwrap();
+        assert!(!prefs.aliases().iter().any(|r| r.starts_with("g")));
+    }
+}
@@ -5,10 +5,12 @@ use std::fs;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write, Read};
 use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use failure::Error;
-use os_pipe::{PipeWriter, IntoStdio};
+use os_pipe::{PipeReader, PipeWriter, IntoStdio};
 use os_pipe;
 use libc;
 use serde_json;
@@ -16,6 +18,7 @@ use tempfile::tempdir;
 
 use protocol::{
     Response,
+    Hello,
     Command,
     Transport,
     Endpoint,
@@ -27,12 +30,20 @@ use protocol::{
     ReadPipes,
     ReadProcess,
     RemoteInfo,
+    ResourceUsage,
     WritePipes,
     PipeMessage,
     GenericPipe,
+    FailedRequest,
+    ErrorKind,
+    Query,
+    Answer,
+    capability,
 };
 
 use crate::Event;
+use crate::edit::Idle;
+use crate::prefs::Settings;
 
 pub struct PipeTransport {
     input: PipeWriter,
@@ -46,6 +57,47 @@ impl Transport for PipeTransport {
     }
 }
 
+/// What the user asked to run, tracked while it runs for the post-exec hook.
+pub struct RunningPlan {
+    /// From `Plan::describe`.
+    pub text: String,
+    pub remote: RemoteInfo,
+    pub depth: usize,
+    pub started: Instant,
+    /// The last process in the pipeline, whose exit code counts.
+    pub last_process: Option<ProcessId>,
+    pub exit_code: Option<i64>,
+    pub stdout_bytes: usize,
+    pub stderr_bytes: usize,
+    /// Summed over every process in the plan (apart from `max_rss_kb`, the largest of them), if
+    /// the backend reported any.  `wall_ms` is left alone; `started` says more.
+    pub usage: Option<ResourceUsage>,
+    /// Whether to report `usage` afterwards, as `time` does.
+    pub timed: bool,
+}
+
+impl RunningPlan {
+    fn add_usage(&mut self, usage: &ResourceUsage) {
+        let total = self.usage.get_or_insert_with(ResourceUsage::default);
+        total.user_ms += usage.user_ms;
+        total.sys_ms += usage.sys_ms;
+        total.max_rss_kb = total.max_rss_kb.max(usage.max_rss_kb);
+        total.blocks_in += usage.blocks_in;
+        total.blocks_out += usage.blocks_out;
+    }
+}
+
+/// How much of a pipe the backend may send ahead of what's been written out.  More is granted as
+/// the output is consumed, so a slow terminal throttles the command producing it.
+const READ_WINDOW: u64 = 256 * 1024;
+
+/// Where a pipe being read has got to, for flow control.
+pub struct PipeReading {
+    remote: RemoteId,
+    received: u64,
+    granted: u64,
+}
+
 pub struct StackedRemotes {
     pub remotes: Vec<(RemoteId, RemoteInfo)>,
     pub waiting_for: HashSet<ProcessId>,
@@ -56,31 +108,177 @@ pub struct StackedRemotes {
     pub cwd_for_remote: HashMap<RemoteId, GenericPipe>,
     pub stdout_pipes: HashSet<GenericPipe>,
     pub stderr_pipes: HashSet<GenericPipe>,
+    /// Only filled in once every directory has been listed, so a command isn't shown as
+    /// unknown just because its directory hasn't been got to yet.
+    pub known_commands: HashMap<RemoteId, HashSet<String>>,
+    /// Commands found so far on remotes still being listed, and how many directories are left.
+    pub listing: HashMap<RemoteId, (usize, HashSet<String>)>,
+    pub reading: HashMap<GenericPipe, PipeReading>,
+    /// Set when a remote is pushed, until the per-remote prefs have been applied to it.
+    pub needs_setup: bool,
+    pub running: Option<RunningPlan>,
+    /// How each remote was started, for reconnecting to it.
+    pub started_with: HashMap<RemoteId, Command>,
+    /// How often to ping the remotes, at the prompt as well as while waiting on them, from the
+    /// prefs.
+    pub heartbeat: Option<Duration>,
+    /// When to next ping; unset until the first, and whenever `heartbeat` changes.
+    pub next_heartbeat: Option<Instant>,
+    /// Remotes with a ping on the way.
+    pub pinging: HashSet<RemoteId>,
+    /// The round trip to each remote, as of its last answered ping.
+    pub latency: HashMap<RemoteId, Duration>,
+    /// The `capability`s both we and the first backend listed when saying hello.
+    pub link_capabilities: Vec<String>,
+    /// The lowest remote in `remotes` that stopped answering.  Everything above it was started
+    /// through it, so is gone too.
+    pub lost: Option<RemoteId>,
+    /// Pipes that were being read from lost remotes, whose data is dropped if any still comes.
+    pub abandoned: HashSet<GenericPipe>,
 }
 
+impl StackedRemotes {
+    /// Marks `remote`, and everything started through it, as lost, and stops waiting for
+    /// anything running there.  Does nothing if it's already gone.
+    pub fn lose(&mut self, remote: RemoteId, why: &str) {
+        let position = |id| self.remotes.iter().position(|&(r, _)| r == id);
+        let index = match position(remote) {
+            Some(index) => index,
+            None => return,
+        };
+        if self.lost.and_then(position).is_some_and(|lost| lost <= index) {
+            return;
+        }
+        eprintln!("nak: lost the connection to {}: {}", self.remotes[index].1.hostname, why);
+        self.lost = Some(remote);
+
+        let gone: HashSet<RemoteId> = self.remotes[index..].iter().map(|&(id, _)| id).collect();
+        // Everything runs on the top remote, which is one of them.
+        self.waiting_for.clear();
+        self.waiting_for_eof.clear();
+        self.waiting_for_remote = None;
+        let pipes: Vec<GenericPipe> = self.reading.iter()
+            .filter(|(_, reading)| gone.contains(&reading.remote))
+            .map(|(&pipe, _)| pipe)
+            .collect();
+        for pipe in pipes {
+            self.reading.remove(&pipe);
+            self.gathering_output.remove(&pipe);
+            self.abandoned.insert(pipe);
+        }
+        for remote in &gone {
+            if let Some(pipe) = self.cwd_for_remote.remove(remote) {
+                self.finished_output.remove(&pipe);
+            }
+        }
+    }
+}
+
+/// A remote is lost once this many heartbeats go by without an answer to its ping.
+pub const HEARTBEATS_MISSED: u32 = 3;
+
+/// How long to wait for a remote to list a directory of commands before giving up on it.
+const LISTING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Where to look for commands on a new remote, so the editor can tell whether the
+// command being typed exists without asking the remote on every keystroke.  Only used for
+// backends too old to send their `$PATH`.
+const COMMAND_DIRS: &[&str] = &["/bin", "/sbin", "/usr/bin", "/usr/sbin", "/usr/local/bin"];
+
 impl<T: Transport> EndpointHandler<T> for StackedRemotes {
     fn remote_ready(endpoint: &mut Endpoint<T, Self>, id: RemoteId, remote_info: RemoteInfo) -> Result<(), Error> {
         assert_eq!(endpoint.handler.waiting_for_remote.expect("no remote waiting"), id);
         endpoint.handler.waiting_for_remote = None;
+        let dirs: Vec<String> = if remote_info.path.is_empty() {
+            COMMAND_DIRS.iter().map(|d| d.to_string()).collect()
+        } else {
+            remote_info.path.clone()
+        };
         endpoint.handler.remotes.push((id, remote_info));
+        endpoint.handler.needs_setup = true;
+
+        // The prompt doesn't wait for these; they're dealt with as they come in, even while
+        // the user is typing.
+        endpoint.handler.listing.insert(id, (dirs.len(), HashSet::new()));
+        for dir in dirs {
+            let query = Query::ListDirectory(dir);
+            endpoint.call(id, query, Some(LISTING_TIMEOUT), Box::new(move |endpoint, answer| {
+                let handler = &mut endpoint.handler;
+                let done = match handler.listing.get_mut(&id) {
+                    Some((left, commands)) => {
+                        // `$PATH` often names directories that don't exist; they just have no
+                        // commands.
+                        if let Ok(Answer::DirectoryListing(items)) = answer {
+                            commands.extend(items);
+                        }
+                        *left -= 1;
+                        *left == 0
+                    }
+                    None => false,
+                };
+                if done {
+                    let (_, commands) = handler.listing.remove(&id).unwrap();
+                    handler.known_commands.insert(id, commands);
+                }
+                Ok(())
+            }))?;
+        }
         Ok(())
     }
 
     fn pipe(endpoint: &mut Endpoint<T, Self>, id: GenericPipe, msg: PipeMessage) -> Result<(), Error> {
         match msg {
-            PipeMessage::Data { data, end_offset: _ } => {
+            PipeMessage::Data { data, end_offset } => {
+                let data = data.into_bytes()?;
+                let (remote, grant) = match endpoint.handler.reading.get_mut(&id) {
+                    Some(reading) => {
+                        reading.received += data.len() as u64;
+                        // Backends from before flow control send 0.
+                        if end_offset != 0 && end_offset != reading.received {
+                            return Err(format_err!("{:?} lost data: got up to {}, expected {}",
+                                id, end_offset, reading.received));
+                        }
+                        let grant = if reading.granted - reading.received < READ_WINDOW / 2 {
+                            reading.granted = reading.received + READ_WINDOW;
+                            Some(reading.granted)
+                        } else {
+                            None
+                        };
+                        (reading.remote, grant)
+                    }
+                    None if endpoint.handler.abandoned.contains(&id) => return Ok(()),
+                    None => return Err(format_err!("data for {:?}, which isn't being read", id)),
+                };
+
                 if let Some(out) = endpoint.handler.gathering_output.get_mut(&id) {
-                    out.extend(data)
+                    out.extend_from_slice(&data)
                 } else if endpoint.handler.stdout_pipes.contains(&id) {
+                    if let Some(ref mut running) = endpoint.handler.running {
+                        running.stdout_bytes += data.len();
+                    }
                     io::stdout().write_all(&data)?;
                 } else if endpoint.handler.stderr_pipes.contains(&id) {
+                    if let Some(ref mut running) = endpoint.handler.running {
+                        running.stderr_bytes += data.len();
+                    }
                     io::stderr().write_all(&data)?;
                 } else {
                     panic!("bad pipe {:?} {:?} {:?}", id, endpoint.handler.stdout_pipes, endpoint.handler.stderr_pipes);
                 }
+
+                // Only once it's been written out, so a blocked stdout holds the backend up too.
+                if let Some(read_up_to) = grant {
+                    endpoint.pipe_read(remote, id.to_read(), read_up_to)?;
+                }
             }
-            PipeMessage::Closed { .. } => {
+            PipeMessage::Closed { end_offset } => {
+                if let Some(reading) = endpoint.handler.reading.remove(&id) {
+                    if end_offset != 0 && end_offset != reading.received {
+                        eprintln!("nak: {:?} closed at {}, but only {} bytes arrived", id, end_offset, reading.received);
+                    }
+                }
                 endpoint.handler.waiting_for_eof.remove(&id);
+                endpoint.handler.abandoned.remove(&id);
                 if let Some(output) = endpoint.handler.gathering_output.remove(&id) {
                     endpoint.handler.finished_output.insert(id, output);
                 }
@@ -90,15 +288,22 @@ impl<T: Transport> EndpointHandler<T> for StackedRemotes {
         Ok(())
     }
 
-    fn command_done(endpoint: &mut Endpoint<T, Self>, id: ProcessId, _exit_code: i64) -> Result<(), Error> {
-        assert!(endpoint.handler.waiting_for.remove(&id));
+    fn command_done(endpoint: &mut Endpoint<T, Self>, id: ProcessId, exit_code: i64, usage: Option<ResourceUsage>) -> Result<(), Error> {
+        if !endpoint.handler.waiting_for.remove(&id) {
+            // Given up on when its remote was lost.
+            return Ok(());
+        }
+        if let Some(ref mut running) = endpoint.handler.running {
+            if running.last_process == Some(id) {
+                running.exit_code = Some(exit_code);
+            }
+            if let Some(usage) = usage {
+                running.add_usage(&usage);
+            }
+        }
         Ok(())
     }
 
-    fn directory_listing(_endpoint: &mut Endpoint<T, Self>, _id: usize, _items: Vec<String>) -> Result<(), Error> {
-        panic!();
-    }
-
     fn edit_request(endpoint: &mut Endpoint<T, Self>, edit_id: usize, command_id: ProcessId, name: String, data: Vec<u8>) -> Result<(), Error> {
         println!("editing {}", name);
         io::stdout().write(&data)?;
@@ -118,19 +323,106 @@ impl<T: Transport> EndpointHandler<T> for StackedRemotes {
         endpoint.finish_edit(command_id, edit_id, new_data)?;
         Ok(())
     }
+
+    fn error(endpoint: &mut Endpoint<T, Self>, id: RemoteId, request: FailedRequest, kind: ErrorKind, message: String) -> Result<(), Error> {
+        let handler = &mut endpoint.handler;
+        match request {
+            FailedRequest::Remote(remote) => {
+                if handler.waiting_for_remote == Some(remote) {
+                    handler.waiting_for_remote = None;
+                } else if handler.remotes.iter().any(|&(r, _)| r == remote) {
+                    handler.lose(remote, &message);
+                    return Ok(());
+                }
+            }
+            FailedRequest::Command(pid) => {
+                if handler.waiting_for.remove(&pid) {
+                    if let Some(ref mut running) = handler.running {
+                        if running.last_process == Some(pid) {
+                            running.exit_code = Some(1);
+                        }
+                    }
+                }
+            }
+            FailedRequest::ListDirectory(_) |
+            FailedRequest::Ping(_) |
+            FailedRequest::Edit(_) |
+            FailedRequest::Pipe(_) |
+            FailedRequest::Other => {}
+        }
+
+        let host = handler.remotes.iter()
+            .find(|&&(remote, _)| remote == id)
+            .map(|(_, info)| info.hostname.as_str())
+            .unwrap_or("the remote");
+        match kind {
+            ErrorKind::InvalidRequest => eprintln!("nak: {} rejected a request: {}", host, message),
+            _ => eprintln!("nak: {}: {}", host, message),
+        }
+        Ok(())
+    }
 }
 
 pub type BackendEndpoint = Endpoint<PipeTransport, StackedRemotes>;
 
-pub fn launch_backend(sender: mpsc::Sender<Event>, name: Option<String>) -> Result<BackendEndpoint, Error> {
+/// What the thread reading from the backend passes on, with a pipe it pokes each time so the
+/// line editor can wait on the terminal and the backend at once.
+pub struct Inbox {
+    pub receiver: mpsc::Receiver<Event>,
+    wake: PipeReader,
+}
+
+impl Inbox {
+    pub fn new(receiver: mpsc::Receiver<Event>, wake: PipeReader) -> Inbox {
+        Inbox { receiver, wake }
+    }
+}
+
+impl Idle for Inbox {
+    fn wake_fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+
+    fn run(&mut self, backend: &mut BackendEndpoint) -> Result<Option<Instant>, Error> {
+        let mut pokes = [0; 64];
+        while let Ok(n) = self.wake.read(&mut pokes) {
+            if n == 0 {
+                break;
+            }
+        }
+        while let Ok(event) = self.receiver.try_recv() {
+            // The editor has the keyboard, Ctrl-C included.
+            match event {
+                Event::Remote(response) => backend.receive(response)?,
+                Event::Disconnected(why) => backend.disconnected(&why)?,
+                _ => {}
+            }
+        }
+        backend.tick(Instant::now())
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Starts the backend, passing what it says on to `sender`.  The returned pipe becomes readable
+/// whenever something's been sent; see `Inbox`.
+pub fn launch_backend(sender: mpsc::Sender<Event>, name: Option<String>) -> Result<(BackendEndpoint, PipeReader), Error> {
 
     let (output_reader, output_writer) = os_pipe::pipe()?;
     let (input_reader, input_writer) = os_pipe::pipe()?;
 
+    let backend_name = name.unwrap_or(String::from("nak-backend"));
+
     let pid = unsafe { libc::fork() };
 
     if pid == 0 {
-        let mut cmd = pr::Command::new(name.unwrap_or(String::from("nak-backend")));
+        let mut cmd = pr::Command::new(&backend_name);
 
         cmd.env("RUST_BACKTRACE", "1");
         cmd.stdout(output_writer.into_stdio());
@@ -153,6 +445,19 @@ pub fn launch_backend(sender: mpsc::Sender<Event>, name: Option<String>) -> Resu
         cwd_for_remote: HashMap::new(),
         stdout_pipes: HashSet::new(),
         stderr_pipes: HashSet::new(),
+        known_commands: HashMap::new(),
+        listing: HashMap::new(),
+        reading: HashMap::new(),
+        needs_setup: false,
+        running: None,
+        started_with: HashMap::new(),
+        heartbeat: Settings::default().heartbeat(),
+        next_heartbeat: None,
+        pinging: HashSet::new(),
+        latency: HashMap::new(),
+        link_capabilities: Vec::new(),
+        lost: None,
+        abandoned: HashSet::new(),
     };
 
     let mut endpoint = Endpoint::new(
@@ -162,30 +467,42 @@ pub fn launch_backend(sender: mpsc::Sender<Event>, name: Option<String>) -> Resu
     let root = endpoint.root();
     endpoint.handler.waiting_for_remote = Some(root);
 
+    let (wake, mut waker) = os_pipe::pipe()?;
+    set_nonblocking(wake.as_raw_fd())?;
+    // A full pipe already says there's something to read.
+    set_nonblocking(waker.as_raw_fd())?;
+
     let mut output = BufReader::new(output_reader);
 
     let mut input = String::new();
     output.read_line(&mut input)?;
-    assert_eq!(input, "nxQh6wsIiiFomXWE+7HQhQ==\n");
+    let theirs = Hello::expect(&input, &format!("the backend ({})", backend_name))?;
+    let ours = Hello::ours(env!("CARGO_PKG_VERSION"));
+    endpoint.trans.send(ours.to_line().as_bytes())?;
+    endpoint.handler.link_capabilities = ours.shared_capabilities(&theirs);
 
     thread::spawn(move || {
-        loop {
+        let why = loop {
             let mut input = String::new();
-            match output.read_line(&mut input) {
-                Ok(n) => {
-                    if n == 0 {
-                        continue;
-                    }
-                    let rpc: Response = serde_json::from_str(&input).unwrap();
-
-                    sender.send(Event::Remote(rpc)).unwrap();
-                }
-                Err(error) => eprintln!("error: {}", error),
+            let event = match output.read_line(&mut input) {
+                Ok(0) => break "the backend exited".to_string(),
+                Ok(_) => match serde_json::from_str(&input) {
+                    Ok(rpc) => Event::Remote(rpc),
+                    Err(e) => break format!("bad response from the backend: {}", e),
+                },
+                Err(e) => break format!("couldn't read from the backend: {}", e),
+            };
+            if sender.send(event).is_err() {
+                return;
             }
+            let _ = waker.write(&[0]);
+        };
+        if sender.send(Event::Disconnected(why)).is_ok() {
+            let _ = waker.write(&[0]);
         }
     });
 
-    Ok(endpoint)
+    Ok((endpoint, wake))
 }
 
 pub trait EndpointExt {
@@ -200,6 +517,23 @@ pub trait EndpointExt {
     fn finish_edit(&mut self, command_id: ProcessId, edit_id: usize, data: Vec<u8>) -> Result<(), Error>;
 
     fn cancel(&mut self, id: ProcessId) -> Result<(), Error>;
+
+    /// Start reading `pipe`, with flow control.
+    fn read_pipe(&mut self, remote: RemoteId, pipe: ReadPipe) -> Result<(), Error>;
+
+    /// Ping every remote that isn't already being pinged and can answer, measuring the round
+    /// trip.  Those that don't answer within `timeout` are lost.
+    fn heartbeat(&mut self, timeout: Duration) -> Result<(), Error>;
+
+    /// Pop the top remote without asking it to close, as it can't be reached.
+    fn forget_top_remote(&mut self) -> Result<(), Error>;
+
+    /// Pings the remotes if a heartbeat's due and fails the calls that have run out of time,
+    /// returning when to next tick.
+    fn tick(&mut self, now: Instant) -> Result<Option<Instant>, Error>;
+
+    /// Nothing more will come from the backend, so every remote is lost.
+    fn disconnected(&mut self, why: &str) -> Result<(), Error>;
 }
 
 
@@ -245,7 +579,8 @@ impl EndpointExt for BackendEndpoint {
 
     fn begin_remote(&mut self, c: Command) -> Result<RemoteId, Error> {
         let cur_remote = self.cur_remote();
-        let remote = self.remote(cur_remote, c)?;
+        let remote = self.remote(cur_remote, c.clone())?;
+        self.handler.started_with.insert(remote, c);
         assert!(self.handler.waiting_for_remote.is_none());
         self.handler.waiting_for_remote = Some(remote);
         Ok(remote)
@@ -253,9 +588,20 @@ impl EndpointExt for BackendEndpoint {
 
     fn end_remote(&mut self) -> Result<(), Error> {
         let cur_remote = self.handler.remotes.pop().unwrap().0;
+        self.handler.known_commands.remove(&cur_remote);
+        self.handler.listing.remove(&cur_remote);
+        self.handler.latency.remove(&cur_remote);
         Ok(self.close_remote(cur_remote)?)
     }
 
+    fn forget_top_remote(&mut self) -> Result<(), Error> {
+        let cur_remote = self.handler.remotes.pop().unwrap().0;
+        self.handler.known_commands.remove(&cur_remote);
+        self.handler.listing.remove(&cur_remote);
+        self.handler.latency.remove(&cur_remote);
+        self.forget_remote(cur_remote)
+    }
+
     fn finish_edit(&mut self, command_id: ProcessId, edit_id: usize, data: Vec<u8>) -> Result<(), Error> {
         // let cur_remote = self.remotes.pop().unwrap();
         Ok(self.finish_edit(command_id, edit_id, data)?)
@@ -264,4 +610,74 @@ impl EndpointExt for BackendEndpoint {
     fn cancel(&mut self, id: ProcessId) -> Result<(), Error> {
         Ok(self.close_process(id)?)
     }
-}
\ No newline at end of file
+
+    fn read_pipe(&mut self, remote: RemoteId, pipe: ReadPipe) -> Result<(), Error> {
+        self.pipe_begin_read(remote, pipe)?;
+        self.pipe_read(remote, pipe, READ_WINDOW)?;
+        self.handler.reading.insert(pipe.to_generic(), PipeReading {
+            remote,
+            received: 0,
+            granted: READ_WINDOW,
+        });
+        Ok(())
+    }
+
+    fn heartbeat(&mut self, timeout: Duration) -> Result<(), Error> {
+        // Pings are relayed by every remote on the way, so they all have to understand them.
+        let reachable: Vec<RemoteId> = self.handler.remotes.iter()
+            .take_while(|(_, info)| info.supports(capability::PING))
+            .map(|&(id, _)| id)
+            .filter(|id| !self.handler.pinging.contains(id))
+            .collect();
+        for remote in reachable {
+            let sent = Instant::now();
+            let pinged = self.call(remote, Query::Ping, Some(timeout), Box::new(move |endpoint, answer| {
+                endpoint.handler.pinging.remove(&remote);
+                match answer {
+                    Ok(_) => {
+                        endpoint.handler.latency.insert(remote, sent.elapsed());
+                    }
+                    Err(ref e) if ErrorKind::of(e) == ErrorKind::TimedOut => {
+                        endpoint.handler.lose(remote, &format!("no answer for {}s", timeout.as_secs()));
+                    }
+                    Err(e) => endpoint.handler.lose(remote, &e.to_string()),
+                }
+                Ok(())
+            }));
+            match pinged {
+                Ok(_) => {
+                    self.handler.pinging.insert(remote);
+                }
+                Err(e) => self.handler.lose(remote, &e.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, now: Instant) -> Result<Option<Instant>, Error> {
+        let heartbeat = match (self.handler.heartbeat, self.handler.next_heartbeat) {
+            (Some(_), Some(due)) if due > now => Some(due),
+            (Some(interval), _) => {
+                self.heartbeat(interval * HEARTBEATS_MISSED)?;
+                self.handler.next_heartbeat = Some(now + interval);
+                Some(now + interval)
+            }
+            (None, _) => None,
+        };
+        self.expire_calls(now)?;
+        Ok(match (self.next_deadline(), heartbeat) {
+            (Some(call), Some(heartbeat)) => Some(call.min(heartbeat)),
+            (deadline, None) | (None, deadline) => deadline,
+        })
+    }
+
+    fn disconnected(&mut self, why: &str) -> Result<(), Error> {
+        let root = self.root();
+        self.handler.lose(root, why);
+        if self.handler.lost.is_none() {
+            // It never got as far as being ready, so there's nothing to recover.
+            return Err(format_err!("lost the connection to the backend: {}", why));
+        }
+        self.fail_calls(why)
+    }
+}
@@ -1,21 +1,29 @@
 
-use std::io::{stdin, stdout, Write};
+use std::collections::VecDeque;
+use std::io::{stdout, Write};
 use std::io;
+use std::iter;
+use std::os::unix::io::RawFd;
+use std::time::Instant;
 
 use failure::Error;
-use termion::input::TermRead;
+use termion::event::{self, Event as TermEvent, Key};
 use termion::raw::IntoRawMode;
 use liner;
 use liner::{KeyMap, Editor, Buffer, KeyBindings, Emacs};
 use liner::EventHandler;
-use dirs;
 
 use protocol::Command;
 
-use crate::comm::BackendEndpoint;
-use crate::parse::{Ast, Cmd, parse_input, Target, Stream};
+use crate::comm::{BackendEndpoint, EndpointExt};
+use crate::parse::{Ast, Cmd, parse_input, parse_partial, Target, Stream, Token, TokenKind};
 use crate::prefs::Prefs;
 use crate::plan::{PlanBuilder, Plan, Remotes, RemoteRef};
+use crate::history::{History, HistoryEntry};
+use crate::render::{self, Style};
+use crate::highlight::highlight;
+use crate::builtins;
+use crate::script::Value;
 
 fn check_single_arg<'a>(items: impl Iterator<Item=String>) -> Result<String, Error> {
     let mut items = items;
@@ -30,9 +38,23 @@ fn check_single_arg<'a>(items: impl Iterator<Item=String>) -> Result<String, Err
     }
 }
 
+/// Commands that are handled specially, rather than being run as a program.
+pub const BUILTINS: &[&str] = &["cd", "micro", "nak", "alias", "unalias", "reload", "time"];
+
+// `~` and `~/...` are the home directory of the remote the command runs on, not this one.
+fn expand_tilde(word: String, home: Option<&String>) -> String {
+    match home {
+        Some(home) if word == "~" => home.clone(),
+        Some(home) if word.starts_with("~/") => format!("{}{}", home.trim_end_matches('/'), &word[1..]),
+        _ => word,
+    }
+}
+
 fn convert_single(_remotes: &Remotes, prefs: &Prefs, cmd: &Cmd) -> Result<Command, Error> {
 
-    let items = prefs.expand(cmd.words.iter().map(|w| w.expand_string()).collect());
+    let items = prefs.expand_command(cmd.words.iter().map(|w| w.expand_string()).collect())?;
+    let home = prefs.remote().and_then(|r| r.home_dir.as_ref());
+    let items: Vec<String> = items.into_iter().map(|w| expand_tilde(w, home)).collect();
 
     let mut it = items.into_iter();
     let head = it.next().unwrap();
@@ -65,7 +87,7 @@ fn convert_ast(remotes: &Remotes, prefs: &Prefs, ast: &Ast, plan: &mut PlanBuild
             stdin
         }
         Ast::Sequence(_head, _clauses) => {
-            panic!();
+            return Err(format_err!("running several commands in sequence isn't supported yet"));
         }
         Ast::Redirect(head, clauses) => {
             for clause in clauses.iter().rev() {
@@ -95,10 +117,28 @@ fn convert_ast(remotes: &Remotes, prefs: &Prefs, ast: &Ast, plan: &mut PlanBuild
     })
 }
 
+// `time` applies to everything after it (pipes included), so it's taken off before parsing.
+fn strip_time(input: &str) -> Result<(&str, bool), Error> {
+    let trimmed = input.trim_start();
+    if !trimmed.starts_with("time") {
+        return Ok((input, false));
+    }
+    let rest = &trimmed["time".len()..];
+    if rest.trim().is_empty() {
+        Err(format_err!("usage: time <command>"))
+    } else if rest.starts_with(char::is_whitespace) {
+        Ok((rest, true))
+    } else {
+        Ok((input, false))
+    }
+}
+
 fn parse_command_simple(remotes: &Remotes, prefs: &Prefs, input: &str) -> Result<Plan, Error> {
-    let cmd = parse_input(input);
+    let (input, timed) = strip_time(input)?;
+    let cmd = parse_input(input).map_err(|e| format_err!("{}", e))?;
 
     let mut p = PlanBuilder::new();
+    p.set_timed(timed);
 
     let stdout = p.pipe();
     let stderr = p.pipe();
@@ -119,9 +159,28 @@ impl liner::Completer for SimpleCompleter {
     }
 }
 
+/// What to keep doing while waiting on the user to type a command.
+pub trait Idle {
+    /// Readable whenever `run` has something new to deal with.
+    fn wake_fd(&self) -> RawFd;
+    /// Deals with whatever's come in from the remotes, returning when it next needs to run.
+    fn run(&mut self, backend: &mut BackendEndpoint) -> Result<Option<Instant>, Error>;
+}
+
 pub trait Reader {
-    fn get_command(&mut self, prompt: String, backend: &BackendEndpoint) -> Result<Plan, Error>;
+    fn get_command(&mut self, prompt: String, backend: &mut BackendEndpoint, idle: &mut dyn Idle) -> Result<Plan, Error>;
     fn hacky_save_history(&mut self);
+    fn prefs(&self) -> &Prefs;
+}
+
+/// The prefs in effect on the remote at the top of the stack.
+pub fn current_prefs(prefs: &Prefs, backend: &BackendEndpoint) -> Prefs {
+    let remotes = &backend.handler.remotes;
+    prefs.for_remote(&remotes.last().unwrap().1, remotes.len())
+        .with_commands(backend.handler.known_commands.get(&backend.cur_remote()))
+        .with_stack(remotes.iter()
+            .map(|(id, info)| (info.clone(), backend.handler.latency.get(id).cloned()))
+            .collect())
 }
 
 pub struct SingleCommandReader {
@@ -139,12 +198,17 @@ impl SingleCommandReader {
 }
 
 impl Reader for SingleCommandReader {
-    fn get_command(&mut self, _prompt: String, _backend: &BackendEndpoint) -> Result<Plan, Error> {
+    fn get_command(&mut self, _prompt: String, backend: &mut BackendEndpoint, _idle: &mut dyn Idle) -> Result<Plan, Error> {
         if let Some(text) = self.text.take() {
+            if let Some(result) = builtins::run(&mut self.prefs, &text, &mut stdout()) {
+                result?;
+                return Ok(Plan::empty());
+            }
+
             let remotes = &Remotes {
                 stack: vec![RemoteRef(0)],
             };
-            let parsed = parse_command_simple(&remotes, &self.prefs, &text)?;
+            let parsed = parse_command_simple(&remotes, &current_prefs(&self.prefs, backend), &text)?;
 
             Ok(parsed)
         } else {
@@ -155,64 +219,317 @@ impl Reader for SingleCommandReader {
     }
     fn hacky_save_history(&mut self) {
     }
+
+    fn prefs(&self) -> &Prefs {
+        &self.prefs
+    }
 }
 
 pub struct SimpleReader {
     prefs: Prefs,
     ctx: liner::Context,
+    history: History,
 }
 
 impl SimpleReader {
     pub fn new(prefs: Prefs) -> Result<SimpleReader, Error> {
-        let mut history = liner::History::new();
-        history.set_file_name(Some(dirs::home_dir().unwrap().join(".config").join("nak").join("history.nak").into_os_string().into_string().unwrap()));
-        match history.load_history() {
-            Ok(_) => {}
-            Err(e) => {
-                if e.kind() != io::ErrorKind::NotFound {
-                    return Err(e.into());
-                }
-            }
+        let history = History::load()?;
+
+        // liner keeps its own copy for up-arrow; we take care of saving it.
+        let mut liner_history = liner::History::new();
+        for entry in history.entries() {
+            liner_history.push(Buffer::from(entry.text.clone()))?;
         }
+
         Ok(SimpleReader {
             prefs,
             ctx: liner::Context {
-                history,
+                history: liner_history,
                 completer: Some(Box::new(SimpleCompleter)),
                 word_divider_fn: Box::new(liner::get_buffer_words),
                 key_bindings: KeyBindings::Emacs,
-            }
+            },
+            history,
         })
     }
 }
 
-impl Reader for SimpleReader {
-    fn get_command(&mut self, prompt: String, _backend: &BackendEndpoint) -> Result<Plan, Error> {
-
-        fn handle_keys<'a, T, W: Write, M: KeyMap<'a, W, T>>(
-            mut keymap: M,
-            handler: &mut EventHandler<W>,
-        ) -> io::Result<String>
-        where
-            String: From<M>,
-        {
-            let stdin = stdin();
-            for c in stdin.keys() {
-                if keymap.handle_key(c.unwrap(), handler)? {
-                    break;
+fn is_alt_right(raw: &[u8]) -> bool {
+    // xterm-style modified arrow keys, which termion doesn't know how to parse.
+    raw == b"\x1b[1;3C" || raw == b"\x1b[1;9C"
+}
+
+fn next_word(text: &str) -> &str {
+    let start = text.len() - text.trim_start().len();
+    let end = text[start..].find(' ').map(|i| start + i).unwrap_or(text.len());
+    &text[..end]
+}
+
+fn is_incomplete<W: Write>(ed: &Editor<W>) -> bool {
+    match parse_input(&ed.current_buffer().to_string()) {
+        Err(e) => e.incomplete,
+        Ok(_) => false,
+    }
+}
+
+// If `text` ends with an abbreviation: the byte offset it starts at, and what it expands to.
+fn abbreviation_at_end(prefs: &Prefs, text: &str) -> Option<(usize, String)> {
+    let partial = parse_partial(text);
+
+    // The words of the command being typed, last first.
+    let stage: Vec<&Token> = partial.tokens.iter().rev()
+        .take_while(|t| t.kind == TokenKind::Command || t.kind == TokenKind::Argument)
+        .collect();
+    if stage.first()?.span.end != text.len() {
+        return None;
+    }
+
+    let words: Vec<&str> = stage.iter().rev().map(|t| &text[t.span.start..t.span.end]).collect();
+    if words.iter().any(|w| w.starts_with('"')) {
+        return None;
+    }
+
+    let (n, replacement) = prefs.abbreviation(&words)?;
+    Some((stage[n - 1].span.start, replacement))
+}
+
+fn expand_abbreviation<W: Write>(ed: &mut Editor<W>, prefs: &Prefs) -> io::Result<()> {
+    let text = ed.current_buffer().to_string();
+    if let Some((start, replacement)) = abbreviation_at_end(prefs, &text) {
+        ed.move_cursor_to_end_of_line()?;
+        ed.delete_until(text[..start].chars().count())?;
+        ed.insert_str_after_cursor(&replacement)?;
+    }
+    Ok(())
+}
+
+// What to add to `text` from the script's `complete(words)`, which returns candidates for the
+// last word: the part they all have in common, plus a space if there's only one.
+fn script_completion(prefs: &Prefs, text: &str) -> Result<Option<String>, Error> {
+    let mut words: Vec<String> = text.split_whitespace().map(|w| w.to_string()).collect();
+    if text.trim_end().len() != text.len() || words.is_empty() {
+        words.push(String::new());
+    }
+
+    let candidates = match prefs.call_script("complete", vec![Value::from_words(&words)])? {
+        None | Some(Value::Nil) => return Ok(None),
+        Some(res) => res.into_words().ok_or_else(|| format_err!("the script's `complete` should return a list of strings or nil"))?,
+    };
+    let current = words.last().unwrap();
+    let candidates: Vec<&String> = candidates.iter().filter(|c| c.starts_with(current.as_str())).collect();
+
+    let first = match candidates.first() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+    let mut common = first.as_str();
+    for c in &candidates[1..] {
+        let len = common.char_indices().zip(c.chars())
+            .find(|&((_, a), b)| a != b)
+            .map_or(common.len().min(c.len()), |((i, _), _)| i);
+        common = &common[..len];
+    }
+
+    let mut res = common[current.len()..].to_string();
+    if candidates.len() == 1 {
+        res.push(' ');
+    }
+    Ok(Some(res))
+}
+
+/// Keys from the terminal, read without blocking anything else `Idle` needs to do.  (termion's
+/// own reader holds on to bytes it's read ahead, so it can't be polled.)
+struct Keys {
+    pending: VecDeque<u8>,
+}
+
+impl Keys {
+    fn new() -> Keys {
+        Keys { pending: VecDeque::new() }
+    }
+
+    /// The next key, or `None` if `wake` became readable or `deadline` passed first.
+    fn next(&mut self, wake: RawFd, deadline: Option<Instant>) -> io::Result<Option<TermEvent>> {
+        if self.pending.is_empty() {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    // Rounded up, so as not to wake just before the deadline and find nothing to do.
+                    ((wait.as_micros() + 999) / 1000).min(i32::MAX as u128) as i32
                 }
+                None => -1,
+            };
+            let mut fds = [
+                libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: wake, events: libc::POLLIN, revents: 0 },
+            ];
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(None),
+                    _ => Err(e),
+                };
+            }
+            if fds[0].revents == 0 {
+                return Ok(None);
             }
 
-            Ok(keymap.into())
+            let mut buf = [0u8; 1024];
+            let n = unsafe { libc::read(0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            match n {
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    return match e.kind() {
+                        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => Ok(None),
+                        _ => Err(e),
+                    };
+                }
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.pending.extend(&buf[..n as usize]),
+            }
         }
 
+        let first = self.pending.pop_front().unwrap();
+        if first == b'\x1b' && self.pending.is_empty() {
+            return Ok(Some(TermEvent::Key(Key::Esc)));
+        }
+        let mut raw = vec![first];
+        let pending = &mut self.pending;
+        let mut rest = iter::from_fn(|| pending.pop_front().map(Ok))
+            .inspect(|b: &io::Result<u8>| if let Ok(b) = b { raw.push(*b) });
+        let res = event::parse_event(first, &mut rest);
+        Ok(Some(res.unwrap_or(TermEvent::Unsupported(raw))))
+    }
+}
+
+impl Reader for SimpleReader {
+    fn get_command(&mut self, prompt: String, backend: &mut BackendEndpoint, idle: &mut dyn Idle) -> Result<Plan, Error> {
+        if self.prefs.changed_on_disk() {
+            match self.prefs.reload() {
+                Ok(()) => eprintln!("nak: prefs reloaded"),
+                Err(e) => eprintln!("nak: keeping the previous prefs: {}", e),
+            }
+        }
+
+        let (hostname, working_dir) = {
+            let top_remote = &backend.handler.remotes.last().unwrap().1;
+            (top_remote.hostname.clone(), top_remote.working_dir.clone())
+        };
+
+        let history = &self.history;
+        let suggestion_for = |ed: &Editor<_>| -> Option<String> {
+            if !ed.cursor_is_at_end_of_line() {
+                return None;
+            }
+            let text = ed.current_buffer().to_string();
+            history.suggest(&hostname, &working_dir, &text).map(|s| s[text.len()..].to_string())
+        };
+
+        let base_prefs = &self.prefs;
+        let redraw = |ed: &Editor<_>, prefs: &Prefs, backend: &BackendEndpoint| -> io::Result<()> {
+            let commands = backend.handler.known_commands.get(&backend.cur_remote());
+            let text = ed.current_buffer().to_string();
+            let suggestion = suggestion_for(ed).unwrap_or_default();
+
+            let mut spans: Vec<(Style, &str)> = highlight(&text, prefs, commands).into_iter()
+                .map(|(style, range)| (style, &text[range]))
+                .collect();
+            spans.push((Style::Suggestion, &suggestion));
+
+            render::redraw(&mut stdout(), &prompt, &spans, ed.cursor())
+        };
+
         let buffer = Buffer::new();
 
         let res = {
             let stdout = stdout().into_raw_mode().unwrap();
-            let ed = Editor::new_with_init_buffer(stdout, prompt, &mut self.ctx, buffer)?;
-            match handle_keys(Emacs::new(ed), &mut |_| {}) {
-                Ok(res) => res,
+            let ed = Editor::new_with_init_buffer(stdout, prompt.clone(), &mut self.ctx, buffer)?;
+            let mut keymap = Emacs::new(ed);
+            let handler: &mut EventHandler<_> = &mut |_| {};
+
+            let mut handle_events = || -> io::Result<()> {
+                let mut prefs = current_prefs(base_prefs, backend);
+                let prefs = &mut prefs;
+                let mut keys = Keys::new();
+                // Ticked straight away, so heartbeats carry on while the user types.
+                let mut deadline = Some(Instant::now());
+                redraw(keymap.editor(), prefs, backend)?;
+
+                loop {
+                    let event = match keys.next(idle.wake_fd(), deadline)? {
+                        Some(event) => event,
+                        None => {
+                            deadline = idle.run(backend)
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                            if backend.handler.lost.is_some() {
+                                // Dealt with once back out of the editor.
+                                write!(io::stdout(), "\r\n")?;
+                                return Err(io::ErrorKind::Interrupted.into());
+                            }
+                            // Commands may have been found, or the prefs reloaded.
+                            *prefs = current_prefs(base_prefs, backend);
+                            redraw(keymap.editor(), prefs, backend)?;
+                            continue;
+                        }
+                    };
+                    let suggestion = suggestion_for(keymap.editor());
+
+                    match event {
+                        TermEvent::Key(Key::Right) |
+                        TermEvent::Key(Key::Ctrl('f')) if keymap.editor().cursor_is_at_end_of_line() => {
+                            // Always intercepted at the end of the line, so that liner's own
+                            // (global) history suggestion never gets accepted.
+                            if let Some(suggestion) = suggestion {
+                                keymap.editor_mut().insert_str_after_cursor(&suggestion)?;
+                            }
+                        }
+                        TermEvent::Key(Key::Alt('f')) if suggestion.is_some() => {
+                            keymap.editor_mut().insert_str_after_cursor(next_word(&suggestion.unwrap()))?;
+                        }
+                        TermEvent::Unsupported(ref raw) if is_alt_right(raw) => {
+                            if let Some(suggestion) = suggestion {
+                                keymap.editor_mut().insert_str_after_cursor(next_word(&suggestion))?;
+                            }
+                        }
+                        TermEvent::Key(Key::Char('\n')) if is_incomplete(keymap.editor()) => {
+                            // Keep reading on a new line, rather than running half a command.
+                            let ed = keymap.editor_mut();
+                            ed.move_cursor_to_end_of_line()?;
+                            ed.insert_after_cursor('\n')?;
+                        }
+                        TermEvent::Key(Key::Char(c @ ' ')) |
+                        TermEvent::Key(Key::Char(c @ '\n')) if keymap.editor().cursor_is_at_end_of_line() => {
+                            // Expanded where the user can see (and edit) it, and so that history
+                            // records the real command.
+                            expand_abbreviation(keymap.editor_mut(), prefs)?;
+                            if keymap.handle_key(Key::Char(c), handler)? {
+                                return Ok(());
+                            }
+                        }
+                        TermEvent::Key(Key::Char('\t')) if keymap.editor().cursor_is_at_end_of_line() => {
+                            let text = keymap.editor().current_buffer().to_string();
+                            match script_completion(prefs, &text) {
+                                Ok(Some(completion)) => keymap.editor_mut().insert_str_after_cursor(&completion)?,
+                                Ok(None) => if keymap.handle_key(Key::Char('\t'), handler)? {
+                                    return Ok(());
+                                },
+                                Err(e) => write!(io::stdout(), "\r\nnak: {}\r\n", e)?,
+                            }
+                        }
+                        TermEvent::Key(key) => {
+                            if keymap.handle_key(key, handler)? {
+                                return Ok(());
+                            }
+                        }
+                        _ => continue,
+                    }
+
+                    redraw(keymap.editor(), prefs, backend)?;
+                }
+            };
+
+            match handle_events() {
+                Ok(()) => String::from(keymap),
                 Err(e) => {
                     return match e.kind() {
                         io::ErrorKind::Interrupted => Ok(Plan::empty()),
@@ -231,15 +548,42 @@ impl Reader for SimpleReader {
             stack: vec![RemoteRef(0)],
         };
 
-        let parsed = parse_command_simple(&remotes, &self.prefs, &res)?;
+        if !res.trim().is_empty() {
+            self.ctx.history.push(Buffer::from(res.clone()))?;
+            self.history.push(HistoryEntry {
+                hostname: Some(hostname),
+                working_dir: Some(working_dir),
+                text: res.clone(),
+            });
+        }
 
-        self.ctx.history.push(Buffer::from(res))?;
+        if let Some(result) = builtins::run(&mut self.prefs, &res, &mut stdout()) {
+            if let Err(e) = result {
+                eprintln!("nak: {}", e);
+            }
+            return Ok(Plan::empty());
+        }
+
+        // A typo shouldn't take the whole shell down.
+        let parsed = match parse_command_simple(&remotes, &current_prefs(&self.prefs, backend), &res) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("nak: {}", e);
+                Plan::empty()
+            }
+        };
 
         Ok(parsed)
     }
 
     fn hacky_save_history(&mut self) {
-        self.ctx.history.commit_history()
+        if let Err(e) = self.history.commit() {
+            eprintln!("failed to save history: {}", e);
+        }
+    }
+
+    fn prefs(&self) -> &Prefs {
+        &self.prefs
     }
 }
 
@@ -248,7 +592,7 @@ mod tests {
     use super::*;
 
     use serde_json;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::{Read, Write};
 
     #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -286,6 +630,80 @@ mod tests {
 
         File::create("tests/plan.actual.json").unwrap().write_all(serde_json::to_string_pretty(&actual).unwrap().as_bytes()).unwrap();
     }
+
+    #[test]
+    fn abbreviations() {
+        let prefs = Prefs::parse("
+            alias --abbr gco=git checkout
+            alias --abbr g s|st=git status
+            alias --abbr --anywhere L=| less
+            alias gl=git log
+        ").unwrap();
+
+        assert_eq!(abbreviation_at_end(&prefs, "gco"), Some((0, "git checkout".to_string())));
+        assert_eq!(abbreviation_at_end(&prefs, "ls | g st"), Some((5, "git status".to_string())));
+        assert_eq!(abbreviation_at_end(&prefs, "cat x L"), Some((6, "| less".to_string())));
+        assert_eq!(abbreviation_at_end(&prefs, "echo gco"), None);
+        assert_eq!(abbreviation_at_end(&prefs, "gco "), None);
+        assert_eq!(abbreviation_at_end(&prefs, "gl"), None);
+
+        assert!(Prefs::parse("alias --abbr g ...=git ...").is_err());
+    }
+
+    #[test]
+    fn describe_expanded() {
+        let remotes = &Remotes {
+            stack: vec![RemoteRef(0)],
+        };
+        let prefs = Prefs::parse("alias ll ...=ls -l ...").unwrap();
+        let describe = |input| parse_command_simple(remotes, &prefs, input).unwrap().describe();
+
+        assert_eq!(describe("ll /tmp | grep \"a b\" > out"), "ls -l /tmp | grep \"a b\" > out");
+        assert_eq!(describe("cd .."), "cd ..");
+        assert_eq!(Plan::empty().describe(), "");
+    }
+
+    #[test]
+    fn tilde() {
+        let home = "/home/me".to_string();
+        assert_eq!(expand_tilde("~".to_string(), Some(&home)), "/home/me");
+        assert_eq!(expand_tilde("~/src".to_string(), Some(&home)), "/home/me/src");
+        assert_eq!(expand_tilde("~other".to_string(), Some(&home)), "~other");
+        assert_eq!(expand_tilde("~/src".to_string(), None), "~/src");
+    }
+
+    #[test]
+    fn time() {
+        let remotes = &Remotes {
+            stack: vec![RemoteRef(0)],
+        };
+        let prefs = Prefs::default();
+        let timed = |input| parse_command_simple(remotes, &prefs, input).map(|p| (p.timed, p.describe()));
+
+        assert_eq!(timed("time ls | wc").unwrap(), (true, "ls | wc".to_string()));
+        assert_eq!(timed("  time  make").unwrap(), (true, "make".to_string()));
+        assert_eq!(timed("timeout 1 make").unwrap(), (false, "timeout 1 make".to_string()));
+        assert!(timed("time").is_err());
+    }
+
+    #[test]
+    fn script_completions() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir.path().join("prefs.nak");
+        fs::write(dir.path().join("prefs.nks"), r#"
+            fn complete(words) {
+                if words[0] == "make" && len(words) == 2 { return ["build", "bench", "test"]; }
+            }
+        "#).unwrap();
+        fs::write(&file_name, "script prefs.nks\n").unwrap();
+        let prefs = Prefs::load_from(file_name).unwrap();
+
+        assert_eq!(script_completion(&prefs, "make ").unwrap(), Some("".to_string()));
+        assert_eq!(script_completion(&prefs, "make b").unwrap(), Some("".to_string()));
+        assert_eq!(script_completion(&prefs, "make bu").unwrap(), Some("ild ".to_string()));
+        assert_eq!(script_completion(&prefs, "make x").unwrap(), None);
+        assert_eq!(script_completion(&prefs, "ls ").unwrap(), None);
+    }
 }
 
 
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::edit::BUILTINS;
+use crate::parse::{parse_partial, TokenKind, KEYWORDS};
+use crate::prefs::Prefs;
+use crate::render::Style;
+
+fn command_style(word: &str, prefs: &Prefs, commands: Option<&HashSet<String>>) -> Style {
+    let name = word.trim_matches('"');
+
+    if KEYWORDS.contains(&name) {
+        Style::Operator
+    } else if BUILTINS.contains(&name) {
+        Style::Command
+    } else if prefs.is_alias(name) {
+        Style::Alias
+    } else if name.contains('/') {
+        // Could be anywhere on the remote; don't guess.
+        Style::Plain
+    } else {
+        match commands {
+            Some(commands) if !commands.is_empty() => {
+                if commands.contains(name) {
+                    Style::Command
+                } else {
+                    Style::UnknownCommand
+                }
+            }
+            _ => Style::Plain,
+        }
+    }
+}
+
+/// Split `input` into styled runs (as byte ranges), covering all of it.
+///
+/// `commands` is whatever we know about the programs available on the current remote;
+/// this never waits on the remote to find out more.
+pub fn highlight(input: &str, prefs: &Prefs, commands: Option<&HashSet<String>>) -> Vec<(Style, Range<usize>)> {
+    let partial = parse_partial(input);
+
+    let mut styles = vec![Style::Plain; input.len()];
+
+    for token in &partial.tokens {
+        let text = &input[token.span.start..token.span.end];
+        let style = match token.kind {
+            TokenKind::Command => command_style(text, prefs, commands),
+            TokenKind::Argument |
+            TokenKind::Path => if text.starts_with('"') {
+                Style::Quoted
+            } else {
+                Style::Plain
+            },
+            TokenKind::Operator => Style::Operator,
+            TokenKind::Redirect => Style::Redirect,
+        };
+        for s in &mut styles[token.span.start..token.span.end] {
+            *s = style;
+        }
+    }
+
+    // Unfinished input isn't an error yet; the user is probably still typing.
+    if let Some(error) = partial.error.filter(|e| !e.incomplete) {
+        for s in &mut styles[error.pos..] {
+            *s = Style::Error;
+        }
+    }
+
+    let mut runs: Vec<(Style, Range<usize>)> = Vec::new();
+    for (i, &style) in styles.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if run.0 == style => run.1.end = i + 1,
+            _ => runs.push((style, i..i + 1)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styles(input: &str, commands: &[&str]) -> Vec<(Style, String)> {
+        let commands: HashSet<String> = commands.iter().map(|c| c.to_string()).collect();
+        highlight(input, &Prefs::default(), Some(&commands)).into_iter()
+            .map(|(style, range)| (style, input[range].to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn highlight_commands() {
+        assert_eq!(styles("ls -l | nope > out", &["ls"]), vec![
+            (Style::Command, "ls".to_string()),
+            (Style::Plain, " -l ".to_string()),
+            (Style::Operator, "|".to_string()),
+            (Style::Plain, " ".to_string()),
+            (Style::UnknownCommand, "nope".to_string()),
+            (Style::Plain, " ".to_string()),
+            (Style::Redirect, ">".to_string()),
+            (Style::Plain, " out".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn highlight_errors() {
+        assert_eq!(styles("cd a\"b", &["ls"]), vec![
+            (Style::Command, "cd".to_string()),
+            (Style::Plain, " a".to_string()),
+            (Style::Error, "\"b".to_string()),
+        ]);
+        assert_eq!(styles("cd \"a b", &["ls"]), vec![
+            (Style::Command, "cd".to_string()),
+            (Style::Plain, " ".to_string()),
+            (Style::Quoted, "\"a b".to_string()),
+        ]);
+    }
+}
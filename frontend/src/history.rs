@@ -0,0 +1,134 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::io;
+use std::path::PathBuf;
+
+use failure::Error;
+use serde_json;
+use dirs;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub hostname: Option<String>,
+    pub working_dir: Option<String>,
+    pub text: String,
+}
+
+/// Command history, annotated with where each command was run.
+///
+/// Stored as one json object per line.  Lines that don't parse (e.g. from the old plain-text
+/// format) are kept as entries without a host or directory.
+pub struct History {
+    file_name: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+    saved: usize,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            file_name: None,
+            entries: Vec::new(),
+            saved: 0,
+        }
+    }
+
+    pub fn load() -> Result<History, Error> {
+        History::load_from(dirs::home_dir().unwrap().join(".config").join("nak").join("history.nak"))
+    }
+
+    pub fn load_from(file_name: PathBuf) -> Result<History, Error> {
+        let mut entries = Vec::new();
+
+        match File::open(&file_name) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    if line.is_empty() {
+                        continue;
+                    }
+                    entries.push(serde_json::from_str(&line).unwrap_or(HistoryEntry {
+                        hostname: None,
+                        working_dir: None,
+                        text: line,
+                    }));
+                }
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let saved = entries.len();
+
+        Ok(History {
+            file_name: Some(file_name),
+            entries,
+            saved,
+        })
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        self.entries.push(entry);
+    }
+
+    /// The most recent command run in the same place that extends `prefix`.
+    pub fn suggest(&self, hostname: &str, working_dir: &str, prefix: &str) -> Option<&str> {
+        if prefix.is_empty() {
+            return None;
+        }
+
+        self.entries.iter().rev()
+            .filter(|e| e.hostname.as_ref().map(|h| h.as_str()) == Some(hostname))
+            .filter(|e| e.working_dir.as_ref().map(|d| d.as_str()) == Some(working_dir))
+            .find(|e| e.text.len() > prefix.len() && e.text.starts_with(prefix))
+            .map(|e| e.text.as_str())
+    }
+
+    /// Append any entries pushed since the last commit to the history file.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if let Some(ref file_name) = self.file_name {
+            let mut f = OpenOptions::new().create(true).append(true).open(file_name)?;
+            for entry in &self.entries[self.saved..] {
+                f.write_all((serde_json::to_string(entry)? + "\n").as_bytes())?;
+            }
+        }
+        self.saved = self.entries.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(hostname: &str, working_dir: &str, text: &str) -> HistoryEntry {
+        HistoryEntry {
+            hostname: Some(hostname.to_string()),
+            working_dir: Some(working_dir.to_string()),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn suggest_most_recent_in_same_place() {
+        let mut h = History::new();
+        h.push(entry("a", "/x", "git status"));
+        h.push(entry("a", "/x", "git stash"));
+        h.push(entry("a", "/y", "git stash pop"));
+        h.push(entry("b", "/x", "git stash list"));
+
+        assert_eq!(h.suggest("a", "/x", "git st"), Some("git stash"));
+        assert_eq!(h.suggest("a", "/x", "git stat"), Some("git status"));
+        assert_eq!(h.suggest("a", "/y", "git"), Some("git stash pop"));
+        assert_eq!(h.suggest("a", "/x", "git stash"), None);
+        assert_eq!(h.suggest("c", "/x", "git"), None);
+        assert_eq!(h.suggest("a", "/x", ""), None);
+    }
+}
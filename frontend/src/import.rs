@@ -0,0 +1,345 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use dirs;
+
+use crate::history::{History, HistoryEntry};
+use crate::prefs::{Prefs, Rule};
+
+/// Something that couldn't be imported, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Skipped {
+    pub line: usize,
+    pub text: String,
+    pub reason: String,
+}
+
+// Split the arguments of an `alias` line into words, following shell quoting closely enough for
+// what people put in their rc files.  `None` if the quotes don't match up.
+fn split_words(text: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => word.push(chars.next()?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                word.push(chars.next()?);
+            }
+            ' ' | '\t' => {
+                if in_word {
+                    words.push(word.split_off(0));
+                    in_word = false;
+                }
+            }
+            // The rest of the line is a comment, or another command.
+            '#' | ';' if !in_word => break,
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
+
+// Characters that mean the alias needs a real shell to do what it says.
+const SHELL_SYNTAX: &[char] = &['$', '`', ';', '|', '&', '<', '>', '(', ')', '{', '}', '\'', '"', '\\', '*', '?', '~'];
+
+fn translate_alias(name: &str, value: &str) -> Result<Rule, String> {
+    if let Some(c) = value.chars().find(|c| SHELL_SYNTAX.contains(c)) {
+        return Err(format!("uses `{}`, which needs a shell function rather than an alias", c));
+    }
+    if value.trim().is_empty() {
+        return Err("empty alias".to_string());
+    }
+    Rule::parse_alias(&format!("{} ...={} ...", name, value)).map_err(|e| e.to_string())
+}
+
+/// Translate the `alias` lines of a bash or zsh rc file.
+pub fn parse_aliases(contents: &str) -> (Vec<Rule>, Vec<Skipped>) {
+    let mut rules = Vec::new();
+    let mut skipped = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let text = line.trim();
+        if !text.starts_with("alias ") {
+            continue;
+        }
+        let mut skip = |reason: &str| skipped.push(Skipped {
+            line: i + 1,
+            text: text.to_string(),
+            reason: reason.to_string(),
+        });
+
+        let words = match split_words(&text["alias ".len()..]) {
+            Some(words) => words,
+            None => {
+                skip("unbalanced quotes");
+                continue;
+            }
+        };
+
+        for word in words {
+            if word.starts_with('-') {
+                skip("`alias` options (e.g. zsh's global and suffix aliases) aren't supported");
+                break;
+            }
+            // Without a `=`, it's printing an alias rather than defining one.
+            let eq = match word.find('=') {
+                Some(eq) => eq,
+                None => continue,
+            };
+            match translate_alias(&word[..eq], &word[eq + 1..]) {
+                Ok(rule) => rules.push(rule),
+                Err(reason) => skip(&format!("{}: {}", &word[..eq], reason)),
+            }
+        }
+    }
+
+    (rules, skipped)
+}
+
+/// Plain bash history: one command per line, possibly with `#<timestamp>` lines in between.
+pub fn parse_bash_history(contents: &str) -> Vec<String> {
+    contents.lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter(|l| !(l.starts_with('#') && l[1..].chars().all(|c| c.is_ascii_digit())))
+        .map(|l| l.to_string())
+        .collect()
+}
+
+// zsh escapes some bytes in its history file: 0x83 means "the next byte, xor 32".
+fn unmetafy(data: &[u8]) -> String {
+    let mut res = Vec::with_capacity(data.len());
+    let mut it = data.iter();
+    while let Some(&b) = it.next() {
+        if b == 0x83 {
+            if let Some(&next) = it.next() {
+                res.push(next ^ 32);
+            }
+        } else {
+            res.push(b);
+        }
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+/// zsh history, in either the plain or `: <start>:<elapsed>;<command>` extended format.  A line
+/// ending in `\` continues onto the next.
+pub fn parse_zsh_history(data: &[u8]) -> Vec<String> {
+    let contents = unmetafy(data);
+    let mut entries = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in contents.lines() {
+        let line = match current.take() {
+            Some(mut cmd) => {
+                cmd.push('\n');
+                cmd.push_str(line);
+                cmd
+            }
+            None => {
+                if line.starts_with(": ") {
+                    match line.find(';') {
+                        Some(semi) => line[semi + 1..].to_string(),
+                        None => continue,
+                    }
+                } else {
+                    line.to_string()
+                }
+            }
+        };
+
+        if line.ends_with('\\') {
+            current = Some(line[..line.len() - 1].to_string());
+        } else if !line.trim().is_empty() {
+            entries.push(line);
+        }
+    }
+
+    entries.extend(current);
+    entries
+}
+
+/// fish history, which is a yaml-ish list of `- cmd: <command>` entries.
+pub fn parse_fish_history(contents: &str) -> Vec<String> {
+    contents.lines()
+        .filter(|l| l.starts_with("- cmd: "))
+        .map(|l| {
+            let mut cmd = String::new();
+            let mut chars = l["- cmd: ".len()..].chars();
+            while let Some(c) = chars.next() {
+                match (c, chars.clone().next()) {
+                    ('\\', Some('n')) => {
+                        cmd.push('\n');
+                        chars.next();
+                    }
+                    ('\\', Some('\\')) => {
+                        cmd.push('\\');
+                        chars.next();
+                    }
+                    (c, _) => cmd.push(c),
+                }
+            }
+            cmd
+        })
+        .collect()
+}
+
+fn read(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Import aliases from ~/.bashrc and ~/.zshrc into prefs, and history from bash, zsh and fish
+/// into nak's history, reporting anything that couldn't be translated.
+pub fn run(dry_run: bool) -> Result<(), Error> {
+    let home = dirs::home_dir().ok_or_else(|| format_err!("can't find the home directory"))?;
+
+    let mut prefs = Prefs::load()?;
+    let mut rules: Vec<Rule> = Vec::new();
+    let mut skipped_count = 0;
+
+    for name in &[".bashrc", ".zshrc"] {
+        let path = home.join(name);
+        let contents = match read(&path)? {
+            Some(data) => String::from_utf8_lossy(&data).into_owned(),
+            None => continue,
+        };
+
+        let (found, skipped) = parse_aliases(&contents);
+        let mut count = 0;
+        for rule in found {
+            let exists = prefs.aliases().iter().chain(&rules).any(|r| r.find_string() == rule.find_string());
+            if !exists {
+                rules.push(rule);
+                count += 1;
+            }
+        }
+        println!("{}: {} alias(es)", path.display(), count);
+
+        for s in &skipped {
+            println!("  skipped line {}: {}\n    {}", s.line, s.reason, s.text);
+        }
+        skipped_count += skipped.len();
+    }
+
+    let mut history = History::load()?;
+    let mut seen: HashSet<String> = history.entries().iter().map(|e| e.text.clone()).collect();
+    let sources: Vec<(PathBuf, fn(&[u8]) -> Vec<String>)> = vec![
+        (home.join(".bash_history"), |data| parse_bash_history(&String::from_utf8_lossy(data))),
+        (home.join(".zsh_history"), parse_zsh_history),
+        (home.join(".local/share/fish/fish_history"), |data| parse_fish_history(&String::from_utf8_lossy(data))),
+    ];
+
+    for (path, parse) in sources {
+        let data = match read(&path)? {
+            Some(data) => data,
+            None => continue,
+        };
+
+        let mut count = 0;
+        for text in parse(&data) {
+            if seen.insert(text.clone()) {
+                // We don't know where these were run, so they're never suggested as you type; they
+                // can still be found by going back through history.
+                history.push(HistoryEntry {
+                    hostname: None,
+                    working_dir: None,
+                    text,
+                });
+                count += 1;
+            }
+        }
+        println!("{}: {} history entries", path.display(), count);
+    }
+
+    if skipped_count > 0 {
+        println!("{} alias(es) couldn't be translated", skipped_count);
+    }
+
+    if dry_run {
+        for rule in &rules {
+            println!("would add: alias {}", rule);
+        }
+        return Ok(());
+    }
+
+    if !rules.is_empty() {
+        prefs.save_aliases(&rules, &[])?;
+    }
+    history.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases() {
+        let (rules, skipped) = parse_aliases("
+            alias ll='ls -l' la=\"ls -a\"
+            alias gs=git\\ status # comment
+            alias -g L='| less'
+            alias today='date +%F; cal'
+            alias ll
+            export alias=no
+        ");
+
+        assert_eq!(rules.iter().map(|r| r.to_string()).collect::<Vec<_>>(), vec![
+            "ll ...=ls -l ...",
+            "la ...=ls -a ...",
+            "gs ...=git status ...",
+        ]);
+        assert_eq!(skipped.iter().map(|s| s.line).collect::<Vec<_>>(), vec![4, 5]);
+    }
+
+    #[test]
+    fn histories() {
+        assert_eq!(parse_bash_history("ls\n#1530000000\ncd /tmp\n\n"), vec!["ls", "cd /tmp"]);
+
+        let zsh = b": 1530000000:0;ls -l\n: 1530000001:2;for x in a b; do\\\necho $x\\\ndone\ngit st\xc3\x83\xa9\n";
+        assert_eq!(parse_zsh_history(zsh), vec![
+            "ls -l",
+            "for x in a b; do\necho $x\ndone",
+            "git st\u{c9}",
+        ]);
+
+        let fish = "- cmd: ls -l\n  when: 1530000000\n- cmd: echo a\\nb \\\\\n  when: 1530000001\n";
+        assert_eq!(parse_fish_history(fish), vec!["ls -l", "echo a\nb \\"]);
+    }
+}
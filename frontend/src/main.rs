@@ -20,27 +20,42 @@ extern crate protocol;
 extern crate dirs;
 
 use std::sync::mpsc;
-use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use failure::Error;
 use structopt::StructOpt;
 
-use protocol::{Response, Command, WritePipes, ProcessId, Condition};
+use protocol::{Response, Command, WritePipes, ProcessId, Condition, RemoteInfo, ResourceUsage, RemoteId, capability};
 
 mod parse;
 mod edit;
 mod prefs;
 mod comm;
 mod plan;
+mod history;
+mod render;
+mod highlight;
+mod builtins;
+mod import;
+mod script;
 
 use crate::prefs::Prefs;
-use crate::comm::{BackendEndpoint, launch_backend, EndpointExt};
-use crate::edit::{SimpleReader, Reader, SingleCommandReader};
-use crate::plan::{Plan, RemoteStep, Step, Sink};
+use crate::comm::{BackendEndpoint, launch_backend, EndpointExt, Inbox, RunningPlan};
+use crate::edit::{SimpleReader, Reader, SingleCommandReader, current_prefs};
+use crate::plan::{Plan, RemoteStep, Step, Sink, RemoteRef};
+use crate::script::Value;
 
 #[derive(Debug)]
 pub enum Event {
     Remote(Response),
+    /// The backend's output ended, or something unreadable came out of it.
+    Disconnected(String),
     Key(termion::event::Key),
     CtrlC,
 }
@@ -58,20 +73,41 @@ pub enum Shell {
 }
 
 struct Exec<R: Reader> {
-    receiver: mpsc::Receiver<Event>,
+    inbox: Inbox,
     remote: BackendEndpoint,
     reader: R,
 }
 
 impl<R: Reader> Exec<R> {
+    /// Waits for the next event, or until the earliest outstanding call times out or the next
+    /// heartbeat is due, in which case there's no event.
+    fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        let deadline = match self.remote.tick(Instant::now())? {
+            Some(deadline) => deadline,
+            None => return Ok(Some(self.inbox.receiver.recv()?)),
+        };
+        let wait = deadline.saturating_duration_since(Instant::now());
+        match self.inbox.receiver.recv_timeout(wait) {
+            Ok(event) => Ok(Some(event)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(mpsc::RecvError.into()),
+        }
+    }
+
     fn one_loop(&mut self) -> Result<bool, Error> {
         if self.remote.handler.waiting_for_remote.is_some() {
-            let msg = self.receiver.recv()?;
+            let msg = match self.next_event()? {
+                Some(msg) => msg,
+                None => return Ok(true),
+            };
 
             match msg {
                 Event::Remote(msg) => {
                     self.remote.receive(msg.clone())?;
                 }
+                Event::Disconnected(why) => {
+                    self.remote.disconnected(&why)?;
+                }
                 Event::CtrlC => {
                     panic!();
                 }
@@ -80,8 +116,14 @@ impl<R: Reader> Exec<R> {
                 }
             }
         } else {
+            if let Some(lost) = self.remote.handler.lost.take() {
+                self.recover(lost)?;
+                return Ok(true);
+            }
             // eprintln!("waiting for {:?} {:?}", self.remote.handler.waiting_for, self.remote.handler.waiting_for_eof);
-            if self.remote.handler.waiting_for.len() == 0 && self.remote.handler.waiting_for_eof.len() == 0 {
+            if self.remote.handler.waiting_for.len() == 0 &&
+                self.remote.handler.waiting_for_eof.len() == 0
+            {
                 for (remote, stream_id) in self.remote.handler.cwd_for_remote.drain() {
 
                     let mut result = self.remote.handler.finished_output.remove(&stream_id).unwrap();
@@ -96,15 +138,79 @@ impl<R: Reader> Exec<R> {
                         }
                     }
                 }
-                let prompt = {
+                if let Some(running) = self.remote.handler.running.take() {
+                    self.after_plan(running);
+                }
+
+                let prefs = current_prefs(self.reader.prefs(), &self.remote);
+                let heartbeat = prefs.settings().heartbeat();
+                if heartbeat != self.remote.handler.heartbeat {
+                    self.remote.handler.heartbeat = heartbeat;
+                    self.remote.handler.next_heartbeat = None;
+                }
+
+                let setup = if mem::replace(&mut self.remote.handler.needs_setup, false) {
                     let top_remote = &self.remote.handler.remotes.last().unwrap().1;
-                    format!("[{}:{}] {}$ ",
-                        self.remote.handler.remotes.len(),
-                        top_remote.hostname,
-                        top_remote.working_dir)
+                    // Compression is of the link from the remote back towards us, and it's up to
+                    // us to say whether we can take it from the first one.
+                    let link_compresses = self.remote.handler.remotes.len() > 1 ||
+                        self.remote.handler.link_capabilities.iter().any(|c| c == capability::COMPRESSION);
+                    let (setup, unsupported): (Vec<Command>, Vec<Command>) = prefs.settings().setup_commands()
+                        .into_iter()
+                        .partition(|c| match c {
+                            Command::SetEnvironment(..) => top_remote.supports(capability::SET_ENVIRONMENT),
+                            Command::SetCompression(_) => top_remote.supports(capability::COMPRESSION) && link_compresses,
+                            _ => true,
+                        });
+                    for command in unsupported {
+                        let what = match command {
+                            Command::SetCompression(_) => "compress for",
+                            _ => "`export` to",
+                        };
+                        eprintln!("nak: the backend on {} is too old to {}", top_remote.hostname, what);
+                    }
+                    setup
+                } else {
+                    Vec::new()
                 };
 
-                let plan = self.reader.get_command(prompt, &mut self.remote)?;
+                let plan = if setup.len() > 0 {
+                    Plan::commands(RemoteRef(0), setup)
+                } else {
+                    let prompt = {
+                        let &(top_id, ref top_remote) = self.remote.handler.remotes.last().unwrap();
+                        let latency = match self.remote.handler.latency.get(&top_id) {
+                            Some(&latency) if prefs.settings().prompt_latency == Some(true) => {
+                                format!(" {}", short_latency(latency))
+                            }
+                            _ => String::new(),
+                        };
+                        format!("[{}:{}{}] {}$ ",
+                            self.remote.handler.remotes.len(),
+                            top_remote.hostname,
+                            latency,
+                            short_dir(top_remote))
+                    };
+                    let prompt = match prefs.settings().prompt_color.as_ref().and_then(|c| render::color_code(c)) {
+                        Some(color) => format!("{}{}{}", color, prompt, termion::color::Fg(termion::color::Reset)),
+                        None => prompt,
+                    };
+                    let prompt = match prefs.call_script("prompt", Vec::new()) {
+                        Ok(None) => prompt,
+                        Ok(Some(Value::Str(p))) => p,
+                        Ok(Some(_)) => {
+                            eprintln!("nak: the script's `prompt` should return a string");
+                            prompt
+                        }
+                        Err(e) => {
+                            eprintln!("nak: {}", e);
+                            prompt
+                        }
+                    };
+
+                    let plan = self.reader.get_command(prompt, &mut self.remote, &mut self.inbox)?;
+                    self.before_plan(plan)?
+                };
 
                 let mut pipe_pairs = Vec::new();
 
@@ -124,6 +230,9 @@ impl<R: Reader> Exec<R> {
                             let pid = self.remote.command(remote, cmd, HashMap::new(), WritePipes {
                                 stdin, stdout, stderr
                             })?;
+                            if let Some(ref mut running) = self.remote.handler.running {
+                                running.last_process = Some(pid);
+                            }
 
                             wait.insert(pid);
                         }
@@ -158,14 +267,13 @@ impl<R: Reader> Exec<R> {
                         stderr: stderr_write,
                     })?;
 
-                    self.remote.pipe_begin_read(remote, stdout_read)?;
-                    self.remote.pipe_begin_read(remote, stderr_read)?;
-                    self.remote.pipe_read(remote, stdout_read, 1024*1024)?;
-                    self.remote.pipe_read(remote, stderr_read, 1024*1024)?;
+                    self.remote.read_pipe(remote, stdout_read)?;
+                    self.remote.read_pipe(remote, stderr_read)?;
 
                     self.remote.handler.gathering_output.insert(stdout_read.to_generic(), Vec::new());
                     self.remote.handler.waiting_for_eof.insert(stdout_read.to_generic());
 
+                    self.remote.handler.waiting_for_eof.insert(stderr_read.to_generic());
                     self.remote.handler.stderr_pipes.insert(stderr_read.to_generic());
 
                     self.remote.handler.cwd_for_remote.insert(remote, stdout_read.to_generic());
@@ -178,17 +286,23 @@ impl<R: Reader> Exec<R> {
                 for (pipe, sink) in plan.sink_map.iter().enumerate() {
                     if let Some(sink) = sink {
                         let comm_pipe = pipe_pairs[pipe].0.take().unwrap();
-                        self.remote.pipe_begin_read(remote, comm_pipe)?;
-                        self.remote.pipe_read(remote, comm_pipe, 1024*1024)?;
+                        if pipe_pairs[pipe].1.is_some() {
+                            // Nothing writes to it (the output went to a file instead), so it
+                            // would never close.
+                            continue;
+                        }
+                        self.remote.read_pipe(remote, comm_pipe)?;
 
                         match sink {
                             Sink::DevNull => {
                                 // TODO: tell backend to throw away data
                             }
                             Sink::Stdout => {
+                                self.remote.handler.waiting_for_eof.insert(comm_pipe.to_generic());
                                 self.remote.handler.stdout_pipes.insert(comm_pipe.to_generic());
                             }
                             Sink::Stderr => {
+                                self.remote.handler.waiting_for_eof.insert(comm_pipe.to_generic());
                                 self.remote.handler.stderr_pipes.insert(comm_pipe.to_generic());
                             }
                             Sink::Gather(id) => {
@@ -207,12 +321,18 @@ impl<R: Reader> Exec<R> {
                 self.remote.handler.waiting_for = wait;
 
             } else {
-                let msg = self.receiver.recv()?;
+                let msg = match self.next_event()? {
+                    Some(msg) => msg,
+                    None => return Ok(true),
+                };
 
                 match msg {
                     Event::Remote(msg) => {
                         self.remote.receive(msg.clone())?;
                     }
+                    Event::Disconnected(why) => {
+                        self.remote.disconnected(&why)?;
+                    }
                     Event::CtrlC => {
                         for id in self.remote.handler.waiting_for.iter().cloned().collect::<Vec<_>>() {
                             self.remote.cancel(id)?;
@@ -228,11 +348,232 @@ impl<R: Reader> Exec<R> {
     }
 }
 
-fn remote_run(receiver: mpsc::Receiver<Event>, remote: BackendEndpoint, reader: impl Reader)
+impl<R: Reader> Exec<R> {
+    /// Ask what to do about a remote that stopped answering: pop it (and everything started
+    /// through it) off the stack, or pop them and start it again.
+    fn recover(&mut self, lost: RemoteId) -> Result<(), Error> {
+        let index = match self.remote.handler.remotes.iter().position(|&(id, _)| id == lost) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        if index == 0 {
+            return Err(format_err!("lost the connection to the backend"));
+        }
+
+        let host = self.remote.handler.remotes[index].1.hostname.clone();
+        let above = self.remote.handler.remotes.len() - index - 1;
+        let question = match above {
+            0 => format!("nak: pop {} off the stack, or reconnect?", host),
+            1 => format!("nak: pop {} and the remote started from it off the stack, or reconnect?", host),
+            n => format!("nak: pop {} and the {} remotes started from it off the stack, or reconnect?", host, n),
+        };
+        let reconnect = ask_reconnect(&question)?;
+
+        // Those above can't be reached to be closed, but the lost one's parent can still be
+        // told to let it go.
+        while self.remote.handler.remotes.len() > index + 1 {
+            self.remote.forget_top_remote()?;
+        }
+        self.remote.end_remote()?;
+
+        if reconnect {
+            if let Some(command) = self.remote.handler.started_with.get(&lost).cloned() {
+                eprintln!("nak: reconnecting to {}", host);
+                self.remote.begin_remote(command)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the script's `pre_exec(command)` hook, which can return `false` to skip the command,
+    /// or a question to ask first.  Anything else, or a broken hook, lets it run.
+    fn before_plan(&mut self, plan: Plan) -> Result<Plan, Error> {
+        let text = plan.describe();
+        if text.is_empty() {
+            return Ok(plan);
+        }
+
+        let prefs = current_prefs(self.reader.prefs(), &self.remote);
+        let target = {
+            let remotes = &self.remote.handler.remotes;
+            Value::from_remote(&remotes.last().unwrap().1, remotes.len())
+        };
+        let run = match prefs.call_script("pre_exec", vec![Value::Str(text.clone()), target]) {
+            Ok(None) | Ok(Some(Value::Nil)) | Ok(Some(Value::Bool(true))) => true,
+            Ok(Some(Value::Bool(false))) => false,
+            Ok(Some(Value::Str(question))) => confirm(&question)?,
+            Ok(Some(_)) => {
+                eprintln!("nak: the script's `pre_exec` should return a bool, a string or nil");
+                true
+            }
+            Err(e) => {
+                eprintln!("nak: {}", e);
+                true
+            }
+        };
+        if !run {
+            return Ok(Plan::empty());
+        }
+
+        let remotes = &self.remote.handler.remotes;
+        self.remote.handler.running = Some(RunningPlan {
+            text,
+            remote: remotes.last().unwrap().1.clone(),
+            depth: remotes.len(),
+            started: Instant::now(),
+            last_process: None,
+            exit_code: None,
+            stdout_bytes: 0,
+            stderr_bytes: 0,
+            usage: None,
+            timed: plan.timed,
+        });
+        Ok(plan)
+    }
+
+    /// Run the script's `post_exec(result)` hook once everything `running` started has finished.
+    fn after_plan(&mut self, running: RunningPlan) {
+        let elapsed = running.started.elapsed();
+
+        let mut result = BTreeMap::new();
+        result.insert("command".to_string(), Value::Str(running.text.clone()));
+        result.insert("hostname".to_string(), Value::Str(running.remote.hostname.clone()));
+        result.insert("exit_code".to_string(), running.exit_code.map_or(Value::Nil, Value::Int));
+        result.insert("duration_ms".to_string(), Value::Int(elapsed.as_secs() as i64 * 1000 + elapsed.subsec_millis() as i64));
+        result.insert("stdout_bytes".to_string(), Value::Int(running.stdout_bytes as i64));
+        result.insert("stderr_bytes".to_string(), Value::Int(running.stderr_bytes as i64));
+        if let Some(ref usage) = running.usage {
+            result.insert("user_ms".to_string(), Value::Int(usage.user_ms as i64));
+            result.insert("sys_ms".to_string(), Value::Int(usage.sys_ms as i64));
+            result.insert("max_rss_kb".to_string(), Value::Int(usage.max_rss_kb as i64));
+        }
+
+        let prefs = current_prefs(self.reader.prefs(), &self.remote);
+        if let Err(e) = prefs.call_script("post_exec", vec![Value::Map(result)]) {
+            eprintln!("nak: {}", e);
+        }
+
+        if running.timed || prefs.settings().time_after.map_or(false, |after| elapsed >= after) {
+            eprintln!("{}", usage_summary(elapsed, running.usage.as_ref()));
+        }
+
+        if long_enough(prefs.settings().notify_after, elapsed) {
+            let message = finished_message(&running, elapsed);
+            if let Err(e) = notify(prefs.settings().notify_command.as_ref(), &message) {
+                eprintln!("nak: couldn't send a notification: {}", e);
+            }
+        }
+    }
+}
+
+/// The working directory, with the home directory abbreviated to `~`.
+fn short_dir(info: &RemoteInfo) -> String {
+    match info.home_dir {
+        Some(ref home) if info.working_dir == *home => "~".to_string(),
+        Some(ref home) if info.working_dir.starts_with(&format!("{}/", home.trim_end_matches('/'))) => {
+            format!("~{}", &info.working_dir[home.trim_end_matches('/').len()..])
+        }
+        _ => info.working_dir.clone(),
+    }
+}
+
+/// e.g. `12ms`, or `1.4s` for anything over a second.
+fn short_latency(d: Duration) -> String {
+    let ms = d.as_secs() * 1000 + d.subsec_millis() as u64;
+    if ms < 1000 {
+        format!("{}ms", ms)
+    } else {
+        format!("{}.{}s", ms / 1000, ms % 1000 / 100)
+    }
+}
+
+fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / (60 * 60), secs / 60 % 60)
+    }
+}
+
+fn seconds(ms: u64) -> String {
+    format!("{}.{:02}s", ms / 1000, ms % 1000 / 10)
+}
+
+fn usage_summary(elapsed: Duration, usage: Option<&ResourceUsage>) -> String {
+    let mut summary = format!("real {}", seconds(elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64));
+    if let Some(usage) = usage {
+        summary.push_str(&format!("  user {}  sys {}  max rss {:.1} MB  blocks {} in, {} out",
+            seconds(usage.user_ms),
+            seconds(usage.sys_ms),
+            usage.max_rss_kb as f64 / 1024.0,
+            usage.blocks_in,
+            usage.blocks_out));
+    }
+    summary
+}
+
+/// Whether something that took `elapsed` is worth telling the user about, given `notify.after`.
+fn long_enough(after: Option<Duration>, elapsed: Duration) -> bool {
+    after.map_or(false, |after| elapsed >= after)
+}
+
+/// What to tell the user when `running` has finished, `elapsed` after it started.
+fn finished_message(running: &RunningPlan, elapsed: Duration) -> String {
+    let status = match running.exit_code {
+        Some(0) => "finished".to_string(),
+        Some(code) => format!("failed ({})", code),
+        None => "stopped".to_string(),
+    };
+    format!("`{}` {} on {} after {}", running.text, status, running.remote.hostname, human_duration(elapsed))
+}
+
+/// Tell the user something finished, even if they've switched to another window: through
+/// `command` if there is one, otherwise with the terminal bell.
+fn notify(command: Option<&Vec<String>>, message: &str) -> Result<(), Error> {
+    let command = match command {
+        Some(command) => command,
+        None => {
+            print!("\x07");
+            io::stdout().flush()?;
+            return Ok(());
+        }
+    };
+
+    let mut child = process::Command::new(&command[0])
+        .args(&command[1..])
+        .arg(message)
+        .stdin(process::Stdio::null())
+        .spawn()?;
+    // Reap it without making the next prompt wait.
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y") || answer.trim().eq_ignore_ascii_case("yes"))
+}
+
+/// Pop is the default, so there's something sensible to do when nobody's there to answer.
+fn ask_reconnect(question: &str) -> Result<bool, Error> {
+    print!("{} [P/r] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("r") || answer.trim().eq_ignore_ascii_case("reconnect"))
+}
+
+fn remote_run(inbox: Inbox, remote: BackendEndpoint, reader: impl Reader)
     -> Result<(), Error>
 {
     let mut exec = Exec {
-        receiver,
+        inbox,
         remote,
         reader,
     };
@@ -252,28 +593,125 @@ struct MainOptions {
 
     #[structopt(long = "command")]
     command: Option<String>,
+
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// Manage the preferences file
+    #[structopt(name = "prefs")]
+    Prefs(PrefsCommand),
+
+    /// Import aliases and history from bash, zsh and fish
+    #[structopt(name = "import")]
+    Import {
+        /// Show what would be imported, without changing anything
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum PrefsCommand {
+    /// Report every problem with the preferences file
+    #[structopt(name = "check")]
+    Check {
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+}
+
+fn prefs_check(file: Option<&Path>) -> Result<(), Error> {
+    let errors = Prefs::check(file)?;
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+
+    if errors.is_empty() {
+        println!("prefs ok");
+    } else {
+        eprintln!("{} problem(s) found", errors.len());
+        std::process::exit(1);
+    }
+
+    Ok(())
 }
 
 fn main() -> Result<(), Error> {
+    let args = MainOptions::from_args();
+
+    match args.subcommand {
+        Some(Subcommand::Prefs(PrefsCommand::Check { file })) => return prefs_check(file.as_ref().map(|f| f.as_path())),
+        Some(Subcommand::Import { dry_run }) => return import::run(dry_run),
+        None => {}
+    }
+
     let prefs = Prefs::load()?;
     // let remote = Box::new(SimpleRemote);
     let (sender, receiver) = mpsc::channel();
 
     let sender_clone = sender.clone();
 
-    let args = MainOptions::from_args();
-
-    let remote = launch_backend(sender, args.backend)?;
+    let (remote, wake) = launch_backend(sender, args.backend)?;
+    let inbox = Inbox::new(receiver, wake);
 
     if let Some(command) = args.command {
-        remote_run(receiver, remote, SingleCommandReader::new(prefs, command))?;
+        remote_run(inbox, remote, SingleCommandReader::new(prefs, command))?;
     } else {
         ctrlc::set_handler(move || {
             sender_clone.send(Event::CtrlC).unwrap();
         }).expect("Error setting CtrlC handler");
 
-        remote_run(receiver, remote, SimpleReader::new(prefs)?)?;
+        remote_run(inbox, remote, SimpleReader::new(prefs)?)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(human_duration(Duration::from_millis(999)), "0s");
+        assert_eq!(human_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(human_duration(Duration::from_secs(60)), "1m 0s");
+        assert_eq!(human_duration(Duration::from_secs(60 * 60 - 1)), "59m 59s");
+        assert_eq!(human_duration(Duration::from_secs(60 * 60)), "1h 0m");
+        assert_eq!(human_duration(Duration::from_secs(26 * 60 * 60 + 5 * 60 + 30)), "26h 5m");
+    }
+
+    #[test]
+    fn notify_threshold() {
+        let after = Prefs::parse("set notify.after = 30").unwrap().settings().notify_after;
+        assert!(!long_enough(after, Duration::from_millis(29_999)));
+        assert!(long_enough(after, Duration::from_secs(30)));
+        assert!(long_enough(after, Duration::from_secs(600)));
+        assert!(!long_enough(None, Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn notify_message() {
+        let mut running = RunningPlan {
+            text: "make -j8".to_string(),
+            remote: RemoteInfo { hostname: "build1".to_string(), ..RemoteInfo::default() },
+            depth: 2,
+            started: Instant::now(),
+            last_process: None,
+            exit_code: Some(0),
+            stdout_bytes: 0,
+            stderr_bytes: 0,
+            usage: None,
+            timed: false,
+        };
+        let elapsed = Duration::from_secs(125);
+        assert_eq!(finished_message(&running, elapsed), "`make -j8` finished on build1 after 2m 5s");
+        running.exit_code = Some(2);
+        assert_eq!(finished_message(&running, elapsed), "`make -j8` failed (2) on build1 after 2m 5s");
+        running.exit_code = None;
+        assert_eq!(finished_message(&running, elapsed), "`make -j8` stopped on build1 after 2m 5s");
+    }
+}
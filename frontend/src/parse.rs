@@ -1,3 +1,4 @@
+use std::fmt;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Stream {
@@ -51,16 +52,81 @@ pub enum Ast {
     Redirect(Box<Ast>, Vec<RedirectClause>),
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    Command,
+    Argument,
+    Operator,
+    Redirect,
+    Path,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub pos: usize,
+    pub message: String,
+    /// The input is fine so far, it just isn't finished (e.g. ends with `|`).
+    pub incomplete: bool,
+}
+
+/// The result of parsing possibly-broken input, as typed so far.
+///
+/// Parsing stops at the first error; `ast` holds everything recognized before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partial {
+    pub ast: Ast,
+    pub tokens: Vec<Token>,
+    pub error: Option<ParseError>,
+}
+
+/// Words that open or close a block when they appear in command position.
+pub const KEYWORDS: &[&str] = &["if", "then", "else", "elif", "fi"];
+
 fn skip_whitespace(input: &mut Consume) {
     while let Some(c) = input.cur() {
-        if c != b' ' {
+        match c {
+            b' ' => input.pos += 1,
+            b'\\' if input.peek(1) == Some(b'\n') => input.pos += 2,
+            b'\\' if input.peek(1).is_none() => {
+                input.fail_incomplete(input.pos, "line continues");
+                break;
+            }
+            _ => break,
+        }
+    }
+}
+
+fn skip_newlines(input: &mut Consume) {
+    loop {
+        skip_whitespace(input);
+        if input.cur() != Some(b'\n') {
             break;
         }
         input.pos += 1;
     }
 }
 
+fn at_and_or(input: &Consume) -> bool {
+    match (input.cur(), input.peek(1)) {
+        (Some(b'&'), Some(b'&')) | (Some(b'|'), Some(b'|')) => true,
+        _ => false,
+    }
+}
+
 fn parse_quote(input: &mut Consume) -> Word {
+    let quote = input.pos;
     assert!(input.next() == Some(b'"'));
 
     let begin = input.pos;
@@ -69,41 +135,80 @@ fn parse_quote(input: &mut Consume) -> Word {
         match input.next() {
             Some(b'"') => break,
             Some(_) => {}
-            None => panic!(),
+            None => {
+                input.fail_incomplete(quote, "unterminated quote");
+                return Word::Normal(input.chars[begin..input.pos].to_string());
+            }
         }
     }
 
     Word::Normal(input.chars[begin..input.pos - 1].to_string())
 }
 
-fn parse_word(input: &mut Consume) -> Word {
-    if let Some(b'"') = input.cur() {
-        return parse_quote(input);
-    }
-
+fn parse_word(input: &mut Consume, kind: TokenKind) -> Word {
     let begin = input.pos;
 
-    while let Some(ch) = input.cur() {
-        match ch {
-            b'"' => panic!(),
-            b' ' | b';' | b'|' | b'>' => break,
-            _ => {
-                input.next();
+    let word = if let Some(b'"') = input.cur() {
+        parse_quote(input)
+    } else {
+        // Pieces of the word between line continuations, which don't split it.
+        let mut text = String::new();
+        let mut start = begin;
+        while let Some(ch) = input.cur() {
+            match ch {
+                b'"' => {
+                    input.fail(input.pos, "unexpected quote");
+                    break;
+                }
+                b' ' | b';' | b'|' | b'>' | b'\n' => break,
+                b'&' if at_and_or(input) => break,
+                b'\\' if input.peek(1) == Some(b'\n') => {
+                    text.push_str(&input.chars[start..input.pos]);
+                    input.pos += 2;
+                    start = input.pos;
+                }
+                b'\\' if input.peek(1).is_none() => {
+                    input.fail_incomplete(input.pos, "line continues");
+                    break;
+                }
+                _ => {
+                    input.next();
+                }
             }
         }
-    }
 
-    Word::Normal(input.chars[begin..input.pos].to_string())
+        text.push_str(&input.chars[start..input.pos]);
+        Word::Normal(text)
+    };
+
+    input.token(kind, begin);
+
+    word
 }
 
 fn parse_cmd(input: &mut Consume) -> Cmd {
-    let mut children = vec![parse_word(input)];
+    match input.cur() {
+        Some(b';') | Some(b'|') | Some(b'>') => input.fail(input.pos, "expected a command"),
+        Some(b'&') if at_and_or(input) => input.fail(input.pos, "expected a command"),
+        _ => {}
+    }
+
+    let head = parse_word(input, TokenKind::Command);
+
+    match head.expand_string().as_str() {
+        "if" => input.open_blocks += 1,
+        "fi" => input.open_blocks = input.open_blocks.saturating_sub(1),
+        _ => {}
+    }
+
+    let mut children = vec![head];
 
     loop {
         skip_whitespace(input);
         match input.cur() {
-            None | Some(b';') | Some(b'|') | Some(b'>') => break,
-            _ => children.push(parse_word(input)),
+            None | Some(b';') | Some(b'|') | Some(b'>') | Some(b'\n') => break,
+            Some(b'&') if at_and_or(input) => break,
+            _ => children.push(parse_word(input, TokenKind::Argument)),
         }
     }
 
@@ -113,6 +218,14 @@ fn parse_cmd(input: &mut Consume) -> Cmd {
     }
 }
 
+// After an operator that needs something on its right, like `|` or `&&`.
+fn expect_more(input: &mut Consume, what: &str) {
+    skip_newlines(input);
+    if input.cur().is_none() {
+        input.fail_incomplete(input.pos, what);
+    }
+}
+
 fn parse_pipe(input: &mut Consume) -> Ast {
     let head = Ast::Cmd(parse_cmd(input));
 
@@ -122,18 +235,23 @@ fn parse_pipe(input: &mut Consume) -> Ast {
         skip_whitespace(input);
         match input.cur() {
             None => break,
+            Some(b'|') if at_and_or(input) => break,
             Some(b'|') => {
-                input.next();
-                skip_whitespace(input);
+                input.operator(TokenKind::Operator, 1);
+                expect_more(input, "expected a command after `|`");
                 children.push(RedirectClause(Stream::Stdout, Target::Command(Ast::Cmd(parse_cmd(input)))));
             }
             Some(b'>') => {
-                input.next();
+                input.operator(TokenKind::Redirect, 1);
                 skip_whitespace(input);
-                children.push(RedirectClause(Stream::Stdout, Target::File(parse_word(input))));
+                children.push(RedirectClause(Stream::Stdout, Target::File(parse_word(input, TokenKind::Path))));
+            }
+            Some(b';') | Some(b'\n') => break,
+            Some(b'&') if at_and_or(input) => break,
+            Some(_) => {
+                input.fail(input.pos, "unexpected character");
+                break;
             }
-            Some(b';') => break,
-            Some(ch) => panic!("{}", ch),
         }
     }
 
@@ -151,15 +269,32 @@ fn parse_seq(input: &mut Consume) -> Ast {
 
     loop {
         skip_whitespace(input);
-        match input.cur() {
+        let ty = match input.cur() {
             None => break,
-            Some(b';') => {
-                input.next();
-                skip_whitespace(input);
-                children.push(SequenceClause(SequenceType::Wait, parse_pipe(input)));
+            Some(b';') | Some(b'\n') => {
+                input.operator(TokenKind::Operator, 1);
+                skip_newlines(input);
+                if input.cur().is_none() {
+                    break;
+                }
+                SequenceType::Wait
             }
-            Some(ch) => panic!("{}", ch),
-        }
+            Some(b'&') if at_and_or(input) => {
+                input.operator(TokenKind::Operator, 2);
+                expect_more(input, "expected a command after `&&`");
+                SequenceType::And
+            }
+            Some(b'|') if at_and_or(input) => {
+                input.operator(TokenKind::Operator, 2);
+                expect_more(input, "expected a command after `||`");
+                SequenceType::Or
+            }
+            Some(_) => {
+                input.fail(input.pos, "unexpected character");
+                break;
+            }
+        };
+        children.push(SequenceClause(ty, parse_pipe(input)));
     }
 
     if children.len() == 0 {
@@ -170,7 +305,7 @@ fn parse_seq(input: &mut Consume) -> Ast {
 }
 
 fn parse_line(input: &mut Consume) -> Ast {
-    skip_whitespace(input);
+    skip_newlines(input);
 
     if input.cur().is_none() {
         return Ast::Empty;
@@ -178,32 +313,73 @@ fn parse_line(input: &mut Consume) -> Ast {
 
     let res = parse_seq(input);
 
-    assert_eq!(input.pos, input.text.len());
+    if input.open_blocks > 0 {
+        input.fail_incomplete(input.pos, "unterminated `if`");
+    }
+
+    assert!(input.error.is_some() || input.pos == input.text.len());
 
     res
 }
 
-pub fn parse_input(input: &str) -> Ast {
-    parse_line(&mut Consume { chars: input, text: input.as_bytes(), pos: 0 })
+pub fn parse_partial(input: &str) -> Partial {
+    let mut consume = Consume {
+        chars: input,
+        text: input.as_bytes(),
+        pos: 0,
+        end: input.len(),
+        tokens: Vec::new(),
+        error: None,
+        open_blocks: 0,
+    };
+
+    let ast = parse_line(&mut consume);
+
+    Partial {
+        ast,
+        tokens: consume.tokens,
+        error: consume.error,
+    }
+}
+
+pub fn parse_input(input: &str) -> Result<Ast, ParseError> {
+    let partial = parse_partial(input);
+    match partial.error {
+        Some(error) => Err(error),
+        None => Ok(partial.ast),
+    }
 }
 
 struct Consume<'a> {
     chars: &'a str,
     text: &'a [u8],
     pos: usize,
+    // Everything past `end` is hidden from the parser once it has hit an error.
+    end: usize,
+    tokens: Vec<Token>,
+    error: Option<ParseError>,
+    open_blocks: usize,
 }
 
 impl<'a> Consume<'a> {
     fn cur(&self) -> Option<u8> {
-        if self.pos < self.text.len() {
+        if self.pos < self.end {
             Some(self.text[self.pos])
         } else {
             None
         }
     }
 
+    fn peek(&self, offset: usize) -> Option<u8> {
+        if self.pos + offset < self.end {
+            Some(self.text[self.pos + offset])
+        } else {
+            None
+        }
+    }
+
     fn next(&mut self) -> Option<u8> {
-        if self.pos < self.text.len() {
+        if self.pos < self.end {
             let res = Some(self.text[self.pos]);
             self.pos += 1;
             res
@@ -211,6 +387,46 @@ impl<'a> Consume<'a> {
             None
         }
     }
+
+    fn token(&mut self, kind: TokenKind, start: usize) {
+        if start < self.pos {
+            self.tokens.push(Token {
+                kind,
+                span: Span { start, end: self.pos },
+            });
+        }
+    }
+
+    fn operator(&mut self, kind: TokenKind, len: usize) {
+        let start = self.pos;
+        self.pos += len;
+        self.token(kind, start);
+    }
+
+    fn fail(&mut self, pos: usize, message: &str) {
+        self.fail_with(pos, message, false);
+    }
+
+    fn fail_incomplete(&mut self, pos: usize, message: &str) {
+        self.fail_with(pos, message, true);
+    }
+
+    fn fail_with(&mut self, pos: usize, message: &str, incomplete: bool) {
+        if self.error.is_none() {
+            self.error = Some(ParseError {
+                pos,
+                message: message.to_string(),
+                incomplete,
+            });
+        }
+        self.end = self.pos;
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.pos + 1)
+    }
 }
 
 #[cfg(test)]
@@ -235,7 +451,7 @@ mod tests {
 
         for test in tests.iter() {
             eprintln!("test: {}", test.input);
-            let output = parse_input(&test.input);
+            let output = parse_input(&test.input).unwrap();
             eprintln!("  output: {:#?}", output);
 
             if let Some(ref expected) = test.output {
@@ -246,9 +462,33 @@ mod tests {
         let actual = tests.iter()
             .map(|t| ParserTest {
                 input: t.input.clone(),
-                output: Some(parse_input(&t.input))
+                output: Some(parse_input(&t.input).unwrap())
             }).collect::<Vec<_>>();
 
         File::create("tests/parser.actual.json").unwrap().write_all(serde_json::to_string_pretty(&actual).unwrap().as_bytes()).unwrap();
     }
+
+    #[test]
+    fn incomplete_input() {
+        for input in &["echo \"a", "ls |", "true &&", "false ||", "echo a \\", "ec\\", "if true"] {
+            let err = parse_input(input).unwrap_err();
+            assert!(err.incomplete, "{:?} should be incomplete, got {:?}", input, err);
+        }
+
+        assert!(parse_input("ls |\n  wc").is_ok());
+        assert!(parse_input("echo a \\\n b").is_ok());
+        assert!(!parse_input("ls | |").unwrap_err().incomplete);
+        assert!(!parse_input("cd a\"b").unwrap_err().incomplete);
+        assert!(parse_input("if true\nthen echo a\nfi").is_ok());
+    }
+
+    #[test]
+    fn continued_word() {
+        let words = |input| match parse_input(input).unwrap() {
+            Ast::Cmd(cmd) => cmd.words.iter().map(|w| w.expand_string()).collect::<Vec<_>>(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(words("ec\\\nho hi"), vec!["echo", "hi"]);
+        assert_eq!(words("echo \\\nhi\\\n there"), vec!["echo", "hi", "there"]);
+    }
 }
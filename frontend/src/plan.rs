@@ -56,6 +56,9 @@ pub struct Plan {
     pub gather_count: usize,
     pub pager_count: usize,
     pub sink_map: Vec<Option<Sink>>,
+    /// Report how long it took and what it used afterwards, as `time` does.
+    #[serde(default)]
+    pub timed: bool,
 }
 
 impl Plan {
@@ -71,6 +74,55 @@ impl Plan {
         b.build()
     }
 
+    /// Run each of `commands`, independently of each other.
+    pub fn commands(remote: RemoteRef, commands: Vec<Command>) -> Plan {
+        let mut b = PlanBuilder::new();
+
+        for cmd in commands {
+            let stdin = b.pipe();
+            let stdout = b.pipe();
+            let stderr = b.pipe();
+            b.add_command(remote, cmd, stdin, stdout, stderr);
+            b.add_stdout(stdout);
+            b.add_stderr(stderr);
+        }
+
+        b.build()
+    }
+
+    /// What the plan will run, after alias expansion, in roughly the syntax it was typed in.
+    /// Empty if it doesn't run anything.
+    pub fn describe(&self) -> String {
+        let mut runs = Vec::new();
+        let mut redirects = Vec::new();
+        for step in &self.steps {
+            match step {
+                Step::Remote(_, RemoteStep::Run(cmd, process)) => runs.push((cmd, process)),
+                Step::Remote(_, RemoteStep::OpenOutputFile(path)) => redirects.push(format!("> {}", quote(path))),
+                Step::Remote(_, RemoteStep::OpenInputFile(path)) => redirects.push(format!("< {}", quote(path))),
+                _ => {}
+            }
+        }
+
+        // Pipelines are built back to front, so put them in order by following the pipes.
+        let mut commands: Vec<(&Command, &PlanProcess)> = Vec::new();
+        while !runs.is_empty() {
+            let fed = |p: &PlanProcess| runs.iter().any(|(_, q)| q.stdout == p.stdin);
+            let next = match commands.last() {
+                Some(&(_, prev)) => runs.iter().position(|(_, p)| p.stdin == prev.stdout),
+                None => None,
+            }.or_else(|| runs.iter().position(|(_, p)| !fed(p))).unwrap_or(0);
+            commands.push(runs.remove(next));
+        }
+
+        let mut text = commands.iter().map(|(cmd, _)| command_text(cmd)).collect::<Vec<_>>().join(" | ");
+        for r in redirects {
+            text.push(' ');
+            text.push_str(&r);
+        }
+        text
+    }
+
     pub fn single(remote: RemoteRef, words: Vec<String>, redirect: Option<(RemoteRef, String)>) -> Plan {
         let mut it = words.into_iter();
         let head = it.next().unwrap();
@@ -100,6 +152,30 @@ impl Plan {
     }
 }
 
+fn quote(word: &str) -> String {
+    if word.is_empty() || word.contains(|c: char| c.is_whitespace() || c == '"') {
+        format!("{:?}", word)
+    } else {
+        word.to_string()
+    }
+}
+
+fn command_text(cmd: &Command) -> String {
+    let words = match cmd {
+        Command::Unknown(head, args) => {
+            let mut words = vec![head.clone()];
+            words.extend(args.iter().cloned());
+            words
+        }
+        Command::SetDirectory(dir) => vec!["cd".to_string(), dir.clone()],
+        Command::GetDirectory => vec!["pwd".to_string()],
+        Command::Edit(file) => vec!["micro".to_string(), file.clone()],
+        Command::SetEnvironment(name, value) => vec!["export".to_string(), format!("{}={}", name, value)],
+        Command::SetCompression(on) => vec!["set".to_string(), format!("compression={}", if *on { "on" } else { "off" })],
+    };
+    words.iter().map(|w| quote(w)).collect::<Vec<_>>().join(" ")
+}
+
 pub struct PlanBuilder {
     plan: Plan,
     proc_ids: Ids,
@@ -115,6 +191,7 @@ impl PlanBuilder {
                 gather_count: 0,
                 pager_count: 0,
                 sink_map: Vec::new(),
+                timed: false,
             },
             proc_ids: Ids::new(),
             pipes: Vec::new(),
@@ -153,6 +230,10 @@ impl PlanBuilder {
         self.pipes[pipe].1 = false;
     }
 
+    pub fn set_timed(&mut self, timed: bool) {
+        self.plan.timed = timed;
+    }
+
     pub fn set_stdin(&mut self, stdin: Option<usize>) {
         self.plan.stdin = stdin;
     }
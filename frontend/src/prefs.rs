@@ -1,43 +1,558 @@
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
 use std::io::Read;
 use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use failure::Error;
+use failure::{Error, Fail};
 use serde_json;
 use dirs;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+use protocol::{Command, RemoteInfo};
+
+use crate::render;
+use crate::script::{Env, Script, Value};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Item {
     Literal(String),
     Variable(usize),
     Expando(usize),
+    /// Any one of these words, e.g. `s|st`.
+    OneOf(Vec<String>),
+    /// The word (a `Literal` or `OneOf`) if it's there, e.g. `[-v]`.
+    Optional(Box<Item>),
+    /// A `Variable` that only matches words passing the check, e.g. `$f:file`.
+    Guarded(usize, Guard),
 }
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Guard {
+    File,
+    Dir,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Rule {
     find: Vec<Item>,
     replace: Vec<Item>,
+    /// Whether the rule can match starting at any word, rather than only the first.
+    #[serde(default)]
+    anywhere: bool,
+    /// Whether the rule is expanded in the edit buffer as it's typed, rather than only when run.
+    #[serde(default)]
+    abbr: bool,
+}
+
+fn parse_word_item(word: &str) -> Item {
+    if word.contains('|') {
+        Item::OneOf(word.split('|').map(|w| w.to_string()).collect())
+    } else {
+        Item::Literal(word.to_string())
+    }
+}
+
+fn parse_items(text: &str, names: &mut HashMap<String, usize>, defining: bool) -> Result<Vec<Item>, Error> {
+    let mut items = Vec::new();
+
+    for word in text.split_whitespace() {
+        let (name, item): (&str, Box<dyn Fn(usize) -> Item>) = if word == "..." {
+            ("...", Box::new(Item::Expando))
+        } else if word.starts_with('$') && word.len() > 1 {
+            let name = &word[1..];
+            match name.find(':') {
+                Some(colon) if defining => {
+                    let guard = match &name[colon + 1..] {
+                        "file" => Guard::File,
+                        "dir" => Guard::Dir,
+                        g => return Err(format_err!("unknown check `{}` (expected `file` or `dir`)", g)),
+                    };
+                    (&name[..colon], Box::new(move |id| Item::Guarded(id, guard)))
+                }
+                Some(_) => return Err(format_err!("`{}`: checks go before the `=`", word)),
+                None => (name, Box::new(Item::Variable)),
+            }
+        } else if !defining {
+            items.push(Item::Literal(word.to_string()));
+            continue;
+        } else if word.len() > 2 && word.starts_with('[') && word.ends_with(']') {
+            let inner = &word[1..word.len() - 1];
+            if inner.starts_with('$') || inner == "..." {
+                return Err(format_err!("only plain words can be optional"));
+            }
+            items.push(Item::Optional(Box::new(parse_word_item(inner))));
+            continue;
+        } else {
+            items.push(parse_word_item(word));
+            continue;
+        };
+
+        let id = if defining {
+            if names.contains_key(name) {
+                return Err(format_err!("`{}` appears twice before the `=`", word));
+            }
+            let id = names.len();
+            names.insert(name.to_string(), id);
+            id
+        } else {
+            match names.get(name) {
+                Some(&id) => id,
+                None => return Err(format_err!("`{}` is used after the `=` but never matched before it", word)),
+            }
+        };
+
+        items.push(item(id));
+    }
+
+    Ok(items)
+}
+
+impl Rule {
+    /// Parse the readable form of an alias, e.g. `g a ...=git add ...`.
+    ///
+    /// `$name` matches a single word and `...` matches all the remaining words; either can
+    /// then be used (any number of times) after the `=`.  Before the `=`, `a|b` matches either
+    /// word, `[w]` matches `w` if it's there, and `$name:file` / `$name:dir` only match existing
+    /// files / directories.  A leading `--anywhere` lets the rule match later in the command too,
+    /// and `--abbr` makes it an abbreviation: expanded in place when space is pressed.
+    pub fn parse_alias(text: &str) -> Result<Rule, Error> {
+        let mut text = text.trim_start();
+        let mut anywhere = false;
+        let mut abbr = false;
+        loop {
+            if text.starts_with("--anywhere ") {
+                anywhere = true;
+            } else if text.starts_with("--abbr ") {
+                abbr = true;
+            } else {
+                break;
+            }
+            text = text[text.find(' ').unwrap()..].trim_start();
+        }
+
+        let eq = text.find('=').ok_or_else(|| format_err!("expected `=` in alias `{}`", text))?;
+
+        let mut names = HashMap::new();
+        let find = parse_items(&text[..eq], &mut names, true)?;
+        let replace = parse_items(&text[eq + 1..], &mut names, false)?;
+
+        let rule = Rule { find, replace, anywhere, abbr };
+        rule.validate()?;
+        Ok(rule)
+    }
+
+    /// Check everything `expand` relies on, so that a broken rule is rejected when it's loaded
+    /// rather than when it's first used.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.find.first() {
+            Some(Item::Literal(_)) | Some(Item::OneOf(_)) => {}
+            Some(_) => return Err(format_err!("an alias has to start with a plain word")),
+            None => return Err(format_err!("nothing to match before the `=`")),
+        }
+
+        let mut bound = HashMap::new();
+        for (i, item) in self.find.iter().enumerate() {
+            let id = match *item {
+                Item::Literal(_) | Item::OneOf(_) => continue,
+                Item::Optional(ref inner) => match **inner {
+                    Item::Literal(_) | Item::OneOf(_) => continue,
+                    _ => return Err(format_err!("only plain words can be optional")),
+                },
+                Item::Expando(_) if i != self.find.len() - 1 => {
+                    return Err(format_err!("`...` has to come last before the `=`"));
+                }
+                Item::Variable(id) | Item::Guarded(id, _) => {
+                    // Either way, it's a single word.
+                    if bound.insert(id, Item::Variable(id)).is_some() {
+                        return Err(format_err!("variable {} is matched more than once", id));
+                    }
+                    continue;
+                }
+                Item::Expando(id) => id,
+            };
+            if bound.insert(id, item.clone()).is_some() {
+                return Err(format_err!("variable {} is matched more than once", id));
+            }
+        }
+
+        if self.abbr {
+            let plain = |item: &Item| match item {
+                Item::Literal(_) | Item::OneOf(_) => true,
+                _ => false,
+            };
+            if !self.find.iter().all(plain) || !self.replace.iter().all(plain) {
+                return Err(format_err!("abbreviations can only use plain words"));
+            }
+        }
+
+        for item in &self.replace {
+            match *item {
+                Item::Literal(_) => {}
+                Item::Variable(id) | Item::Expando(id) => match bound.get(&id) {
+                    Some(b) if mem::discriminant(b) == mem::discriminant(item) => {}
+                    Some(b) => return Err(format_err!("`{}` is matched as `{}`", item, b)),
+                    None => return Err(format_err!("`{}` is used after the `=` but never matched before it", item)),
+                },
+                _ => return Err(format_err!("`{}` can only be used before the `=`", item)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether this rule can apply to commands starting with `word`.
+    pub fn starts_with(&self, word: &str) -> bool {
+        match self.find.first() {
+            Some(item) => word_matches(item, word),
+            None => false,
+        }
+    }
+
+    /// Whether `pattern` (as given to `unalias`) picks out this rule: either its whole `find`
+    /// pattern, or a single word it starts with.
+    fn matches_pattern(&self, pattern: &str) -> bool {
+        let pattern = pattern.split_whitespace().collect::<Vec<_>>().join(" ");
+        self.find_string() == pattern || (!pattern.contains(' ') && self.starts_with(&pattern))
+    }
+
+    /// The part before the `=`.
+    pub fn find_string(&self) -> String {
+        self.find.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(" ")
+    }
+}
+
+fn word_matches(item: &Item, word: &str) -> bool {
+    match item {
+        Item::Literal(w) => w == word,
+        Item::OneOf(ws) => ws.iter().any(|w| w == word),
+        _ => false,
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Item::Literal(word) => write!(f, "{}", word),
+            Item::Variable(id) => write!(f, "${}", id + 1),
+            Item::Expando(_) => write!(f, "..."),
+            Item::OneOf(words) => write!(f, "{}", words.join("|")),
+            Item::Optional(item) => write!(f, "[{}]", item),
+            Item::Guarded(id, Guard::File) => write!(f, "${}:file", id + 1),
+            Item::Guarded(id, Guard::Dir) => write!(f, "${}:dir", id + 1),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let replace = self.replace.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        if self.abbr {
+            write!(f, "--abbr ")?;
+        }
+        if self.anywhere {
+            write!(f, "--anywhere ")?;
+        }
+        write!(f, "{}={}", self.find_string(), replace)
+    }
+}
+
+/// A problem with the prefs file, pointing at where it is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefsError {
+    /// 1-based; `None` when the problem can't be pinned to a line (e.g. in old json prefs).
+    pub line: Option<usize>,
+    /// What the line was setting, e.g. `alias g a`.
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for PrefsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl Fail for PrefsError {}
+
+/// Things set up on a remote as soon as it's ready, as opposed to aliases (which only affect
+/// how commands are interpreted).
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Settings {
+    pub prompt_color: Option<String>,
+    /// Working directory to start in.
+    pub dir: Option<String>,
+    pub env: Vec<(String, String)>,
+    /// Commands taking longer than this get a notification when they finish.
+    pub notify_after: Option<Duration>,
+    /// Run locally with the message as its last argument; without it, the terminal bell rings.
+    pub notify_command: Option<Vec<String>>,
+    /// Commands taking longer than this get a `time`-style summary when they finish.
+    pub time_after: Option<Duration>,
+    /// Whether pipe data coming back from the remote is deflated on the way.
+    pub compression: Option<bool>,
+    /// How often to ping the remotes while waiting on them; zero for never.
+    pub heartbeat: Option<Duration>,
+    /// Whether to show the round trip to the remote in the prompt.
+    pub prompt_latency: Option<bool>,
+}
+
+/// How often to ping the remotes while waiting on them, unless the prefs say otherwise.
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(10);
+
+fn parse_seconds(value: &str) -> Result<Duration, Error> {
+    value.trim_end_matches('s').parse()
+        .map(Duration::from_secs)
+        .map_err(|_| format_err!("expected a number of seconds, not `{}`", value))
+}
+
+fn parse_switch(value: &str) -> Result<bool, Error> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format_err!("expected `on` or `off`, not `{}`", value)),
+    }
+}
+
+impl Settings {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "prompt.color" => {
+                if render::color_code(value).is_none() {
+                    return Err(format_err!("unknown colour `{}`", value));
+                }
+                self.prompt_color = Some(value.to_string());
+            }
+            "dir" => self.dir = Some(value.to_string()),
+            "notify.after" => self.notify_after = Some(parse_seconds(value)?),
+            "time.after" => self.time_after = Some(parse_seconds(value)?),
+            "compression" => self.compression = Some(parse_switch(value)?),
+            "heartbeat" if value == "off" => self.heartbeat = Some(Duration::from_secs(0)),
+            "heartbeat" => self.heartbeat = Some(parse_seconds(value)?),
+            "prompt.latency" => self.prompt_latency = Some(parse_switch(value)?),
+            "notify.command" => {
+                let words: Vec<String> = value.split_whitespace().map(|w| w.to_string()).collect();
+                if words.is_empty() {
+                    return Err(format_err!("expected a command, e.g. `notify-send nak`"));
+                }
+                self.notify_command = Some(words);
+            }
+            _ => return Err(format_err!("unknown setting (expected `prompt.color`, `prompt.latency`, `dir`, `notify.after`, `notify.command`, `time.after`, `compression` or `heartbeat`)")),
+        }
+        Ok(())
+    }
+
+    /// Layer `other` on top of these settings.
+    fn merge(&mut self, other: &Settings) {
+        if other.prompt_color.is_some() {
+            self.prompt_color = other.prompt_color.clone();
+        }
+        if other.dir.is_some() {
+            self.dir = other.dir.clone();
+        }
+        if other.notify_after.is_some() {
+            self.notify_after = other.notify_after;
+        }
+        if other.notify_command.is_some() {
+            self.notify_command = other.notify_command.clone();
+        }
+        if other.time_after.is_some() {
+            self.time_after = other.time_after;
+        }
+        if other.compression.is_some() {
+            self.compression = other.compression;
+        }
+        if other.heartbeat.is_some() {
+            self.heartbeat = other.heartbeat;
+        }
+        if other.prompt_latency.is_some() {
+            self.prompt_latency = other.prompt_latency;
+        }
+        self.env.extend(other.env.iter().cloned());
+    }
+
+    /// How often to ping the remotes, or `None` not to.
+    pub fn heartbeat(&self) -> Option<Duration> {
+        match self.heartbeat {
+            Some(interval) if interval == Duration::from_secs(0) => None,
+            Some(interval) => Some(interval),
+            None => Some(DEFAULT_HEARTBEAT),
+        }
+    }
+
+    /// Commands that put these settings into effect on a remote.
+    pub fn setup_commands(&self) -> Vec<Command> {
+        let mut commands: Vec<Command> = self.env.iter()
+            .map(|(k, v)| Command::SetEnvironment(k.clone(), v.clone()))
+            .collect();
+        if let Some(ref dir) = self.dir {
+            commands.push(Command::SetDirectory(dir.clone()));
+        }
+        if let Some(on) = self.compression {
+            commands.push(Command::SetCompression(on));
+        }
+        commands
+    }
+}
+
+/// Which remotes a `[...]` section of the prefs file applies to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Matcher {
+    Host(String),
+    User(String),
+    /// How many remotes deep, counting the local one as 1.
+    Depth(usize),
+    /// The remote's OS, or OS and architecture, e.g. `linux` or `linux-x86_64`.
+    Os(String),
+}
+
+impl Matcher {
+    fn parse(text: &str) -> Result<Matcher, Error> {
+        let mut words = text.split_whitespace();
+        let res = match (words.next(), words.next()) {
+            (Some("host"), Some(glob)) => Matcher::Host(glob.to_string()),
+            (Some("user"), Some(name)) => Matcher::User(name.to_string()),
+            (Some("depth"), Some(depth)) => Matcher::Depth(depth.parse()
+                .map_err(|_| format_err!("expected a number, not `{}`", depth))?),
+            (Some("os"), Some(os)) => Matcher::Os(os.to_string()),
+            _ => return Err(format_err!("expected `[host <glob>]`, `[user <name>]`, `[depth <n>]` or `[os <name>]`")),
+        };
+        if words.next().is_some() {
+            return Err(format_err!("too many words in section"));
+        }
+        Ok(res)
+    }
+
+    fn matches(&self, info: &RemoteInfo, depth: usize) -> bool {
+        match self {
+            Matcher::Host(glob) => glob_match(glob, &info.hostname),
+            Matcher::User(name) => *name == info.username,
+            Matcher::Depth(d) => *d == depth,
+            // Older backends don't say, so this never matches them.
+            Matcher::Os(os) => info.os.as_ref() == Some(os) || info.platform().as_ref() == Some(os),
+        }
+    }
+}
+
+/// Shell-style matching, where `*` matches any run of characters and `?` any single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Where to resume after the most recent `*`, if the current attempt fails.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Clone, Debug)]
+struct Override {
+    matcher: Matcher,
+    aliases: Vec<Rule>,
+    settings: Settings,
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Prefs {
     aliases: Vec<Rule>,
+    #[serde(skip)]
+    settings: Settings,
+    /// From `[...]` sections, in the order they appear in the file.
+    #[serde(skip)]
+    overrides: Vec<Override>,
+    /// Where `$name:file` and friends are checked, when the remote is the local machine.
+    #[serde(skip)]
+    local_dir: Option<PathBuf>,
+    /// From the `script` line, if any.
+    #[serde(skip)]
+    script: Option<Arc<Script>>,
+    /// The remote these prefs were picked for (see `for_remote`), and how deep it is.
+    #[serde(skip)]
+    remote: Option<(RemoteInfo, usize)>,
+    /// The commands known to exist there, for the script's `has_command`.
+    #[serde(skip)]
+    commands: Option<HashSet<String>>,
+    /// The whole stack of remotes, for the script's `remotes`.
+    #[serde(skip)]
+    stack: Vec<(RemoteInfo, Option<Duration>)>,
+    #[serde(skip)]
+    file_name: Option<PathBuf>,
+    /// When the file was last (re)loaded, so we can tell whether it's been edited since.
+    #[serde(skip)]
+    modified: Option<SystemTime>,
+}
+
+fn default_path() -> PathBuf {
+    dirs::home_dir().unwrap().join(".config").join("nak").join("prefs.nak")
+}
+
+fn modified_time(file_name: &Path) -> Option<SystemTime> {
+    fs::metadata(file_name).and_then(|m| m.modified()).ok()
+}
+
+fn load_script(path: &str, dir: Option<&Path>) -> Result<Script, Error> {
+    let path = if path.starts_with("~/") {
+        dirs::home_dir().ok_or_else(|| format_err!("can't find the home directory"))?.join(&path[2..])
+    } else {
+        dir.map_or_else(|| PathBuf::from(path), |dir| dir.join(path))
+    };
+    let text = fs::read_to_string(&path).map_err(|e| format_err!("{}: {}", path.display(), e))?;
+    Script::parse(&text).map_err(|e| format_err!("{}: {}", path.display(), e))
+}
+
+fn is_legacy_json(contents: &str) -> bool {
+    contents.trim_start().starts_with('{')
 }
 
 impl Prefs {
     pub fn load() -> Result<Prefs, Error> {
+        Prefs::load_from(default_path())
+    }
+
+    pub fn load_from(file_name: PathBuf) -> Result<Prefs, Error> {
+        let modified = modified_time(&file_name);
         let mut contents = String::new();
-        match File::open(dirs::home_dir().unwrap().join(".config").join("nak").join("prefs.nak")) {
+        match File::open(&file_name) {
             Ok(mut f) => {
                 f.read_to_string(&mut contents)?;
 
-                let prefs: Prefs = serde_json::from_str(&contents)?;
+                let (mut prefs, errors) = Prefs::parse_in(&contents, file_name.parent());
+                if let Some(e) = errors.first() {
+                    return Err(format_err!("{}: {} (see `nak prefs check`)", file_name.display(), e));
+                }
+                prefs.file_name = Some(file_name);
+                prefs.modified = modified;
                 Ok(prefs)
             }
             Err(e) => {
                 if e.kind() == io::ErrorKind::NotFound {
-                    Ok(Prefs::default())
+                    Ok(Prefs {
+                        file_name: Some(file_name),
+                        ..Prefs::default()
+                    })
                 } else {
                     Err(e.into())
                 }
@@ -45,65 +560,680 @@ impl Prefs {
         }
     }
 
-    pub fn expand(&self, cmd: Vec<String>) -> Vec<String> {
+    /// Parse the contents of a prefs file, stopping at the first problem.
+    pub fn parse(contents: &str) -> Result<Prefs, PrefsError> {
+        let (prefs, mut errors) = Prefs::parse_all(contents);
+        if errors.is_empty() {
+            Ok(prefs)
+        } else {
+            Err(errors.remove(0))
+        }
+    }
+
+    /// Parse the contents of a prefs file, collecting every problem along the way.  Anything with a
+    /// problem is left out of the returned `Prefs`.
+    ///
+    /// Files from before the `alias` syntax existed are still accepted as json.
+    pub fn parse_all(contents: &str) -> (Prefs, Vec<PrefsError>) {
+        Prefs::parse_in(contents, None)
+    }
+
+    // Like `parse_all`, with relative `script` paths taken from `dir`.
+    fn parse_in(contents: &str, dir: Option<&Path>) -> (Prefs, Vec<PrefsError>) {
+        if is_legacy_json(contents) {
+            return Prefs::parse_json(contents);
+        }
 
-        'outer: for rule in &self.aliases {
-            let mut var_matches = HashMap::new();
-            let mut expando_matches = HashMap::new();
-
-            let mut it = cmd.iter();
-            let mut next = it.next();
-
-            for item in &rule.find {
-                match item {
-                    Item::Literal(word) => {
-                        if next == Some(word) {
-                            next = it.next();
-                        } else {
-                            continue 'outer;
-                        }
+        let mut prefs = Prefs::default();
+        let mut errors = Vec::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let res = if line.ends_with(']') {
+                    Matcher::parse(&line[1..line.len() - 1])
+                } else {
+                    Err(format_err!("expected `]`"))
+                };
+                match res {
+                    Ok(matcher) => prefs.overrides.push(Override {
+                        matcher,
+                        aliases: Vec::new(),
+                        settings: Settings::default(),
+                    }),
+                    Err(e) => {
+                        errors.push(PrefsError {
+                            line: Some(i + 1),
+                            field: "section".to_string(),
+                            message: e.to_string(),
+                        });
+                        // Don't let what follows leak into the enclosing scope.
+                        prefs.overrides.push(Override {
+                            matcher: Matcher::Depth(0),
+                            aliases: Vec::new(),
+                            settings: Settings::default(),
+                        });
                     }
-                    Item::Variable(id) => {
-                        assert!(!var_matches.contains_key(id));
-                        assert!(!expando_matches.contains_key(id));
+                }
+                continue;
+            }
+
+            let (directive, rest) = match line.find(char::is_whitespace) {
+                Some(pos) => (&line[..pos], line[pos..].trim()),
+                None => (line, ""),
+            };
+
+            let in_section = !prefs.overrides.is_empty();
+            // Everything after a `[...]` belongs to that section.
+            let (aliases, settings) = match prefs.overrides.last_mut() {
+                Some(o) => (&mut o.aliases, &mut o.settings),
+                None => (&mut prefs.aliases, &mut prefs.settings),
+            };
+
+            let (key, value) = match rest.find('=') {
+                Some(pos) => (rest[..pos].trim(), rest[pos + 1..].trim()),
+                None => (rest, ""),
+            };
+
+            let res = match directive {
+                "alias" => Rule::parse_alias(rest).map(|rule| aliases.push(rule)),
+                "set" if !rest.contains('=') => Err(format_err!("expected `set <name> = <value>`")),
+                "set" => settings.set(key, value),
+                "export" if key.is_empty() || !rest.contains('=') => Err(format_err!("expected `export <NAME>=<value>`")),
+                "export" => Ok(settings.env.push((key.to_string(), value.to_string()))),
+                "script" if in_section => Err(format_err!("the script applies everywhere, so it has to come before any `[...]`")),
+                "script" if prefs.script.is_some() => Err(format_err!("there can only be one script")),
+                "script" => match load_script(rest, dir) {
+                    Ok(script) => Ok(prefs.script = Some(Arc::new(script))),
+                    Err(e) => Err(e),
+                },
+                _ => Err(format_err!("unknown setting (expected `alias`, `set`, `export` or `script`)")),
+            };
+
+            if let Err(e) = res {
+                let field = match directive {
+                    "alias" | "set" | "export" | "script" => format!("{} {}", directive, key),
+                    _ => directive.to_string(),
+                };
+                errors.push(PrefsError {
+                    line: Some(i + 1),
+                    field,
+                    message: e.to_string(),
+                });
+            }
+        }
+
+        (prefs, errors)
+    }
+
+    fn parse_json(contents: &str) -> (Prefs, Vec<PrefsError>) {
+        let mut prefs: Prefs = match serde_json::from_str(contents) {
+            Ok(prefs) => prefs,
+            Err(e) => return (Prefs::default(), vec![PrefsError {
+                line: Some(e.line()),
+                field: "json".to_string(),
+                message: e.to_string(),
+            }]),
+        };
+
+        let mut errors = Vec::new();
+        let mut index = 0;
+        prefs.aliases.retain(|rule| {
+            let res = rule.validate();
+            if let Err(ref e) = res {
+                errors.push(PrefsError {
+                    line: None,
+                    field: format!("aliases[{}]", index),
+                    message: e.to_string(),
+                });
+            }
+            index += 1;
+            res.is_ok()
+        });
+
+        (prefs, errors)
+    }
+
+    /// The prefs that apply on the remote described by `info`, `depth` remotes deep (counting the
+    /// local one as 1): any matching sections layered on top of the rest, later ones winning.
+    pub fn for_remote(&self, info: &RemoteInfo, depth: usize) -> Prefs {
+        let mut res = Prefs {
+            aliases: Vec::new(),
+            settings: self.settings.clone(),
+            overrides: Vec::new(),
+            local_dir: if depth == 1 { Some(PathBuf::from(&info.working_dir)) } else { None },
+            script: self.script.clone(),
+            remote: Some((info.clone(), depth)),
+            commands: None,
+            stack: Vec::new(),
+            file_name: None,
+            modified: None,
+        };
+
+        let matching: Vec<&Override> = self.overrides.iter()
+            .filter(|o| o.matcher.matches(info, depth))
+            .collect();
+
+        for o in &matching {
+            res.settings.merge(&o.settings);
+        }
+        // `expand` uses the first rule that matches, so the most specific go first.
+        for o in matching.iter().rev() {
+            res.aliases.extend(o.aliases.iter().cloned());
+        }
+        res.aliases.extend(self.aliases.iter().cloned());
+
+        res
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Whether the file has changed since it was last loaded (or we last tried to).
+    pub fn changed_on_disk(&self) -> bool {
+        match self.file_name {
+            Some(ref file_name) => modified_time(file_name) != self.modified,
+            None => false,
+        }
+    }
+
+    /// Re-read the prefs file.  If it has a problem, the current prefs are kept and the change
+    /// isn't reported again until the file is next modified.
+    ///
+    /// Anything defined interactively (without `--save`) is dropped.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let file_name = match self.file_name {
+            Some(ref file_name) => file_name.clone(),
+            None => return Err(format_err!("these prefs weren't loaded from a file")),
+        };
+
+        self.modified = modified_time(&file_name);
+        *self = Prefs::load_from(file_name)?;
+        Ok(())
+    }
+
+    /// Every problem with the prefs file at `file_name`, or the default one.
+    pub fn check(file_name: Option<&Path>) -> Result<Vec<PrefsError>, Error> {
+        let file_name = file_name.map(|f| f.to_path_buf()).unwrap_or_else(default_path);
+        let contents = fs::read_to_string(&file_name)?;
+        Ok(Prefs::parse_in(&contents, file_name.parent()).1)
+    }
+
+    pub fn aliases(&self) -> &[Rule] {
+        &self.aliases
+    }
+
+    /// Add `rule`, replacing any existing rule that matches exactly the same commands.
+    pub fn add_alias(&mut self, rule: Rule) {
+        match self.aliases.iter().position(|r| r.find == rule.find) {
+            Some(i) => self.aliases[i] = rule,
+            None => self.aliases.push(rule),
+        }
+    }
+
+    /// Remove the aliases matching `pattern`: either a whole `find` pattern, or a single word
+    /// to remove every alias starting with it.  Returns what was removed.
+    pub fn remove_aliases(&mut self, pattern: &str) -> Vec<Rule> {
+        let (removed, kept) = self.aliases.drain(..)
+            .partition(|r| r.matches_pattern(pattern));
+        self.aliases = kept;
+        removed
+    }
+
+    /// Whether any `[...]` section has an alias `remove_aliases(pattern)` would remove.
+    pub fn section_has_alias(&self, pattern: &str) -> bool {
+        self.overrides.iter()
+            .flat_map(|o| &o.aliases)
+            .any(|r| r.matches_pattern(pattern))
+    }
+
+    /// Apply the same change as `add_alias` / `remove_aliases` to the prefs file, leaving the rest
+    /// of it (comments and all) alone.  A json file gets converted to the `alias` syntax.
+    pub fn save_aliases(&mut self, added: &[Rule], removed: &[Rule]) -> Result<(), Error> {
+        let file_name = match self.file_name {
+            Some(ref file_name) => file_name,
+            None => return Err(format_err!("these prefs weren't loaded from a file")),
+        };
+
+        let contents = match fs::read_to_string(file_name) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut lines: Vec<String> = if is_legacy_json(&contents) {
+            Prefs::parse(&contents)?.aliases.iter().map(|r| format!("alias {}", r)).collect()
+        } else {
+            contents.lines().map(|l| l.to_string()).collect()
+        };
+
+        // Only the part before any `[...]` section applies everywhere.
+        let sections = lines.iter().position(|l| l.trim().starts_with('[')).unwrap_or(lines.len());
+        let sections = lines.split_off(sections);
+
+        lines.retain(|line| {
+            let line = line.trim();
+            if !line.starts_with("alias ") {
+                return true;
+            }
+            match Rule::parse_alias(&line["alias ".len()..]) {
+                Ok(rule) => !removed.iter().chain(added).any(|r| r.find == rule.find),
+                Err(_) => true,
+            }
+        });
+        while lines.last().map_or(false, |l| l.trim().is_empty()) {
+            lines.pop();
+        }
+        lines.extend(added.iter().map(|r| format!("alias {}", r)));
+        if !sections.is_empty() {
+            lines.push(String::new());
+            lines.extend(sections);
+        }
+
+        if let Some(dir) = file_name.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(file_name, lines.join("\n") + "\n")?;
+        // Everything written is already in effect; don't throw away the rest by reloading.
+        self.modified = modified_time(file_name);
+
+        Ok(())
+    }
+
+    /// Let the script know which commands exist on the remote (see `for_remote`).
+    pub fn with_commands(mut self, commands: Option<&HashSet<String>>) -> Prefs {
+        self.commands = commands.cloned();
+        self
+    }
+
+    /// Let the script see every remote on the stack, local machine first, and how long each
+    /// last took to answer a ping.
+    pub fn with_stack(mut self, stack: Vec<(RemoteInfo, Option<Duration>)>) -> Prefs {
+        self.stack = stack;
+        self
+    }
+
+    /// The remote these prefs were picked for by `for_remote`.
+    pub fn remote(&self) -> Option<&RemoteInfo> {
+        self.remote.as_ref().map(|(info, _)| info)
+    }
+
+    /// Call the script's function `name`, if there is a script and it defines one.
+    pub fn call_script(&self, name: &str, args: Vec<Value>) -> Result<Option<Value>, Error> {
+        let script = match self.script {
+            Some(ref script) => script,
+            None => return Ok(None),
+        };
+        let env = Env {
+            prefs: self,
+            remote: self.remote.as_ref().map(|&(ref info, depth)| (info, depth)),
+            commands: self.commands.as_ref(),
+            stack: &self.stack,
+        };
+        script.call(name, args, &env)
+    }
+
+    /// Whether some alias applies to commands starting with `word`.
+    pub fn is_alias(&self, word: &str) -> bool {
+        self.aliases.iter().any(|rule| rule.starts_with(word))
+    }
+
+    fn check_guard(&self, guard: Guard, word: &str) -> bool {
+        // Only the local machine can be checked without a round trip; elsewhere, play it safe.
+        let dir = match self.local_dir {
+            Some(ref dir) => dir,
+            None => return false,
+        };
+        match fs::metadata(dir.join(word)) {
+            Ok(m) => match guard {
+                Guard::File => m.is_file(),
+                Guard::Dir => m.is_dir(),
+            },
+            Err(_) => false,
+        }
+    }
 
-                        if let Some(word) = next {
-                            var_matches.insert(id, word.clone());
+    /// Match `rule` against the start of `words`, returning what to replace them all with.
+    fn apply(&self, rule: &Rule, words: &[String]) -> Option<Vec<String>> {
+        let mut matches = HashMap::new();
+        let mut pos = 0;
 
-                            next = it.next();
-                        } else {
-                            continue 'outer;
-                        }
+        for item in &rule.find {
+            match item {
+                Item::Literal(_) | Item::OneOf(_) => {
+                    if !word_matches(item, words.get(pos)?) {
+                        return None;
                     }
-                    Item::Expando(id) => {
-                        assert!(!var_matches.contains_key(id));
-                        assert!(!expando_matches.contains_key(id));
-
-                        let mut words = Vec::new();
-                        if let Some(next) = next {
-                            words.push(next.clone());
-                            for i in &mut it {
-                                words.push(i.clone());
-                            }
-                        }
-                        expando_matches.insert(id, words);
+                    pos += 1;
+                }
+                Item::Optional(inner) => {
+                    if words.get(pos).map_or(false, |w| word_matches(inner, w)) {
+                        pos += 1;
                     }
                 }
+                Item::Variable(id) => {
+                    matches.insert(*id, vec![words.get(pos)?.clone()]);
+                    pos += 1;
+                }
+                Item::Guarded(id, guard) => {
+                    let word = words.get(pos)?;
+                    if !self.check_guard(*guard, word) {
+                        return None;
+                    }
+                    matches.insert(*id, vec![word.clone()]);
+                    pos += 1;
+                }
+                Item::Expando(id) => {
+                    matches.insert(*id, words[pos..].to_vec());
+                    pos = words.len();
+                }
+            }
+        }
+
+        // Successfully matched, now construct the output!
+        let mut res = Vec::new();
+        for item in &rule.replace {
+            match item {
+                Item::Literal(word) => res.push(word.clone()),
+                Item::Variable(id) | Item::Expando(id) => res.extend(matches[id].iter().cloned()),
+                _ => unreachable!("rejected by Rule::validate"),
             }
+        }
+
+        Some(res)
+    }
+
+    /// If the command being typed, `words`, ends with an abbreviation: how many of the words it
+    /// covers, and what to replace them with.
+    pub fn abbreviation(&self, words: &[&str]) -> Option<(usize, String)> {
+        for rule in self.aliases.iter().filter(|r| r.abbr) {
+            let n = rule.find.len();
+            if n > words.len() || (n < words.len() && !rule.anywhere) {
+                continue;
+            }
+            let tail = &words[words.len() - n..];
+            if rule.find.iter().zip(tail).all(|(item, word)| word_matches(item, word)) {
+                let replace = rule.replace.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+                return Some((n, replace.join(" ")));
+            }
+        }
+        None
+    }
+
+    /// Rewrite `cmd` with the first rule that matches, then do the same to the result, and so on.
+    ///
+    /// Each rule is used at most once, so that e.g. `rm ...=rm -i ...` terminates, as do cycles
+    /// like `a=b` and `b=a`.
+    pub fn expand(&self, cmd: Vec<String>) -> Vec<String> {
+        let mut cmd = cmd;
+        let mut used = vec![false; self.aliases.len()];
+
+        'again: loop {
+            for (i, rule) in self.aliases.iter().enumerate() {
+                if used[i] {
+                    continue;
+                }
+
+                let starts = if rule.anywhere { cmd.len() } else { cmd.len().min(1) };
+                for start in 0..starts {
+                    if let Some(res) = self.apply(rule, &cmd[start..]) {
+                        cmd.truncate(start);
+                        cmd.extend(res);
+                        used[i] = true;
+                        continue 'again;
+                    }
+                }
+            }
+
+            return cmd;
+        }
+    }
+
+    /// `expand`, then the script's `expand(words)`, which can return a new list of words or
+    /// `nil` to leave them alone.
+    pub fn expand_command(&self, cmd: Vec<String>) -> Result<Vec<String>, Error> {
+        let cmd = self.expand(cmd);
+        match self.call_script("expand", vec![Value::from_words(&cmd)])? {
+            None | Some(Value::Nil) => Ok(cmd),
+            Some(res) => match res.into_words() {
+                Some(ref words) if words.is_empty() => Err(format_err!("the script's `expand` returned no words")),
+                Some(words) => Ok(words),
+                None => Err(format_err!("the script's `expand` should return a list of strings or nil")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn alias_syntax() {
+        let prefs = Prefs::parse("
+            # git shortcuts
+            alias g a ...=git add ...
+            alias g ...=git ...
+            alias twice $x=echo $x $x
+        ").unwrap();
+
+        assert_eq!(prefs.expand(words("g a -p")), words("git add -p"));
+        assert_eq!(prefs.expand(words("g log")), words("git log"));
+        assert_eq!(prefs.expand(words("twice hi")), words("echo hi hi"));
+
+        let rule = Rule::parse_alias("vagrant ssh $host ...=nak plugin $host ...").unwrap();
+        assert_eq!(rule.to_string(), "vagrant ssh $1 ...=nak plugin $1 ...");
+        assert_eq!(Rule::parse_alias(&rule.to_string()).unwrap(), rule);
+
+        assert!(Rule::parse_alias("g a").is_err());
+        assert!(Rule::parse_alias("$x=echo").is_err());
+        assert!(Rule::parse_alias("g $x $x=echo").is_err());
+        assert!(Rule::parse_alias("g=echo $y").is_err());
+        assert!(Rule::parse_alias("g ... x=echo").is_err());
+        assert!(Prefs::parse("alias g=git\nbogus").is_err());
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let (prefs, errors) = Prefs::parse_all("alias g ...=git ...\n\nalias h ... x=y\nset a = b\n");
+        assert_eq!(prefs.aliases().len(), 1);
+        assert_eq!(errors.iter().map(|e| (e.line, e.field.as_str())).collect::<Vec<_>>(),
+            vec![(Some(3), "alias h ... x"), (Some(4), "set a")]);
+
+        let json = r#"{"aliases": [
+            {"find": [{"Literal": "a"}, {"Variable": 0}, {"Expando": 0}], "replace": []},
+            {"find": [{"Literal": "b"}], "replace": [{"Variable": 1}]}
+        ]}"#;
+        let (prefs, errors) = Prefs::parse_all(json);
+        assert_eq!(prefs.aliases().len(), 0);
+        assert_eq!(errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>(), vec!["aliases[0]", "aliases[1]"]);
+
+        assert_eq!(Prefs::parse("{").unwrap_err().field, "json");
+    }
+
+    #[test]
+    fn reload_keeps_working_prefs() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir.path().join("prefs.nak");
+
+        fs::write(&file_name, "alias g ...=git ...\n").unwrap();
+        let mut prefs = Prefs::load_from(file_name.clone()).unwrap();
+        assert!(!prefs.changed_on_disk());
 
-            // Successfully matched, now construct the output!
-            let mut res = Vec::new();
-            for item in &rule.replace {
-                match item {
-                    Item::Literal(word) => res.push(word.clone()),
-                    Item::Variable(id) => res.push(var_matches.remove(id).unwrap()),
-                    Item::Expando(id) => res.extend(expando_matches.remove(id).unwrap()),
+        fs::write(&file_name, "alias g ...=git ...\nalias oops\n").unwrap();
+        assert!(prefs.changed_on_disk());
+        assert!(prefs.reload().is_err());
+        assert!(!prefs.changed_on_disk());
+        assert_eq!(prefs.expand(words("g log")), words("git log"));
+
+        fs::write(&file_name, "alias h ...=git ...\n").unwrap();
+        prefs.reload().unwrap();
+        assert!(prefs.is_alias("h") && !prefs.is_alias("g"));
+    }
+
+    #[test]
+    fn scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir.path().join("prefs.nak");
+        fs::write(dir.path().join("prefs.nks"), r#"
+            fn expand(words) {
+                if words[0] == "serve" {
+                    if remote().depth > 1 { return ["echo", "not here"]; }
+                    return ["python3", "-m", "http.server"] + slice(words, 1);
                 }
             }
+        "#).unwrap();
+
+        fs::write(&file_name, "alias s ...=serve ...
+script prefs.nks
+").unwrap();
+        let prefs = Prefs::load_from(file_name.clone()).unwrap();
+        let local = prefs.for_remote(&remote("box", "me"), 1);
+        assert_eq!(local.expand_command(words("s 8000")).unwrap(), words("python3 -m http.server 8000"));
+        assert_eq!(local.expand_command(words("ls")).unwrap(), words("ls"));
+        assert_eq!(prefs.for_remote(&remote("box", "me"), 2).expand_command(words("serve")).unwrap(), vec!["echo".to_string(), "not here".to_string()]);
+
+        fs::write(&file_name, "script missing.nks\n[depth 2]\nscript prefs.nks\n").unwrap();
+        let errors = Prefs::check(Some(&file_name)).unwrap();
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), vec![Some(1), Some(3)]);
+        assert_eq!(errors[0].field, "script missing.nks");
+    }
 
-            return res;
+    fn remote(hostname: &str, username: &str) -> RemoteInfo {
+        RemoteInfo {
+            hostname: hostname.to_string(),
+            username: username.to_string(),
+            working_dir: "/".to_string(),
+            os: Some("linux".to_string()),
+            arch: Some("x86_64".to_string()),
+            ..RemoteInfo::default()
         }
+    }
+
+    #[test]
+    fn per_remote_overrides() {
+        let prefs = Prefs::parse("
+            alias rm ...=rm ...
+            export EDITOR=micro
+            set notify.after = 30s
+            set prompt.latency = on
+
+            [host *.prod.example.com]
+            set prompt.color = red
+            set notify.after = 5
+            set compression = on
+            set heartbeat = 30
+            alias rm ...=rm -i ...
+
+            [user vagrant]
+            alias b ...=cargo build --verbose ...
+            set dir = /vagrant
+
+            [depth 3]
+            set prompt.color = yellow
+
+            [os linux-x86_64]
+            alias open ...=xdg-open ...
+        ").unwrap();
+
+        let local = prefs.for_remote(&remote("laptop", "me"), 1);
+        assert_eq!(local.settings().prompt_color, None);
+        assert_eq!(local.settings().notify_after, Some(Duration::from_secs(30)));
+        assert_eq!(local.expand(words("rm x")), words("rm x"));
+        assert!(!local.is_alias("b"));
+        assert!(local.is_alias("open"));
+        assert!(!prefs.for_remote(&RemoteInfo { os: Some("macos".to_string()), ..remote("mac", "me") }, 1).is_alias("open"));
+
+        let prod = prefs.for_remote(&remote("db1.prod.example.com", "me"), 2);
+        assert_eq!(prod.settings().prompt_color, Some("red".to_string()));
+        assert_eq!(prod.settings().notify_after, Some(Duration::from_secs(5)));
+        assert_eq!(prod.expand(words("rm x")), words("rm -i x"));
+        assert_eq!(prod.settings().setup_commands(),
+            vec![
+                Command::SetEnvironment("EDITOR".to_string(), "micro".to_string()),
+                Command::SetCompression(true),
+            ]);
+        assert_eq!(local.settings().compression, None);
+        assert_eq!(local.settings().heartbeat(), Some(DEFAULT_HEARTBEAT));
+        assert_eq!(prod.settings().heartbeat(), Some(Duration::from_secs(30)));
+        assert_eq!(prod.settings().prompt_latency, Some(true));
+
+        let deep = prefs.for_remote(&remote("db1.prod.example.com", "vagrant"), 3);
+        assert_eq!(deep.settings().prompt_color, Some("yellow".to_string()));
+        assert_eq!(deep.expand(words("b")), words("cargo build --verbose"));
+        assert_eq!(deep.settings().dir, Some("/vagrant".to_string()));
+
+        assert!(Prefs::parse("[host]").is_err());
+        assert!(Prefs::parse("set prompt.color = mauve").is_err());
+        assert!(Prefs::parse("set notify.after = soon").is_err());
+        assert!(Prefs::parse("set compression = maybe").is_err());
+        assert_eq!(Prefs::parse("set heartbeat = off").unwrap().for_remote(&remote("laptop", "me"), 1).settings().heartbeat(), None);
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*.prod.*", "db1.prod.example.com"));
+        assert!(glob_match("web?", "web1"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("web?", "web12"));
+        assert!(!glob_match("*.prod", "prod"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+    }
+
+    #[test]
+    fn richer_expansion() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let prefs = Prefs::parse("
+            alias g s|st ...=git status ...
+            alias g [-v] l=git log --oneline
+            alias e $f:file=micro $f
+            alias e $d:dir=ls $d
+            alias --anywhere please ...=sudo ...
+            alias g ...=git ...
+            alias git ...=hub ...
+            alias rm ...=rm -i ...
+            alias a=b
+            alias b=a
+        ").unwrap();
+        let local = prefs.for_remote(&RemoteInfo {
+            hostname: "laptop".to_string(),
+            username: "me".to_string(),
+            working_dir: dir.path().to_str().unwrap().to_string(),
+            ..RemoteInfo::default()
+        }, 1);
+
+        assert_eq!(local.expand(words("g st -s")), words("hub status -s"));
+        assert_eq!(local.expand(words("g -v l")), words("hub log --oneline"));
+        assert_eq!(local.expand(words("g l")), words("hub log --oneline"));
+        // Words a rule doesn't match aren't carried over; that's what `...` is for.
+        assert_eq!(local.expand(words("g l extra")), words("hub log --oneline"));
+        assert_eq!(local.expand(words("e notes.txt")), words("micro notes.txt"));
+        assert_eq!(local.expand(words("e .")), words("ls ."));
+        assert_eq!(local.expand(words("e missing")), words("e missing"));
+        assert_eq!(local.expand(words("time please rm x")), words("time sudo rm x"));
+        assert_eq!(local.expand(words("rm x")), words("rm -i x"));
+        assert_eq!(local.expand(words("a")), words("a"));
+        assert_eq!(local.expand(words("ls a")), words("ls a"));
+
+        // Guards can't be checked past the local machine.
+        let remote = prefs.for_remote(&remote("server", "me"), 2);
+        assert_eq!(remote.expand(words("e /")), words("e /"));
 
-        cmd
+        let rule = Rule::parse_alias("--anywhere g s|st [-v] $f:file ...=git $f ...").unwrap();
+        assert_eq!(Rule::parse_alias(&rule.to_string()).unwrap(), rule);
+        assert!(Rule::parse_alias("g=git $f:file").is_err());
+        assert!(Rule::parse_alias("g [$x]=git").is_err());
+        assert!(Rule::parse_alias("[g] x=git").is_err());
     }
 }
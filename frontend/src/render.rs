@@ -0,0 +1,158 @@
+use std::io::{self, Write};
+
+use termion;
+use termion::{clear, color, cursor, style};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Style {
+    Plain,
+    Suggestion,
+    Command,
+    UnknownCommand,
+    Alias,
+    Quoted,
+    Operator,
+    Redirect,
+    Error,
+}
+
+impl Style {
+    fn write_start<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Style::Plain => Ok(()),
+            Style::Suggestion => write!(out, "{}", color::Fg(color::LightBlack)),
+            Style::Command => write!(out, "{}", color::Fg(color::Green)),
+            Style::UnknownCommand => write!(out, "{}", color::Fg(color::Red)),
+            Style::Alias => write!(out, "{}", color::Fg(color::Cyan)),
+            Style::Quoted => write!(out, "{}", color::Fg(color::Yellow)),
+            Style::Operator |
+            Style::Redirect => write!(out, "{}", color::Fg(color::Magenta)),
+            Style::Error => write!(out, "{}{}", color::Fg(color::Red), style::Underline),
+        }
+    }
+
+    fn write_end<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Style::Plain => Ok(()),
+            Style::Error => write!(out, "{}{}", color::Fg(color::Reset), style::NoUnderline),
+            _ => write!(out, "{}", color::Fg(color::Reset)),
+        }
+    }
+}
+
+/// Width of `text` on screen, not counting any escape sequences embedded in it.
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut in_escape = false;
+    for c in text.chars() {
+        if in_escape {
+            if c.is_ascii_alphabetic() {
+                in_escape = false;
+            }
+        } else if c == '\x1b' {
+            in_escape = true;
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+/// Shown in place of the prompt on the second and later lines of multi-line input.
+///
+/// It has to be exactly as wide as the real prompt, which is what liner assumes.
+pub fn continuation_prompt(prompt_width: usize) -> String {
+    if prompt_width >= 2 {
+        format!("{:>width$}", "> ", width = prompt_width)
+    } else {
+        " ".repeat(prompt_width)
+    }
+}
+
+// This has to agree exactly with the arithmetic in liner's `Editor::_display`, since liner
+// expects to find the terminal cursor where it left it the next time it redraws.
+fn layout_width(prompt_width: usize, text: &str, terminal_width: usize) -> usize {
+    let mut total = 0;
+    for line in text.split('\n') {
+        if total % terminal_width != 0 {
+            total = ((total / terminal_width) + 1) * terminal_width;
+        }
+        total += prompt_width + line.chars().count();
+    }
+    total
+}
+
+/// The escape sequence for a named foreground colour, as used in prefs.
+pub fn color_code(name: &str) -> Option<String> {
+    Some(match name {
+        "black" => color::Fg(color::Black).to_string(),
+        "red" => color::Fg(color::Red).to_string(),
+        "green" => color::Fg(color::Green).to_string(),
+        "yellow" => color::Fg(color::Yellow).to_string(),
+        "blue" => color::Fg(color::Blue).to_string(),
+        "magenta" => color::Fg(color::Magenta).to_string(),
+        "cyan" => color::Fg(color::Cyan).to_string(),
+        "white" => color::Fg(color::White).to_string(),
+        "default" => color::Fg(color::Reset).to_string(),
+        _ => return None,
+    })
+}
+
+fn terminal_width() -> usize {
+    match termion::terminal_size() {
+        Ok((0, _)) | Err(_) => 80,
+        Ok((cols, _)) => cols as usize,
+    }
+}
+
+/// Redraw the line liner just displayed, with our own styling on top.
+///
+/// `cursor` is a char offset into the concatenated text of `spans`, and must fall
+/// before any `Style::Suggestion` span.
+pub fn redraw<W: Write>(out: &mut W, prompt: &str, spans: &[(Style, &str)], cursor: usize) -> io::Result<()> {
+    let w = terminal_width();
+    let prompt_width = display_width(prompt);
+
+    let text: String = spans.iter().map(|s| s.1).collect();
+    let before_cursor: String = text.chars().take(cursor).collect();
+
+    let cursor_total = layout_width(prompt_width, &before_cursor, w);
+    let end_total = layout_width(prompt_width, &text, w);
+
+    let cursor_row = cursor_total / w;
+    if cursor_row > 0 {
+        write!(out, "{}", cursor::Up(cursor_row as u16))?;
+    }
+    write!(out, "\r{}{}", clear::AfterCursor, prompt)?;
+
+    for &(style, span) in spans {
+        style.write_start(out)?;
+        for (i, line) in span.split('\n').enumerate() {
+            if i > 0 {
+                write!(out, "\r\n{}{}{}",
+                    color::Fg(color::LightBlack),
+                    continuation_prompt(prompt_width),
+                    color::Fg(color::Reset))?;
+                style.write_start(out)?;
+            }
+            write!(out, "{}", line)?;
+        }
+        style.write_end(out)?;
+    }
+
+    if end_total % w == 0 {
+        write!(out, "\r\n")?;
+    }
+
+    let end_row = end_total / w;
+    if end_row > cursor_row {
+        write!(out, "{}", cursor::Up((end_row - cursor_row) as u16))?;
+    }
+    write!(out, "\r")?;
+    let cursor_col = cursor_total % w;
+    if cursor_col > 0 {
+        write!(out, "{}", cursor::Right(cursor_col as u16))?;
+    }
+
+    out.flush()
+}
@@ -0,0 +1,1235 @@
+//! A small scripting language for prefs, e.g. to compute the prompt or rewrite commands with
+//! more logic than alias rules allow.
+//!
+//! Scripts are sandboxed: they can't touch processes or the network, only look at what `Env`
+//! exposes, and each call gets a fixed budget of steps and of memory so a runaway loop can't hang
+//! the shell or run it out of memory.  Nor can they touch files, with one exception: `log`
+//! appends a line to ~/.config/nak/script.log (e.g. for auditing from `post_exec`).  Their only
+//! other effects are `print`ing to the terminal and setting its `title`.
+//!
+//! ```text
+//! # Comments start with `#`.
+//! fn prompt() {
+//!     let r = remote();
+//!     let p = r.hostname + ":" + r.working_dir;
+//!     if r.depth > 1 {
+//!         p = color("yellow", "(" + str(r.depth) + ") ") + p;
+//!     }
+//!     return p + "$ ";
+//! }
+//!
+//! fn expand(words) {
+//!     if words[0] == "serve" && len(words) == 1 {
+//!         return ["python3", "-m", "http.server"];
+//!     }
+//! }
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use failure::{Error, Fail};
+use dirs;
+
+use protocol::RemoteInfo;
+
+use crate::prefs::Prefs;
+use crate::render;
+
+/// How many expressions and statements a single call may evaluate.
+const FUEL: usize = 100_000;
+const MAX_DEPTH: usize = 64;
+/// How deeply blocks and expressions may nest in the source.
+const MAX_NESTING: usize = 32;
+/// How many blocks and expressions, across all the calls in progress, may be in the middle of
+/// running at once.  Each is a stack frame, and a big one in a debug build.
+const MAX_STACK: usize = 256;
+/// Roughly how many bytes of values a single call may build, all told.
+const MEMORY: usize = 32 << 20;
+
+fn log_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".config").join("nak").join("script.log"))
+}
+
+fn append_log(line: &str) -> io::Result<()> {
+    let path = log_path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{}", line.replace('\n', " "))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Nil | Value::Bool(false) | Value::Int(0) => false,
+            Value::Str(s) => !s.is_empty(),
+            Value::List(l) => !l.is_empty(),
+            _ => true,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+
+    /// `Some` if this is a list of strings.
+    pub fn into_words(self) -> Option<Vec<String>> {
+        match self {
+            Value::List(items) => items.into_iter().map(|i| match i {
+                Value::Str(s) => Some(s),
+                _ => None,
+            }).collect(),
+            _ => None,
+        }
+    }
+
+    // Roughly how many bytes it takes up, or `None` if it's nested more than `depth` deep
+    // (which would make cloning or dropping it recurse too far).
+    fn weight(&self, depth: usize) -> Option<usize> {
+        let own = mem::size_of::<Value>();
+        Some(match self {
+            _ if depth == 0 => return None,
+            Value::Str(s) => own + s.len(),
+            Value::List(items) => {
+                let mut total = own;
+                for item in items {
+                    total += item.weight(depth - 1)?;
+                }
+                total
+            }
+            Value::Map(map) => {
+                let mut total = own;
+                for (k, v) in map {
+                    total += k.len() + v.weight(depth - 1)?;
+                }
+                total
+            }
+            _ => own,
+        })
+    }
+
+    /// What `remote()` returns for `info`, `depth` remotes deep.
+    pub fn from_remote(info: &RemoteInfo, depth: usize) -> Value {
+        Value::Map(remote_map(info, depth))
+    }
+
+    pub fn from_words(words: &[String]) -> Value {
+        Value::List(words.iter().map(|w| Value::Str(w.clone())).collect())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// A problem with a script; `line` is 1-based, and 0 for problems found while running it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "line {}: {}", self.line, self.message)
+        } else {
+            write!(f, "{}", self.message)
+        }
+    }
+}
+
+impl Fail for ScriptError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Int(i64),
+    Str(String),
+    Punct(&'static str),
+}
+
+const PUNCTS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||",
+    "(", ")", "{", "}", "[", "]", ",", ";", ".", "=", "<", ">", "+", "-", "*", "/", "%", "!",
+];
+
+fn lex(text: &str) -> Result<Vec<(Tok, usize)>, ScriptError> {
+    let mut toks = Vec::new();
+    let mut line = 1;
+    let mut chars = text.char_indices().peekable();
+
+    let fail = |line, message: String| Err(ScriptError { line, message });
+
+    while let Some(&(i, c)) = chars.peek() {
+        if c == '\n' {
+            line += 1;
+            chars.next();
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            while chars.peek().map_or(false, |&(_, c)| c != '\n') {
+                chars.next();
+            }
+        } else if c.is_ascii_digit() {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !c.is_ascii_digit() {
+                    break;
+                }
+                end = j + 1;
+                chars.next();
+            }
+            match text[i..end].parse() {
+                Ok(n) => toks.push((Tok::Int(n), line)),
+                Err(_) => return fail(line, format!("number too big: {}", &text[i..end])),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            toks.push((Tok::Ident(text[i..end].to_string()), line));
+        } else if c == '"' {
+            let start = line;
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => s.push('\n'),
+                        Some((_, 't')) => s.push('\t'),
+                        Some((_, 'e')) => s.push('\x1b'),
+                        Some((_, c)) => s.push(c),
+                        None => return fail(start, "unterminated string".to_string()),
+                    },
+                    Some((_, c)) => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        s.push(c);
+                    }
+                    None => return fail(start, "unterminated string".to_string()),
+                }
+            }
+            toks.push((Tok::Str(s), start));
+        } else {
+            match PUNCTS.iter().find(|p| text[i..].starts_with(*p)) {
+                Some(p) => {
+                    for _ in 0..p.len() {
+                        chars.next();
+                    }
+                    toks.push((Tok::Punct(p), line));
+                }
+                None => return fail(line, format!("unexpected `{}`", c)),
+            }
+        }
+    }
+
+    Ok(toks)
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Lit(Value),
+    List(Vec<Expr>),
+    Var(String),
+    Call(String, Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Field(Box<Expr>, String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+enum Stmt {
+    Let(String, Expr),
+    Assign(String, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    For(String, Expr, Vec<Stmt>),
+    Return(Option<Expr>),
+    Expr(Expr),
+}
+
+#[derive(Clone, Debug)]
+struct Function {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+struct Parser {
+    toks: Vec<(Tok, usize)>,
+    pos: usize,
+    /// How many blocks and expressions deep we are; see `MAX_NESTING`.
+    depth: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.toks.get(self.pos).or(self.toks.last()).map_or(1, |t| t.1)
+    }
+
+    fn fail<T>(&self, message: String) -> Result<T, ScriptError> {
+        Err(ScriptError { line: self.line(), message })
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos).map(|t| &t.0)
+    }
+
+    fn at(&self, p: &str) -> bool {
+        match self.peek() {
+            Some(Tok::Punct(q)) => *q == p,
+            _ => false,
+        }
+    }
+
+    fn at_keyword(&self, k: &str) -> bool {
+        match self.peek() {
+            Some(Tok::Ident(i)) => i == k,
+            _ => false,
+        }
+    }
+
+    fn eat(&mut self, p: &str) -> bool {
+        if self.at(p) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, p: &str) -> Result<(), ScriptError> {
+        if self.eat(p) {
+            Ok(())
+        } else {
+            self.fail(format!("expected `{}`", p))
+        }
+    }
+
+    // Called on the way into anything that nests, with a matching `leave` on the way out.
+    fn enter(&mut self) -> Result<(), ScriptError> {
+        if self.depth >= MAX_NESTING {
+            return self.fail("nested too deeply".to_string());
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self, levels: usize) {
+        self.depth -= levels;
+    }
+
+    fn ident(&mut self) -> Result<String, ScriptError> {
+        match self.peek().cloned() {
+            Some(Tok::Ident(i)) => {
+                self.pos += 1;
+                Ok(i)
+            }
+            _ => self.fail("expected a name".to_string()),
+        }
+    }
+
+    fn function(&mut self) -> Result<(String, Function), ScriptError> {
+        if !self.at_keyword("fn") {
+            return self.fail("expected `fn`".to_string());
+        }
+        self.pos += 1;
+        let name = self.ident()?;
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.eat(")") {
+            params.push(self.ident()?);
+            if !self.at(")") {
+                self.expect(",")?;
+            }
+        }
+        let body = self.block()?;
+        Ok((name, Function { params, body }))
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, ScriptError> {
+        self.expect("{")?;
+        self.enter()?;
+        let mut stmts = Vec::new();
+        while !self.eat("}") {
+            if self.peek().is_none() {
+                return self.fail("expected `}`".to_string());
+            }
+            stmts.push(self.stmt()?);
+        }
+        self.leave(1);
+        Ok(stmts)
+    }
+
+    fn stmt(&mut self) -> Result<Stmt, ScriptError> {
+        if self.at_keyword("let") {
+            self.pos += 1;
+            let name = self.ident()?;
+            self.expect("=")?;
+            let value = self.expr()?;
+            self.expect(";")?;
+            return Ok(Stmt::Let(name, value));
+        }
+        if self.at_keyword("if") {
+            return self.if_stmt();
+        }
+        if self.at_keyword("for") {
+            self.pos += 1;
+            let name = self.ident()?;
+            if !self.at_keyword("in") {
+                return self.fail("expected `in`".to_string());
+            }
+            self.pos += 1;
+            let list = self.expr()?;
+            let body = self.block()?;
+            return Ok(Stmt::For(name, list, body));
+        }
+        if self.at_keyword("return") {
+            self.pos += 1;
+            let value = if self.at(";") { None } else { Some(self.expr()?) };
+            self.expect(";")?;
+            return Ok(Stmt::Return(value));
+        }
+
+        let expr = self.expr()?;
+        if self.eat("=") {
+            let name = match expr {
+                Expr::Var(name) => name,
+                _ => return self.fail("can only assign to a variable".to_string()),
+            };
+            let value = self.expr()?;
+            self.expect(";")?;
+            return Ok(Stmt::Assign(name, value));
+        }
+        self.expect(";")?;
+        Ok(Stmt::Expr(expr))
+    }
+
+    fn if_stmt(&mut self) -> Result<Stmt, ScriptError> {
+        self.pos += 1;
+        let cond = self.expr()?;
+        let then = self.block()?;
+        let otherwise = if self.at_keyword("else") {
+            self.pos += 1;
+            if self.at_keyword("if") {
+                // Each `else if` nests inside the one before.
+                self.enter()?;
+                let res = self.if_stmt()?;
+                self.leave(1);
+                vec![res]
+            } else {
+                self.block()?
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(Stmt::If(cond, then, otherwise))
+    }
+
+    // Each operator in a chain like `a + b + c` nests the left-hand side one deeper.
+    fn expr(&mut self) -> Result<Expr, ScriptError> {
+        self.enter()?;
+        let mut levels = 1;
+        let mut lhs = self.and()?;
+        while self.eat("||") {
+            self.enter()?;
+            levels += 1;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        self.leave(levels);
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, ScriptError> {
+        let mut levels = 0;
+        let mut lhs = self.comparison()?;
+        while self.eat("&&") {
+            self.enter()?;
+            levels += 1;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.comparison()?));
+        }
+        self.leave(levels);
+        Ok(lhs)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ScriptError> {
+        let lhs = self.binary(&["+", "-"], Parser::term)?;
+        for op in &["==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat(op) {
+                let rhs = self.binary(&["+", "-"], Parser::term)?;
+                return Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Expr, ScriptError> {
+        self.binary(&["*", "/", "%"], Parser::unary)
+    }
+
+    fn binary(&mut self, ops: &[&'static str], next: fn(&mut Parser) -> Result<Expr, ScriptError>) -> Result<Expr, ScriptError> {
+        let mut levels = 0;
+        let mut lhs = next(self)?;
+        'outer: loop {
+            for op in ops {
+                if self.eat(op) {
+                    self.enter()?;
+                    levels += 1;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(next(self)?));
+                    continue 'outer;
+                }
+            }
+            self.leave(levels);
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ScriptError> {
+        let op: fn(Box<Expr>) -> Expr = if self.eat("!") {
+            Expr::Not
+        } else if self.eat("-") {
+            Expr::Neg
+        } else {
+            return self.postfix();
+        };
+        self.enter()?;
+        let operand = self.unary()?;
+        self.leave(1);
+        Ok(op(Box::new(operand)))
+    }
+
+    fn postfix(&mut self) -> Result<Expr, ScriptError> {
+        let mut levels = 0;
+        let mut expr = self.primary()?;
+        loop {
+            if self.at("[") || self.at(".") {
+                self.enter()?;
+                levels += 1;
+            }
+            if self.eat("[") {
+                let index = self.expr()?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.eat(".") {
+                expr = Expr::Field(Box::new(expr), self.ident()?);
+            } else {
+                self.leave(levels);
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn list(&mut self, end: &str) -> Result<Vec<Expr>, ScriptError> {
+        let mut items = Vec::new();
+        while !self.eat(end) {
+            items.push(self.expr()?);
+            if !self.at(end) {
+                self.expect(",")?;
+            }
+        }
+        Ok(items)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ScriptError> {
+        let tok = match self.peek().cloned() {
+            Some(tok) => tok,
+            None => return self.fail("unexpected end of script".to_string()),
+        };
+        self.pos += 1;
+
+        Ok(match tok {
+            Tok::Int(n) => Expr::Lit(Value::Int(n)),
+            Tok::Str(s) => Expr::Lit(Value::Str(s)),
+            Tok::Ident(ref i) if i == "true" => Expr::Lit(Value::Bool(true)),
+            Tok::Ident(ref i) if i == "false" => Expr::Lit(Value::Bool(false)),
+            Tok::Ident(ref i) if i == "nil" => Expr::Lit(Value::Nil),
+            Tok::Ident(name) => {
+                if self.eat("(") {
+                    Expr::Call(name, self.list(")")?)
+                } else {
+                    Expr::Var(name)
+                }
+            }
+            Tok::Punct("[") => Expr::List(self.list("]")?),
+            Tok::Punct("(") => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                expr
+            }
+            Tok::Punct(p) => {
+                self.pos -= 1;
+                return self.fail(format!("unexpected `{}`", p));
+            }
+        })
+    }
+}
+
+/// What a script can see of the shell while it runs.  It's all read-only.
+pub struct Env<'a> {
+    pub prefs: &'a Prefs,
+    /// The remote at the top of the stack, and how deep it is (the local machine is 1).
+    pub remote: Option<(&'a RemoteInfo, usize)>,
+    /// Commands known to exist on that remote, if we've found out yet.
+    pub commands: Option<&'a HashSet<String>>,
+    /// Every remote on the stack, local machine first, with how long it last took to answer a
+    /// ping (if it has).
+    pub stack: &'a [(RemoteInfo, Option<Duration>)],
+}
+
+#[derive(Debug, Default)]
+pub struct Script {
+    functions: HashMap<String, Function>,
+}
+
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+struct Interp<'a> {
+    script: &'a Script,
+    env: &'a Env<'a>,
+    fuel: usize,
+    /// Bytes left of `MEMORY`.
+    memory: usize,
+    depth: usize,
+    /// See `MAX_STACK`.
+    stack: usize,
+}
+
+type Scope = HashMap<String, Value>;
+
+fn expect_str(v: &Value, what: &str) -> Result<String, String> {
+    match v {
+        Value::Str(s) => Ok(s.clone()),
+        _ => Err(format!("{} should be a string, not {}", what, v.type_name())),
+    }
+}
+
+fn expect_int(v: &Value, what: &str) -> Result<i64, String> {
+    match v {
+        Value::Int(i) => Ok(*i),
+        _ => Err(format!("{} should be an int, not {}", what, v.type_name())),
+    }
+}
+
+fn remote_map(info: &RemoteInfo, depth: usize) -> BTreeMap<String, Value> {
+    let mut m = BTreeMap::new();
+    m.insert("hostname".to_string(), Value::Str(info.hostname.clone()));
+    m.insert("username".to_string(), Value::Str(info.username.clone()));
+    m.insert("working_dir".to_string(), Value::Str(info.working_dir.clone()));
+    m.insert("depth".to_string(), Value::Int(depth as i64));
+    let optional = |v: &Option<String>| v.clone().map_or(Value::Nil, Value::Str);
+    m.insert("home_dir".to_string(), optional(&info.home_dir));
+    m.insert("os".to_string(), optional(&info.os));
+    m.insert("arch".to_string(), optional(&info.arch));
+    m.insert("shell".to_string(), optional(&info.shell));
+    m
+}
+
+// Python-style: negative indices count from the end; out of range is clamped.
+fn clamp_index(i: i64, len: usize) -> usize {
+    if i < 0 {
+        (len as i64 + i).max(0) as usize
+    } else {
+        (i as usize).min(len)
+    }
+}
+
+impl<'a> Interp<'a> {
+    fn burn(&mut self) -> Result<(), String> {
+        if self.fuel == 0 {
+            return Err("script took too long".to_string());
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    // Runs `f` one level deeper; see `MAX_STACK`.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.stack >= MAX_STACK {
+            return Err("nested too deeply".to_string());
+        }
+        self.stack += 1;
+        let res = f(self);
+        self.stack -= 1;
+        res
+    }
+
+    // Before building something that could be much bigger than the values it's built from.
+    fn reserve(&mut self, bytes: usize) -> Result<(), String> {
+        if bytes > self.memory {
+            return Err("script used too much memory".to_string());
+        }
+        self.memory -= bytes;
+        Ok(())
+    }
+
+    // Every value an expression produces is paid for, since most are new copies.
+    fn charge(&mut self, v: Value) -> Result<Value, String> {
+        let weight = v.weight(MAX_DEPTH).ok_or_else(|| "values nested too deeply".to_string())?;
+        self.reserve(weight)?;
+        Ok(v)
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let f = match self.script.functions.get(name) {
+            Some(f) => f,
+            None => return self.builtin(name, args),
+        };
+
+        if args.len() != f.params.len() {
+            return Err(format!("`{}` takes {} argument(s), not {}", name, f.params.len(), args.len()));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err("too much recursion".to_string());
+        }
+
+        let mut scope: Scope = f.params.iter().cloned().zip(args).collect();
+        self.depth += 1;
+        let res = self.block(&f.body, &mut scope);
+        self.depth -= 1;
+
+        Ok(match res.map_err(|e| format!("in `{}`: {}", name, e))? {
+            Flow::Return(v) => v,
+            Flow::Normal => Value::Nil,
+        })
+    }
+
+    fn builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let arity = |n: usize| if args.len() == n {
+            Ok(())
+        } else {
+            Err(format!("`{}` takes {} argument(s), not {}", name, n, args.len()))
+        };
+
+        Ok(match name {
+            "len" => {
+                arity(1)?;
+                Value::Int(match &args[0] {
+                    Value::Str(s) => s.chars().count(),
+                    Value::List(l) => l.len(),
+                    Value::Map(m) => m.len(),
+                    v => return Err(format!("`len` of {}", v.type_name())),
+                } as i64)
+            }
+            "str" => {
+                arity(1)?;
+                Value::Str(args[0].to_string())
+            }
+            "int" => {
+                arity(1)?;
+                match &args[0] {
+                    Value::Int(i) => Value::Int(*i),
+                    Value::Str(s) => s.trim().parse().map(Value::Int).unwrap_or(Value::Nil),
+                    v => return Err(format!("`int` of {}", v.type_name())),
+                }
+            }
+            "split" => {
+                let s = expect_str(args.get(0).unwrap_or(&Value::Nil), "`split`'s first argument")?;
+                // Each piece costs a `Value` on top of its text.
+                self.reserve((s.len() + 1) * mem::size_of::<Value>())?;
+                match args.len() {
+                    1 => Value::List(s.split_whitespace().map(|w| Value::Str(w.to_string())).collect()),
+                    2 => {
+                        let sep = expect_str(&args[1], "the separator")?;
+                        if sep.is_empty() {
+                            return Err("can't split on an empty string".to_string());
+                        }
+                        Value::List(s.split(sep.as_str()).map(|w| Value::Str(w.to_string())).collect())
+                    }
+                    _ => return Err("`split` takes 1 or 2 arguments".to_string()),
+                }
+            }
+            "join" => {
+                arity(2)?;
+                let sep = expect_str(&args[1], "the separator")?;
+                match &args[0] {
+                    Value::List(items) => {
+                        self.reserve(items.len() * sep.len())?;
+                        Value::Str(items.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(&sep))
+                    }
+                    v => return Err(format!("`join` of {}", v.type_name())),
+                }
+            }
+            "trim" => {
+                arity(1)?;
+                Value::Str(expect_str(&args[0], "`trim`'s argument")?.trim().to_string())
+            }
+            "starts_with" | "ends_with" => {
+                arity(2)?;
+                let s = expect_str(&args[0], "the first argument")?;
+                let p = expect_str(&args[1], "the second argument")?;
+                Value::Bool(if name == "starts_with" { s.starts_with(&p) } else { s.ends_with(&p) })
+            }
+            "contains" => {
+                arity(2)?;
+                Value::Bool(match &args[0] {
+                    Value::Str(s) => s.contains(&expect_str(&args[1], "the second argument")?),
+                    Value::List(l) => l.contains(&args[1]),
+                    Value::Map(m) => m.contains_key(&expect_str(&args[1], "the key")?),
+                    v => return Err(format!("`contains` on {}", v.type_name())),
+                })
+            }
+            "replace" => {
+                arity(3)?;
+                let s = expect_str(&args[0], "the first argument")?;
+                let from = expect_str(&args[1], "the second argument")?;
+                let to = expect_str(&args[2], "the third argument")?;
+                let count = if from.is_empty() { s.chars().count() + 1 } else { s.matches(from.as_str()).count() };
+                self.reserve(count * to.len())?;
+                Value::Str(s.replace(&from, &to))
+            }
+            "slice" => {
+                if args.len() != 2 && args.len() != 3 {
+                    return Err("`slice` takes 2 or 3 arguments".to_string());
+                }
+                let start = expect_int(&args[1], "the start")?;
+                match &args[0] {
+                    Value::List(l) => {
+                        let end = match args.get(2) { Some(e) => expect_int(e, "the end")?, None => l.len() as i64 };
+                        let (start, end) = (clamp_index(start, l.len()), clamp_index(end, l.len()));
+                        Value::List(if start < end { l[start..end].to_vec() } else { Vec::new() })
+                    }
+                    Value::Str(s) => {
+                        let chars: Vec<char> = s.chars().collect();
+                        let end = match args.get(2) { Some(e) => expect_int(e, "the end")?, None => chars.len() as i64 };
+                        let (start, end) = (clamp_index(start, chars.len()), clamp_index(end, chars.len()));
+                        Value::Str(if start < end { chars[start..end].iter().collect() } else { String::new() })
+                    }
+                    v => return Err(format!("`slice` of {}", v.type_name())),
+                }
+            }
+            "remote" => {
+                arity(0)?;
+                match self.env.remote {
+                    Some((info, depth)) => Value::from_remote(info, depth),
+                    None => Value::Nil,
+                }
+            }
+            "remotes" => {
+                arity(0)?;
+                Value::List(self.env.stack.iter().enumerate().map(|(i, (info, latency))| {
+                    let mut m = remote_map(info, i + 1);
+                    let ms = latency.map_or(Value::Nil, |l| Value::Int(l.as_millis() as i64));
+                    m.insert("latency_ms".to_string(), ms);
+                    Value::Map(m)
+                }).collect())
+            }
+            "has_command" => {
+                arity(1)?;
+                let name = expect_str(&args[0], "the command")?;
+                match self.env.commands {
+                    Some(commands) => Value::Bool(commands.contains(&name)),
+                    // Don't know (yet)
+                    None => Value::Nil,
+                }
+            }
+            "is_alias" => {
+                arity(1)?;
+                Value::Bool(self.env.prefs.is_alias(&expect_str(&args[0], "the word")?))
+            }
+            "color" => {
+                arity(2)?;
+                let color = expect_str(&args[0], "the colour")?;
+                let text = expect_str(&args[1], "the text")?;
+                let code = render::color_code(&color).ok_or_else(|| format!("unknown colour `{}`", color))?;
+                Value::Str(format!("{}{}{}", code, text, render::color_code("default").unwrap()))
+            }
+            "print" => {
+                let text = args.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" ");
+                eprintln!("{}", text);
+                Value::Nil
+            }
+            "title" => {
+                arity(1)?;
+                let title = expect_str(&args[0], "the title")?;
+                // Don't let it end the escape sequence early.
+                let title: String = title.chars().filter(|c| !c.is_control()).collect();
+                let mut out = io::stdout();
+                write!(out, "\x1b]0;{}\x07", title).and_then(|_| out.flush()).map_err(|e| e.to_string())?;
+                Value::Nil
+            }
+            "log" => {
+                arity(1)?;
+                let line = expect_str(&args[0], "the line")?;
+                append_log(&line).map_err(|e| format!("couldn't write to the log: {}", e))?;
+                Value::Nil
+            }
+            _ => return Err(format!("no function called `{}`", name)),
+        })
+    }
+
+    fn block(&mut self, stmts: &[Stmt], scope: &mut Scope) -> Result<Flow, String> {
+        self.nested(|interp| interp.run_block(stmts, scope))
+    }
+
+    fn run_block(&mut self, stmts: &[Stmt], scope: &mut Scope) -> Result<Flow, String> {
+        for stmt in stmts {
+            self.burn()?;
+            match stmt {
+                Stmt::Let(name, e) => {
+                    let v = self.eval(e, scope)?;
+                    scope.insert(name.clone(), v);
+                }
+                Stmt::Assign(name, e) => {
+                    let v = self.eval(e, scope)?;
+                    match scope.get_mut(name) {
+                        Some(slot) => *slot = v,
+                        None => return Err(format!("`{}` isn't defined (use `let`)", name)),
+                    }
+                }
+                Stmt::If(cond, then, otherwise) => {
+                    let branch = if self.eval(cond, scope)?.truthy() { then } else { otherwise };
+                    if let Flow::Return(v) = self.block(branch, scope)? {
+                        return Ok(Flow::Return(v));
+                    }
+                }
+                Stmt::For(name, list, body) => {
+                    let items = match self.eval(list, scope)? {
+                        Value::List(items) => items,
+                        Value::Str(s) => {
+                            self.reserve(s.len() * mem::size_of::<Value>())?;
+                            s.chars().map(|c| Value::Str(c.to_string())).collect()
+                        }
+                        v => return Err(format!("can't loop over {}", v.type_name())),
+                    };
+                    for item in items {
+                        scope.insert(name.clone(), item);
+                        if let Flow::Return(v) = self.block(body, scope)? {
+                            return Ok(Flow::Return(v));
+                        }
+                    }
+                }
+                Stmt::Return(e) => {
+                    let v = match e {
+                        Some(e) => self.eval(e, scope)?,
+                        None => Value::Nil,
+                    };
+                    return Ok(Flow::Return(v));
+                }
+                Stmt::Expr(e) => {
+                    self.eval(e, scope)?;
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn eval(&mut self, e: &Expr, scope: &mut Scope) -> Result<Value, String> {
+        self.nested(|interp| interp.run_expr(e, scope))
+    }
+
+    fn run_expr(&mut self, e: &Expr, scope: &mut Scope) -> Result<Value, String> {
+        self.burn()?;
+        let v = match e {
+            Expr::Lit(v) => v.clone(),
+            Expr::List(items) => {
+                let mut res = Vec::new();
+                for item in items {
+                    res.push(self.eval(item, scope)?);
+                }
+                Value::List(res)
+            }
+            Expr::Var(name) => match scope.get(name) {
+                Some(v) => v.clone(),
+                None => return Err(format!("`{}` isn't defined", name)),
+            },
+            Expr::Call(name, args) => {
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push(self.eval(arg, scope)?);
+                }
+                self.call(name, values)?
+            }
+            Expr::Index(target, index) => {
+                let target = self.eval(target, scope)?;
+                let index = self.eval(index, scope)?;
+                match (&target, &index) {
+                    (Value::List(l), Value::Int(i)) => {
+                        let i = if *i < 0 { l.len() as i64 + i } else { *i };
+                        if i < 0 { Value::Nil } else { l.get(i as usize).cloned().unwrap_or(Value::Nil) }
+                    }
+                    (Value::Map(m), Value::Str(k)) => m.get(k).cloned().unwrap_or(Value::Nil),
+                    _ => return Err(format!("can't index {} with {}", target.type_name(), index.type_name())),
+                }
+            }
+            Expr::Field(target, field) => match self.eval(target, scope)? {
+                Value::Map(m) => m.get(field).cloned().unwrap_or(Value::Nil),
+                v => return Err(format!("{} has no field `{}`", v.type_name(), field)),
+            },
+            Expr::Not(e) => Value::Bool(!self.eval(e, scope)?.truthy()),
+            Expr::Neg(e) => Value::Int(expect_int(&self.eval(e, scope)?, "`-`'s operand")?.wrapping_neg()),
+            Expr::And(a, b) => {
+                let a = self.eval(a, scope)?;
+                if a.truthy() { self.eval(b, scope)? } else { a }
+            }
+            Expr::Or(a, b) => {
+                let a = self.eval(a, scope)?;
+                if a.truthy() { a } else { self.eval(b, scope)? }
+            }
+            Expr::Binary(op, a, b) => {
+                let a = self.eval(a, scope)?;
+                let b = self.eval(b, scope)?;
+                binary(op, a, b)?
+            }
+        };
+        self.charge(v)
+    }
+}
+
+fn binary(op: &str, a: Value, b: Value) -> Result<Value, String> {
+    Ok(match (op, a, b) {
+        ("==", a, b) => Value::Bool(a == b),
+        ("!=", a, b) => Value::Bool(a != b),
+        ("+", Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_add(b)),
+        ("+", Value::Str(a), Value::Str(b)) => Value::Str(a + &b),
+        ("+", Value::List(mut a), Value::List(b)) => {
+            a.extend(b);
+            Value::List(a)
+        }
+        ("-", Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_sub(b)),
+        ("*", Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_mul(b)),
+        ("/", Value::Int(_), Value::Int(0)) |
+        ("%", Value::Int(_), Value::Int(0)) => return Err("division by zero".to_string()),
+        ("/", Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_div(b)),
+        ("%", Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_rem(b)),
+        ("<", Value::Int(a), Value::Int(b)) => Value::Bool(a < b),
+        (">", Value::Int(a), Value::Int(b)) => Value::Bool(a > b),
+        ("<=", Value::Int(a), Value::Int(b)) => Value::Bool(a <= b),
+        (">=", Value::Int(a), Value::Int(b)) => Value::Bool(a >= b),
+        ("<", Value::Str(a), Value::Str(b)) => Value::Bool(a < b),
+        (">", Value::Str(a), Value::Str(b)) => Value::Bool(a > b),
+        ("<=", Value::Str(a), Value::Str(b)) => Value::Bool(a <= b),
+        (">=", Value::Str(a), Value::Str(b)) => Value::Bool(a >= b),
+        (op, a, b) => return Err(format!("can't `{}` {} and {}", op, a.type_name(), b.type_name())),
+    })
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, ScriptError> {
+        let mut parser = Parser {
+            toks: lex(text)?,
+            pos: 0,
+            depth: 0,
+        };
+
+        let mut functions = HashMap::new();
+        while parser.peek().is_some() {
+            let line = parser.line();
+            let (name, f) = parser.function()?;
+            if functions.insert(name.clone(), f).is_some() {
+                return Err(ScriptError { line, message: format!("`{}` is defined twice", name) });
+            }
+        }
+
+        Ok(Script { functions })
+    }
+
+    pub fn defines(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Call the script's function `name`, if it has one.
+    pub fn call(&self, name: &str, args: Vec<Value>, env: &Env) -> Result<Option<Value>, Error> {
+        if !self.defines(name) {
+            return Ok(None);
+        }
+
+        let mut interp = Interp {
+            script: self,
+            env,
+            fuel: FUEL,
+            memory: MEMORY,
+            depth: 0,
+            stack: 0,
+        };
+        interp.call(name, args)
+            .map(Some)
+            .map_err(|message| ScriptError { line: 0, message }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(text: &str, name: &str, args: Vec<Value>) -> Result<Option<Value>, Error> {
+        let prefs = Prefs::default();
+        let info = RemoteInfo {
+            hostname: "box".to_string(),
+            username: "me".to_string(),
+            working_dir: "/home/me".to_string(),
+            ..RemoteInfo::default()
+        };
+        let env = Env {
+            prefs: &prefs,
+            remote: Some((&info, 2)),
+            commands: None,
+            stack: &[],
+        };
+        Script::parse(text).unwrap().call(name, args, &env)
+    }
+
+    #[test]
+    fn evaluates() {
+        let script = r#"
+            fn prompt() {
+                let r = remote();
+                let p = r.hostname + ":" + r.working_dir;
+                if r.depth > 1 && !has_command("git") {
+                    p = "(" + str(r.depth) + ") " + p;
+                } else {
+                    p = "?";
+                }
+                return p + "$ ";
+            }
+
+            fn expand(words) {
+                if words[0] == "up" {
+                    let n = int(words[1]) || 1;
+                    let parts = [];
+                    for i in split(slice("........", 0, n)) {
+                        parts = parts + [".."];
+                    }
+                    return ["cd", join(parts, "/")];
+                }
+                if words[0] == "fact" { return [str(fact(int(words[1])))]; }
+            }
+
+            fn fact(n) {
+                if n <= 1 { return 1; }
+                return n * fact(n - 1);
+            }
+        "#;
+
+        assert_eq!(run(script, "prompt", vec![]).unwrap(), Some(Value::Str("(2) box:/home/me$ ".to_string())));
+        assert_eq!(run(script, "expand", vec![Value::from_words(&["fact".to_string(), "5".to_string()])]).unwrap(),
+            Some(Value::from_words(&["120".to_string()])));
+        assert_eq!(run(script, "expand", vec![Value::from_words(&["ls".to_string()])]).unwrap(), Some(Value::Nil));
+        assert_eq!(run(script, "missing", vec![]).unwrap(), None);
+    }
+
+    #[test]
+    fn sandboxed() {
+        assert!(run("fn f() { for x in [1] { f(); } }", "f", vec![]).unwrap_err().to_string().contains("recursion"));
+
+        let spin = "fn f(n) { if n > 0 { return 0; } let l = [1, 1, 1, 1, 1, 1, 1, 1]; \
+                    for a in l { for b in l { for c in l { for d in l { for e in l { f(1); } } } } } }";
+        assert!(run(spin, "f", vec![Value::Int(0)]).unwrap_err().to_string().contains("too long"));
+
+        assert!(run("fn f() { return open(\"/etc/passwd\"); }", "f", vec![]).is_err());
+        assert_eq!(Script::parse("fn f() {\n  let x = ;\n}").unwrap_err().line, 2);
+        assert!(Script::parse("fn f() {} fn f() {}").is_err());
+        assert_eq!(run("fn f(n) { return -n; }", "f", vec![Value::Int(i64::min_value())]).unwrap(),
+            Some(Value::Int(i64::min_value())));
+    }
+
+    #[test]
+    fn bounded_memory() {
+        let x = || vec![Value::Str("x".to_string())];
+        for grow in &["s + s", "replace(s, \"\", s)", "join([s, s], s)"] {
+            let script = format!("fn f(s) {{ return f({}); }}", grow);
+            assert!(run(&script, "f", x()).unwrap_err().to_string().contains("memory"), "{}", grow);
+        }
+        let list = vec![Value::from_words(&["x".to_string()])];
+        assert!(run("fn f(l) { return f(l + l); }", "f", list).unwrap_err().to_string().contains("memory"));
+
+        let nest = format!("fn f() {{ let l = nil; for c in \"{}\" {{ l = [l]; }} }}", "x".repeat(100));
+        assert!(run(&nest, "f", vec![]).unwrap_err().to_string().contains("nested"));
+    }
+
+    #[test]
+    fn bounded_nesting() {
+        for (open, close) in &[("(", ")"), ("!", ""), ("[", "]"), ("1 + (", ")")] {
+            let script = format!("fn f() {{ return {}1{}; }}", open.repeat(10_000), close.repeat(10_000));
+            assert!(Script::parse(&script).unwrap_err().message.contains("nested"), "{}", open);
+        }
+        let chain = format!("fn f() {{ return 1{}; }}", " + 1".repeat(10_000));
+        assert!(Script::parse(&chain).is_err());
+        let blocks = format!("fn f() {{ {}{} }}", "if true { ".repeat(10_000), "}".repeat(10_000));
+        assert!(Script::parse(&blocks).is_err());
+
+        // As deep as the parser allows, as deep as recursion allows, without overflowing even a
+        // test thread's stack.
+        let deep = format!("fn f(n) {{ if n <= 0 {{ return 0; }} return {}f(n - 1); }}", "-".repeat(MAX_NESTING - 4));
+        assert!(run(&deep, "f", vec![Value::Int(MAX_DEPTH as i64 - 1)]).unwrap_err().to_string().contains("nested"));
+        assert_eq!(run(&deep, "f", vec![Value::Int(4)]).unwrap(), Some(Value::Int(0)));
+    }
+
+    #[test]
+    fn sees_the_stack() {
+        let prefs = Prefs::default();
+        let local = RemoteInfo { hostname: "laptop".to_string(), ..RemoteInfo::default() };
+        let server = RemoteInfo { hostname: "server".to_string(), ..RemoteInfo::default() };
+        let stack = vec![(local.clone(), None), (server.clone(), Some(Duration::from_millis(40)))];
+        let env = Env {
+            prefs: &prefs,
+            remote: Some((&server, 2)),
+            commands: None,
+            stack: &stack,
+        };
+        let script = Script::parse("fn f() { let r = remotes(); return [len(r), r[0].latency_ms, r[1].hostname, r[1].latency_ms]; }").unwrap();
+        assert_eq!(script.call("f", vec![], &env).unwrap(), Some(Value::List(vec![
+            Value::Int(2), Value::Nil, Value::Str("server".to_string()), Value::Int(40),
+        ])));
+    }
+}
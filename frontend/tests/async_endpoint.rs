@@ -0,0 +1,88 @@
+//! `protocol::AsyncEndpoint` against a real backend, as another frontend might use it.
+
+extern crate executable_path;
+extern crate failure;
+extern crate protocol;
+extern crate serde_json;
+
+mod common;
+
+use std::collections::HashMap;
+use std::thread;
+
+use executable_path::executable_path;
+use protocol::{block_on, AsyncEndpoint, Command, WritePipes};
+
+use common::{spawn_backend, Running};
+
+#[test]
+fn awaits_answers() {
+    let (mut child, trans, output) = spawn_backend(false);
+    let endpoint = AsyncEndpoint::new(trans);
+    let receiver = endpoint.clone();
+    let receiving = thread::spawn(move || receiver.receive_from(output));
+
+    block_on(async {
+        let info = endpoint.root_ready().await.unwrap();
+        assert!(info.capabilities.iter().any(|c| c == "compression"));
+
+        let root = endpoint.lock().root();
+        let backend = executable_path("backend").to_str().unwrap().to_string();
+        let (nested, ready) = endpoint.remote(root, Command::Unknown(backend, vec![])).unwrap();
+        ready.await.unwrap();
+
+        let dir = env!("CARGO_MANIFEST_DIR").to_string();
+        let listing = endpoint.list_directory(nested, dir.clone()).unwrap().await.unwrap();
+        assert!(listing.contains(&"Cargo.toml".to_string()), "{:?}", listing);
+        assert!(endpoint.list_directory(nested, format!("{}/nonexistent", dir)).unwrap().await.is_err());
+
+        let (stdin, _) = endpoint.pipe();
+        let (stdout_read, stdout) = endpoint.pipe();
+        let (_, stderr) = endpoint.pipe();
+        let echo = Command::Unknown("echo".to_string(), vec!["hi".to_string()]);
+        let (_, exit) = endpoint.command(nested, echo, HashMap::new(), WritePipes { stdin, stdout, stderr }).unwrap();
+        let mut output = endpoint.read_pipe(nested, stdout_read).unwrap();
+        let mut text = Vec::new();
+        while let Some(data) = output.recv().await {
+            text.extend(data.unwrap());
+        }
+        assert_eq!(text, b"hi\n");
+        assert_eq!(exit.await.unwrap().exit_code, 0);
+    });
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    receiving.join().unwrap().unwrap();
+}
+
+#[test]
+fn backend_exits_mid_command() {
+    let (child, trans, output) = spawn_backend(false);
+    let mut child = Running(child);
+    let endpoint = AsyncEndpoint::new(trans);
+    let receiver = endpoint.clone();
+    let receiving = thread::spawn(move || receiver.receive_from(output));
+
+    block_on(async {
+        endpoint.root_ready().await.unwrap();
+        let root = endpoint.lock().root();
+
+        let (stdin, _) = endpoint.pipe();
+        let (stdout_read, stdout) = endpoint.pipe();
+        let (_, stderr) = endpoint.pipe();
+        let talk = Command::Unknown("sh".to_string(), vec!["-c".to_string(), "echo started; exec sleep 5".to_string()]);
+        let (_, exit) = endpoint.command(root, talk, HashMap::new(), WritePipes { stdin, stdout, stderr }).unwrap();
+        let mut output = endpoint.read_pipe(root, stdout_read).unwrap();
+        assert_eq!(output.recv().await.unwrap().unwrap(), b"started\n");
+
+        child.kill().unwrap();
+        assert!(exit.await.is_err());
+        assert!(output.recv().await.unwrap().is_err());
+        assert!(output.recv().await.is_none());
+    });
+
+    receiving.join().unwrap().unwrap();
+    // Nothing's listening any more, so this fails rather than waiting forever.
+    let root = endpoint.lock().root();
+    assert!(endpoint.ping(root).is_err());
+}
@@ -0,0 +1,57 @@
+//! Calls to a backend that has stopped answering, which time out or are cancelled, and the
+//! answers that turn up once it carries on.
+
+extern crate executable_path;
+extern crate failure;
+extern crate protocol;
+extern crate serde_json;
+
+mod common;
+
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use protocol::{Answer, CallId, ErrorKind, Query};
+
+use common::TestBackend;
+
+type Answers = Arc<Mutex<Vec<(usize, Result<Answer, ErrorKind>)>>>;
+
+fn ping(backend: &mut TestBackend, answers: &Answers, n: usize, timeout: Option<Duration>) -> CallId {
+    let root = backend.endpoint.root();
+    let answers = answers.clone();
+    backend.endpoint.call(root, Query::Ping, timeout, Box::new(move |_, answer| {
+        answers.lock().unwrap().push((n, answer.map_err(|e| ErrorKind::of(&e))));
+        Ok(())
+    })).unwrap()
+}
+
+fn signal(backend: &TestBackend, signal: &str) {
+    let status = process::Command::new("kill").arg(signal).arg(backend.child.id().to_string()).status().unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn unanswered_calls() {
+    let mut backend = TestBackend::start(false);
+    let answers = Answers::default();
+
+    signal(&backend, "-STOP");
+    ping(&mut backend, &answers, 1, Some(Duration::from_millis(200)));
+    let cancelled = ping(&mut backend, &answers, 2, None);
+    assert!(backend.endpoint.next_deadline().is_some());
+
+    thread::sleep(Duration::from_millis(300));
+    backend.endpoint.expire_calls(Instant::now()).unwrap();
+    backend.endpoint.cancel_call(cancelled);
+    assert_eq!(backend.endpoint.pending_calls(), 0);
+    assert_eq!(*answers.lock().unwrap(), vec![(1, Err(ErrorKind::TimedOut))]);
+
+    // The late pongs come first, and are dropped.
+    signal(&backend, "-CONT");
+    ping(&mut backend, &answers, 3, Some(Duration::from_secs(10)));
+    backend.receive_until(|_| answers.lock().unwrap().len() == 2);
+    assert_eq!(answers.lock().unwrap()[1], (3, Ok(Answer::Pong)));
+}
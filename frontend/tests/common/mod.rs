@@ -0,0 +1,217 @@
+//! Drives a backend directly through `protocol::Endpoint`, for tests that need more control
+//! than the frontend's command line gives.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::ops::{Deref, DerefMut};
+use std::process::{self, Child, ChildStdin, ChildStdout, Stdio};
+
+use executable_path::executable_path;
+use failure::Error;
+use protocol::{
+    Command,
+    Endpoint,
+    EndpointHandler,
+    ErrorKind,
+    FailedRequest,
+    GenericPipe,
+    Hello,
+    PipeMessage,
+    ProcessId,
+    RemoteId,
+    RemoteInfo,
+    ResourceUsage,
+    Response,
+    Transport,
+    WritePipes,
+    PLAIN_DATA_VAR,
+};
+
+pub const WINDOW: u64 = 256 * 1024;
+
+pub struct ChildTransport(ChildStdin);
+
+impl Transport for ChildTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.0.write_all(data)?;
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// Counts what comes back, and keeps the pipes being read topped up with credit.
+#[derive(Default)]
+pub struct Counter {
+    pub ready: usize,
+    /// The remote each pipe is read from, and how many bytes it's sent.
+    pub reading: HashMap<GenericPipe, (RemoteId, u64)>,
+    pub closed: usize,
+    pub done: usize,
+    pub exit_codes: HashMap<ProcessId, i64>,
+    /// Errors the backend sent, which `receive_until` only lets through if they're waited for.
+    pub errors: Vec<(FailedRequest, ErrorKind, String)>,
+}
+
+impl<T: Transport> EndpointHandler<T> for Counter {
+    fn remote_ready(endpoint: &mut Endpoint<T, Self>, _id: RemoteId, _remote_info: RemoteInfo) -> Result<(), Error> {
+        endpoint.handler.ready += 1;
+        Ok(())
+    }
+
+    fn command_done(endpoint: &mut Endpoint<T, Self>, id: ProcessId, exit_code: i64, _usage: Option<ResourceUsage>) -> Result<(), Error> {
+        endpoint.handler.exit_codes.insert(id, exit_code);
+        endpoint.handler.done += 1;
+        Ok(())
+    }
+
+    fn edit_request(_endpoint: &mut Endpoint<T, Self>, _edit_id: usize, _command_id: ProcessId, _name: String, _data: Vec<u8>) -> Result<(), Error> {
+        panic!("unexpected edit")
+    }
+
+    fn pipe(endpoint: &mut Endpoint<T, Self>, id: GenericPipe, msg: PipeMessage) -> Result<(), Error> {
+        match msg {
+            PipeMessage::Data { data, .. } => {
+                let (remote, received) = endpoint.handler.reading.get_mut(&id).expect("data for a pipe not being read");
+                let before = *received;
+                *received += data.len() as u64;
+                // Top up the credit every half window, as the frontend does.
+                if *received / (WINDOW / 2) != before / (WINDOW / 2) {
+                    let (remote, read_up_to) = (*remote, *received + WINDOW);
+                    endpoint.pipe_read(remote, id.to_read(), read_up_to)?;
+                }
+            }
+            PipeMessage::Closed { .. } => endpoint.handler.closed += 1,
+            msg => panic!("unexpected {:?}", msg),
+        }
+        Ok(())
+    }
+
+    fn error(endpoint: &mut Endpoint<T, Self>, _id: RemoteId, request: FailedRequest, kind: ErrorKind, message: String) -> Result<(), Error> {
+        endpoint.handler.errors.push((request, kind, message));
+        Ok(())
+    }
+}
+
+/// A backend process, killed once it's dropped.
+pub struct Running(pub Child);
+
+impl Deref for Running {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.0
+    }
+}
+
+impl DerefMut for Running {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.0
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub struct TestBackend {
+    pub child: Running,
+    pub output: BufReader<ChildStdout>,
+    pub endpoint: Endpoint<ChildTransport, Counter>,
+}
+
+/// Starts a backend and says hello to it.  `plain` keeps pipe data as JSON arrays.
+pub fn spawn_backend(plain: bool) -> (Child, ChildTransport, BufReader<ChildStdout>) {
+    let mut command = process::Command::new(executable_path("backend"));
+    command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null());
+    if plain {
+        command.env(PLAIN_DATA_VAR, "1");
+    } else {
+        command.env_remove(PLAIN_DATA_VAR);
+    }
+    let mut child = command.spawn().unwrap();
+
+    let mut output = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    output.read_line(&mut line).unwrap();
+    let theirs = Hello::expect(&line, "the backend").unwrap();
+    let mut ours = Hello::ours("test");
+    if plain {
+        ours.capabilities.clear();
+    }
+    assert_eq!(theirs.supports(protocol::capability::BASE64_DATA), !plain);
+
+    let mut trans = ChildTransport(child.stdin.take().unwrap());
+    trans.send(ours.to_line().as_bytes()).unwrap();
+    (child, trans, output)
+}
+
+impl TestBackend {
+    /// Starts a backend and waits for it to be ready.
+    pub fn start(plain: bool) -> TestBackend {
+        let (child, trans, output) = spawn_backend(plain);
+        let endpoint = Endpoint::new(trans, Counter::default());
+        let mut backend = TestBackend { child: Running(child), output, endpoint };
+        backend.receive_until(|c| c.ready == 1);
+        backend
+    }
+
+    /// Handles responses until `done` says to stop.  Panics on anything that isn't a whole
+    /// response, and on an error `done` wasn't waiting for.
+    pub fn receive_until(&mut self, done: impl Fn(&Counter) -> bool) {
+        assert!(self.receive_while_running(&done), "backend exited");
+    }
+
+    /// Like `receive_until`, but returns false if the backend exits first.
+    pub fn receive_while_running(&mut self, done: impl Fn(&Counter) -> bool) -> bool {
+        let mut line = String::new();
+        while !done(&self.endpoint.handler) {
+            if let Some((request, _, message)) = self.endpoint.handler.errors.first() {
+                panic!("{:?} failed: {}", request, message);
+            }
+            line.clear();
+            if self.output.read_line(&mut line).unwrap() == 0 {
+                return false;
+            }
+            let response: Response = serde_json::from_str(&line)
+                .unwrap_or_else(|e| panic!("bad response {:?}: {}", line, e));
+            self.endpoint.receive(response).unwrap();
+        }
+        true
+    }
+
+    /// Starts another backend on `parent`, and waits for it to be ready.
+    pub fn nest(&mut self, parent: RemoteId) -> RemoteId {
+        let backend = executable_path("backend").to_str().unwrap().to_string();
+        let ready = self.endpoint.handler.ready;
+        let remote = self.endpoint.remote(parent, Command::Unknown(backend, vec![])).unwrap();
+        self.receive_until(|c| c.ready == ready + 1);
+        remote
+    }
+
+    /// Starts a command and reads its stdout, without waiting for it.
+    pub fn spawn(&mut self, remote: RemoteId, command: Command) -> (ProcessId, GenericPipe) {
+        let (stdin, _) = self.endpoint.pipe();
+        let (stdout_read, stdout) = self.endpoint.pipe();
+        let (_, stderr) = self.endpoint.pipe();
+        let id = self.endpoint.command(remote, command, HashMap::new(), WritePipes { stdin, stdout, stderr }).unwrap();
+        self.endpoint.pipe_begin_read(remote, stdout_read).unwrap();
+        self.endpoint.pipe_read(remote, stdout_read, WINDOW).unwrap();
+        self.endpoint.handler.reading.insert(stdout_read.to_generic(), (remote, 0));
+        (id, stdout_read.to_generic())
+    }
+
+    /// Runs a command to completion, checking it succeeded, and returns how many bytes it
+    /// wrote to stdout.
+    pub fn run(&mut self, remote: RemoteId, command: Command) -> u64 {
+        let (done, closed) = (self.endpoint.handler.done, self.endpoint.handler.closed);
+        let (id, pipe) = self.spawn(remote, command);
+        self.receive_until(|c| c.done == done + 1 && c.closed == closed + 1);
+        assert_eq!(self.endpoint.handler.exit_codes[&id], 0);
+        self.endpoint.handler.reading[&pipe].1
+    }
+}
@@ -0,0 +1,43 @@
+//! Lots of commands at once on one backend, which shouldn't need a thread apiece.
+
+extern crate executable_path;
+extern crate failure;
+extern crate protocol;
+extern crate serde_json;
+
+mod common;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use protocol::Command;
+
+use common::TestBackend;
+
+const COMMANDS: usize = 300;
+
+#[test]
+fn many_concurrent_commands() {
+    let mut backend = TestBackend::start(false);
+    let root = backend.endpoint.root();
+
+    // Each waits for the others to start before saying anything.
+    let command = || Command::Unknown("sh".to_string(), vec!["-c".to_string(), "sleep 2; seq 1000".to_string()]);
+    let pipes: Vec<_> = (0..COMMANDS).map(|_| backend.spawn(root, command()).1).collect();
+
+    if cfg!(target_os = "linux") {
+        // Long enough for them all to start, but not to finish.
+        thread::sleep(Duration::from_millis(500));
+        let tasks = format!("/proc/{}/task", backend.child.id());
+        let threads = fs::read_dir(tasks).unwrap().count();
+        assert!(threads < 10, "the backend has {} threads", threads);
+    }
+
+    backend.receive_until(|c| c.done == COMMANDS && c.closed == COMMANDS);
+    let expected: u64 = (1..=1000).map(|i: u64| i.to_string().len() as u64 + 1).sum();
+    for pipe in &pipes {
+        assert_eq!(backend.endpoint.handler.reading[pipe].1, expected);
+    }
+    assert!(backend.endpoint.handler.exit_codes.values().all(|&code| code == 0));
+}
@@ -0,0 +1,57 @@
+//! Commands that fail, files that can't be opened and commands cancelled while they're still
+//! talking, none of which should stop the backend running the next command.
+
+extern crate executable_path;
+extern crate failure;
+extern crate protocol;
+extern crate serde_json;
+
+mod common;
+
+use protocol::{Command, ErrorKind, FailedRequest};
+
+use common::TestBackend;
+
+fn echo() -> Command {
+    Command::Unknown("echo".to_string(), vec!["hi".to_string()])
+}
+
+#[test]
+fn failures_are_reported() {
+    let mut backend = TestBackend::start(false);
+    let root = backend.endpoint.root();
+
+    let (id, _) = backend.spawn(root, Command::Unknown("/nonexistent/program".to_string(), vec![]));
+    backend.receive_until(|c| c.done == 1 && c.closed == 1);
+    assert_eq!(backend.endpoint.handler.exit_codes[&id], 127);
+
+    let pipe = backend.endpoint.open_input_file(root, "/nonexistent/file".to_string()).unwrap();
+    backend.endpoint.pipe_begin_read(root, pipe).unwrap();
+    backend.receive_until(|c| !c.errors.is_empty());
+    let (request, _, message) = backend.endpoint.handler.errors.remove(0);
+    assert_eq!(request, FailedRequest::Pipe(pipe.to_generic()));
+    assert!(message.starts_with("/nonexistent/file: "), "{}", message);
+
+    let (pipe, _) = backend.endpoint.pipe();
+    backend.endpoint.pipe_read(root, pipe, 10).unwrap();
+    backend.receive_until(|c| !c.errors.is_empty());
+    let (request, kind, _) = backend.endpoint.handler.errors.remove(0);
+    assert_eq!(request, FailedRequest::Pipe(pipe.to_generic()));
+    assert_eq!(kind, ErrorKind::InvalidRequest);
+
+    assert_eq!(backend.run(root, echo()), 3);
+}
+
+#[test]
+fn cancelled_while_writing() {
+    let mut backend = TestBackend::start(false);
+    let root = backend.endpoint.root();
+
+    let (id, pipe) = backend.spawn(root, Command::Unknown("yes".to_string(), vec![]));
+    backend.receive_until(|c| c.reading[&pipe].1 > 1 << 20);
+    backend.endpoint.close_process(id).unwrap();
+    backend.receive_until(|c| c.done == 1 && c.closed == 1);
+    assert_ne!(backend.endpoint.handler.exit_codes[&id], 0);
+
+    assert_eq!(backend.run(root, echo()), 3);
+}
@@ -0,0 +1,65 @@
+//! Pings through a nested backend, and what happens when it dies underneath us.
+
+extern crate executable_path;
+extern crate failure;
+extern crate protocol;
+extern crate serde_json;
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use executable_path::executable_path;
+use protocol::{block_on, AsyncEndpoint, Command, Event, WritePipes};
+
+use common::spawn_backend;
+
+#[test]
+fn lost_remote() {
+    let (mut child, trans, output) = spawn_backend(false);
+    let endpoint = AsyncEndpoint::new(trans);
+    let receiver = endpoint.clone();
+    let receiving = thread::spawn(move || receiver.receive_from(output));
+
+    let (done, finished) = mpsc::channel();
+    let test = thread::spawn(move || block_on(async {
+        let info = endpoint.root_ready().await.unwrap();
+        assert!(info.supports("ping"));
+        let mut events = endpoint.events();
+
+        let root = endpoint.lock().root();
+        let backend = executable_path("backend").to_str().unwrap().to_string();
+        let (nested, ready) = endpoint.remote(root, Command::Unknown(backend, vec![])).unwrap();
+        ready.await.unwrap();
+
+        endpoint.ping(root).unwrap().await.unwrap();
+        endpoint.ping(nested).unwrap().await.unwrap();
+
+        // Commands are the backend's children, so this kills the nested backend.
+        let (stdin, _) = endpoint.pipe();
+        let (_, stdout) = endpoint.pipe();
+        let (_, stderr) = endpoint.pipe();
+        let kill = Command::Unknown("sh".to_string(), vec!["-c".to_string(), "kill $PPID".to_string()]);
+        endpoint.command(nested, kill, HashMap::new(), WritePipes { stdin, stdout, stderr }).unwrap();
+
+        match events.recv().await {
+            Some(Event::Lost(id, _)) => assert_eq!(id, nested),
+            other => panic!("expected the nested backend to be lost, got {:?}", other),
+        }
+        assert!(endpoint.ping(nested).is_err());
+        endpoint.ping(root).unwrap().await.unwrap();
+        done.send(()).unwrap();
+    }));
+
+    if let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(Duration::from_secs(20)) {
+        child.kill().unwrap();
+        panic!("still waiting to hear the nested backend was lost");
+    }
+    test.join().unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    receiving.join().unwrap().unwrap();
+}
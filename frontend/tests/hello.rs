@@ -0,0 +1,40 @@
+//! What a backend does when the frontend's hello says they can't talk.
+
+extern crate executable_path;
+extern crate protocol;
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+use executable_path::executable_path;
+use protocol::{Hello, PROTOCOL_VERSION};
+
+#[test]
+fn too_new_a_frontend() {
+    let mut child = Command::new(executable_path("backend"))
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+        .spawn().unwrap();
+    let mut output = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    output.read_line(&mut line).unwrap();
+    Hello::expect(&line, "the backend").unwrap();
+
+    let newer = Hello {
+        protocol: PROTOCOL_VERSION + 2,
+        min_protocol: PROTOCOL_VERSION + 1,
+        ..Hello::ours("9.0.0")
+    };
+    child.stdin.as_mut().unwrap().write_all(newer.to_line().as_bytes()).unwrap();
+
+    // It says why before it goes, after whatever it sent first.
+    let mut said = Vec::new();
+    loop {
+        line.clear();
+        if output.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        said.push(line.clone());
+    }
+    assert!(said.iter().any(|l| l.contains("the frontend (version 9.0.0) speaks protocol")), "{:?}", said);
+    assert!(!child.wait().unwrap().success());
+}
@@ -0,0 +1,71 @@
+//! The frontend giving up, rather than waiting forever, when its backend exits mid-command or
+//! says something it can't read.
+
+extern crate executable_path;
+extern crate protocol;
+extern crate tempfile;
+
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use executable_path::executable_path;
+use protocol::Hello;
+
+fn script(dir: &Path, name: &str, text: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, format!("#!/bin/sh\n{}", text)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// Runs `command` with heartbeats off, so only the backend's output going away can end it,
+/// returning its exit code and stderr.
+fn run_until_lost(home: &Path, backend: &str, command: &str) -> (Option<i32>, String) {
+    let prefs = home.join(".config/nak");
+    fs::create_dir_all(&prefs).unwrap();
+    fs::write(prefs.join("prefs.nak"), "set heartbeat = off\n").unwrap();
+
+    let mut child = process::Command::new(executable_path("frontend"))
+        .args(&["--command", command, "--backend", backend])
+        .env("HOME", home)
+        .stdout(Stdio::null()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("the frontend is still waiting on a backend that's gone");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    (status.code(), stderr)
+}
+
+#[test]
+fn backend_exits_mid_command() {
+    let temp = tempfile::TempDir::new().unwrap();
+    let command = script(temp.path(), "kill-backend", "kill $PPID\nexec sleep 30\n");
+    let (code, stderr) = run_until_lost(temp.path(), executable_path("backend").to_str().unwrap(), &command);
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("the backend exited"), "{}", stderr);
+}
+
+#[test]
+fn garbled_backend() {
+    let temp = tempfile::TempDir::new().unwrap();
+    let hello = Hello::ours("0.1.0").to_line();
+    let backend = script(temp.path(), "garbled", &format!("printf '%s' '{}'\nread hello\necho garbage\nexec sleep 30\n", hello));
+    let (code, stderr) = run_until_lost(temp.path(), &backend, "true");
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("bad response from the backend"), "{}", stderr);
+}
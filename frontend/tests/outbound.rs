@@ -0,0 +1,62 @@
+//! Output from several remotes at once, to check the frames relayed up from nested backends
+//! never get torn by the backend's own.
+
+extern crate executable_path;
+extern crate failure;
+extern crate protocol;
+extern crate serde_json;
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use protocol::Command;
+
+use common::TestBackend;
+
+const LINES: usize = 100_000;
+
+#[test]
+fn concurrent_local_and_nested_output() {
+    let mut backend = TestBackend::start(false);
+    let root = backend.endpoint.root();
+    let nested = backend.nest(root);
+    let deeper = backend.nest(nested);
+
+    let seq = || Command::Unknown("seq".to_string(), vec![LINES.to_string()]);
+    let expected: u64 = (1..=LINES).map(|i| i.to_string().len() as u64 + 1).sum();
+
+    let mut pipes = vec![];
+    for &remote in &[root, nested, deeper, root, nested, deeper] {
+        pipes.push(backend.spawn(remote, seq()).1);
+    }
+    // Any torn frame fails to parse in `receive_until`.
+    backend.receive_until(|c| c.done == pipes.len() && c.closed == pipes.len());
+
+    for pipe in &pipes {
+        assert_eq!(backend.endpoint.handler.reading[pipe].1, expected);
+    }
+    assert!(backend.endpoint.handler.exit_codes.values().all(|&code| code == 0));
+}
+
+#[test]
+fn frontend_hangs_up() {
+    let mut backend = TestBackend::start(false);
+    let root = backend.endpoint.root();
+    let nested = backend.nest(root);
+
+    let yes = || Command::Unknown("yes".to_string(), vec![]);
+    let (_, local) = backend.spawn(root, yes());
+    let (_, relayed) = backend.spawn(nested, yes());
+    backend.receive_until(|c| c.reading[&local].1 > 1 << 20 && c.reading[&relayed].1 > 1 << 20);
+
+    // Both directions close at once, as when an ssh hop dies.
+    let TestBackend { mut child, output, endpoint } = backend;
+    drop((output, endpoint));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while child.try_wait().unwrap().is_none() {
+        assert!(Instant::now() < deadline, "the backend is still running");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,77 @@
+//! How fast `cat` of a 100MB file comes back through two nested remotes, as JSON arrays, as
+//! base64 and deflated on every hop.  Slow, so only run on request:
+//!
+//!     cargo test --release -p frontend --test throughput -- --ignored --nocapture
+
+extern crate executable_path;
+extern crate failure;
+extern crate protocol;
+extern crate serde_json;
+extern crate tempfile;
+
+mod common;
+
+use std::fs::File;
+use std::io::Write;
+use std::time::Instant;
+
+use protocol::Command;
+
+use common::TestBackend;
+
+const FILE_SIZE: usize = 100 * 1024 * 1024;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Plain,
+    Base64,
+    Compressed,
+}
+
+/// Returns the throughput in MB/s.
+fn cat_through_nested_remotes(path: &str, mode: Mode) -> f64 {
+    let mut backend = TestBackend::start(mode == Mode::Plain);
+
+    let mut remote = backend.endpoint.root();
+    let mut remotes = vec![remote];
+    for _ in 0..2 {
+        remote = backend.nest(remote);
+        remotes.push(remote);
+    }
+    if mode == Mode::Compressed {
+        for &hop in &remotes {
+            backend.run(hop, Command::SetCompression(true));
+        }
+    }
+
+    let started = Instant::now();
+    let received = backend.run(remote, Command::Unknown("cat".to_string(), vec![path.to_string()]));
+    let elapsed = started.elapsed();
+    assert_eq!(received, FILE_SIZE as u64);
+
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    FILE_SIZE as f64 / (1024.0 * 1024.0) / secs
+}
+
+#[test]
+#[ignore]
+fn nested_cat_throughput() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big");
+    {
+        let mut file = File::create(&path).unwrap();
+        let chunk: Vec<u8> = (0..1024 * 1024).map(|i| (i * 7919 % 251) as u8).collect();
+        for _ in 0..FILE_SIZE / chunk.len() {
+            file.write_all(&chunk).unwrap();
+        }
+    }
+    let path = path.to_str().unwrap();
+
+    let plain = cat_through_nested_remotes(path, Mode::Plain);
+    let base64 = cat_through_nested_remotes(path, Mode::Base64);
+    // The file repeats every 251 bytes, so this is about as good as compression gets.
+    let compressed = cat_through_nested_remotes(path, Mode::Compressed);
+    println!("json arrays: {:.1} MB/s", plain);
+    println!("base64:      {:.1} MB/s ({:.1}x)", base64, base64 / plain);
+    println!("deflate:     {:.1} MB/s ({:.1}x)", compressed, compressed / plain);
+}
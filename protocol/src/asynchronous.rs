@@ -0,0 +1,729 @@
+//! An `Endpoint` whose requests return futures, for frontends that would rather `.await` their
+//! answers than implement `EndpointHandler` themselves.
+//!
+//! Requests go out through a plain `Transport`, or through an `AsyncTransport` by way of
+//! `outgoing`, which queues them for a future to write; `Backend` can send its responses the same
+//! way.  Responses have to be fed in from wherever the backend's output is read: `receive_from`
+//! does that from a thread of its own, or `receive` can be called from an existing event loop.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::BufRead;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use failure::Error;
+
+use crate::{
+    Backend,
+    Command,
+    Condition,
+    Endpoint,
+    EndpointHandler,
+    ErrorKind,
+    FailedRequest,
+    GenericPipe,
+    PipeMessage,
+    ProcessId,
+    ReadPipe,
+    RemoteId,
+    RemoteInfo,
+    RequestError,
+    ResourceUsage,
+    Response,
+    Transport,
+    WritePipe,
+    WritePipes,
+    Query,
+    Answer,
+};
+
+/// How far ahead of what's been received pipes may be read.
+const READ_WINDOW: u64 = 256 * 1024;
+
+struct Slot<T> {
+    value: Option<Result<T, Error>>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// The answer to a single request.  Fails if the connection goes before it comes.
+pub struct Reply<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+/// Completes a `Reply`.  Dropping it unanswered fails the `Reply`.
+struct Responder<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+fn reply<T>() -> (Responder<T>, Reply<T>) {
+    let slot = Arc::new(Mutex::new(Slot { value: None, closed: false, waker: None }));
+    (Responder { slot: slot.clone() }, Reply { slot })
+}
+
+impl<T> Responder<T> {
+    fn send(self, value: Result<T, Error>) {
+        self.slot.lock().unwrap().value = Some(value);
+    }
+}
+
+impl<T> Drop for Responder<T> {
+    fn drop(&mut self) {
+        let mut slot = self.slot.lock().unwrap();
+        slot.closed = true;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, Error>> {
+        let mut slot = self.slot.lock().unwrap();
+        if let Some(value) = slot.value.take() {
+            Poll::Ready(value)
+        } else if slot.closed {
+            Poll::Ready(Err(format_err!("the connection closed before an answer came")))
+        } else {
+            slot.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// Things that arrive over time, such as a pipe's data, in order.
+pub struct Incoming<T> {
+    state: Arc<Mutex<QueueState<T>>>,
+}
+
+/// Adds to an `Incoming`.  Dropping it ends the `Incoming`.
+struct Feed<T> {
+    state: Arc<Mutex<QueueState<T>>>,
+}
+
+fn incoming<T>() -> (Feed<T>, Incoming<T>) {
+    let state = Arc::new(Mutex::new(QueueState { items: VecDeque::new(), closed: false, waker: None }));
+    (Feed { state: state.clone() }, Incoming { state })
+}
+
+impl<T> Feed<T> {
+    fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        state.items.push_back(item);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Whether the `Incoming` has gone, so nothing pushed will be seen.
+    fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.state) == 1
+    }
+}
+
+impl<T> Drop for Feed<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Incoming<T> {
+    /// The next item, or `None` once there are no more.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { incoming: self }
+    }
+}
+
+pub struct Recv<'a, T> {
+    incoming: &'a mut Incoming<T>,
+}
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.incoming.state.lock().unwrap();
+        if let Some(item) = state.items.pop_front() {
+            Poll::Ready(Some(item))
+        } else if state.closed {
+            Poll::Ready(None)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// A `Transport` whose sends may have to wait, e.g. on a socket in an async runtime.
+pub trait AsyncTransport {
+    fn send<'a>(&'a mut self, msg: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+}
+
+/// The `Transport` in front of an `AsyncTransport`, which queues what's sent for the future
+/// `outgoing` returns with it.
+pub struct Outgoing(Feed<Vec<u8>>);
+
+impl Transport for Outgoing {
+    fn send(&mut self, msg: &[u8]) -> Result<(), Error> {
+        if self.0.is_closed() {
+            return Err(format_err!("the connection has closed"));
+        }
+        self.0.push(msg.to_vec());
+        Ok(())
+    }
+}
+
+/// Puts `trans` behind a `Transport`.  The future writes out what's sent, in order, until the
+/// `Outgoing` is dropped or a write fails, after which sends fail too.
+pub fn outgoing<A: AsyncTransport>(mut trans: A) -> (Outgoing, impl Future<Output = Result<(), Error>>) {
+    let (feed, mut queue) = incoming::<Vec<u8>>();
+    let sending = async move {
+        while let Some(msg) = queue.recv().await {
+            trans.send(&msg).await?;
+        }
+        Ok(())
+    };
+    (Outgoing(feed), sending)
+}
+
+impl Backend<Outgoing> {
+    /// A `Backend` answering over `trans`; see `outgoing`.
+    pub fn over<A: AsyncTransport>(trans: A) -> (Backend<Outgoing>, impl Future<Output = Result<(), Error>>) {
+        let (trans, sending) = outgoing(trans);
+        (Backend::new(trans), sending)
+    }
+}
+
+/// How a command finished.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exit {
+    pub exit_code: i64,
+    pub usage: Option<ResourceUsage>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EditRequest {
+    pub edit_id: usize,
+    pub command_id: ProcessId,
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// What a backend says that isn't the answer to a request.
+#[derive(Debug)]
+pub enum Event {
+    /// A command wants a file edited; answer with `Endpoint::finish_edit`.
+    Edit(EditRequest),
+    /// A request failed that nothing is waiting on.
+    Error(RemoteId, Error),
+    /// The connection to a remote that was up has gone.  What was waiting on it, or on remotes
+    /// started through it, has failed, and requests to them fail until it's ready again, but
+    /// nothing's forgotten until `Endpoint::forget_remote`.
+    Lost(RemoteId, Error),
+}
+
+struct PipeFeed {
+    remote: RemoteId,
+    received: u64,
+    granted: u64,
+    feed: Feed<Result<Vec<u8>, Error>>,
+}
+
+/// The `EndpointHandler` behind `AsyncEndpoint`, holding what's waiting on an answer.
+pub struct Pending {
+    remotes: HashMap<RemoteId, Responder<RemoteInfo>>,
+    /// With the remote each runs on.
+    commands: HashMap<ProcessId, (RemoteId, Responder<Exit>)>,
+    pipes: HashMap<GenericPipe, PipeFeed>,
+    /// Remotes whose connection has gone, and so can't be reached, nor can any started through
+    /// them.
+    lost: HashSet<RemoteId>,
+    /// Taken once `receive_from` has run out, which ends `AsyncEndpoint::events`.
+    events: Option<Feed<Event>>,
+    /// Set once `receive_from` has run out, after which nothing sent could be answered.
+    closed: bool,
+}
+
+impl Pending {
+    fn event(&self, event: Event) {
+        if let Some(ref events) = self.events {
+            events.push(event);
+        }
+    }
+
+    /// Fails everything waiting on `remote`, or on remotes started through it, which can no
+    /// longer be reached.
+    fn lose<T: Transport>(endpoint: &mut Endpoint<T, Self>, remote: RemoteId, kind: ErrorKind, message: &str) -> Result<(), Error> {
+        endpoint.handler.lost.insert(remote);
+        endpoint.fail_calls_to(remote, message)?;
+
+        let reached = |endpoint: &Endpoint<T, Self>, at: RemoteId| endpoint.is_on_path(remote, at);
+        let remotes: Vec<RemoteId> = endpoint.handler.remotes.keys()
+            .filter(|&&at| reached(endpoint, at))
+            .cloned()
+            .collect();
+        let commands: Vec<ProcessId> = endpoint.handler.commands.iter()
+            .filter(|(_, (at, _))| reached(endpoint, *at))
+            .map(|(&id, _)| id)
+            .collect();
+        let pipes: Vec<GenericPipe> = endpoint.handler.pipes.iter()
+            .filter(|(_, pipe)| reached(endpoint, pipe.remote))
+            .map(|(&id, _)| id)
+            .collect();
+
+        let pending = &mut endpoint.handler;
+        for id in remotes {
+            pending.remotes.remove(&id).unwrap().send(Err(RequestError::error(kind, message)));
+        }
+        for id in commands {
+            pending.commands.remove(&id).unwrap().1.send(Err(RequestError::error(kind, message)));
+        }
+        for id in pipes {
+            pending.pipes.remove(&id).unwrap().feed.push(Err(RequestError::error(kind, message)));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> EndpointHandler<T> for Pending {
+    fn remote_ready(endpoint: &mut Endpoint<T, Self>, id: RemoteId, remote_info: RemoteInfo) -> Result<(), Error> {
+        endpoint.handler.lost.remove(&id);
+        if let Some(answer) = endpoint.handler.remotes.remove(&id) {
+            answer.send(Ok(remote_info));
+        }
+        Ok(())
+    }
+
+    fn command_done(endpoint: &mut Endpoint<T, Self>, id: ProcessId, exit_code: i64, usage: Option<ResourceUsage>) -> Result<(), Error> {
+        if let Some((_, answer)) = endpoint.handler.commands.remove(&id) {
+            answer.send(Ok(Exit { exit_code, usage }));
+        }
+        Ok(())
+    }
+
+    fn edit_request(endpoint: &mut Endpoint<T, Self>, edit_id: usize, command_id: ProcessId, name: String, data: Vec<u8>) -> Result<(), Error> {
+        endpoint.handler.event(Event::Edit(EditRequest { edit_id, command_id, name, data }));
+        Ok(())
+    }
+
+    fn pipe(endpoint: &mut Endpoint<T, Self>, id: GenericPipe, msg: PipeMessage) -> Result<(), Error> {
+        match msg {
+            PipeMessage::Data { data, .. } => {
+                let grant = {
+                    let pipe = endpoint.handler.pipes.get_mut(&id)
+                        .ok_or_else(|| format_err!("data for {:?}, which isn't being read", id))?;
+                    let data = data.into_bytes()?;
+                    pipe.received += data.len() as u64;
+                    pipe.feed.push(Ok(data));
+                    // Granted as it arrives, like the frontend does; a reader that falls behind
+                    // buffers it here.
+                    if pipe.granted - pipe.received < READ_WINDOW / 2 {
+                        pipe.granted = pipe.received + READ_WINDOW;
+                        Some((pipe.remote, pipe.granted))
+                    } else {
+                        None
+                    }
+                };
+                if let Some((remote, read_up_to)) = grant {
+                    endpoint.pipe_read(remote, id.to_read(), read_up_to)?;
+                }
+            }
+            PipeMessage::Closed { .. } => {
+                endpoint.handler.pipes.remove(&id);
+            }
+            msg => return Err(format_err!("unexpected {:?} for {:?}", msg, id)),
+        }
+        Ok(())
+    }
+
+    fn error(endpoint: &mut Endpoint<T, Self>, id: RemoteId, request: FailedRequest, kind: ErrorKind, message: String) -> Result<(), Error> {
+        let error = RequestError::error(kind, message);
+        let pending = &mut endpoint.handler;
+        match request {
+            FailedRequest::Command(pid) if pending.commands.contains_key(&pid) => {
+                pending.commands.remove(&pid).unwrap().1.send(Err(error));
+            }
+            FailedRequest::Remote(remote) if pending.remotes.contains_key(&remote) => {
+                pending.remotes.remove(&remote).unwrap().send(Err(error));
+            }
+            FailedRequest::Remote(remote) if remote != id => {
+                Pending::lose(endpoint, remote, kind, &error.to_string())?;
+                endpoint.handler.event(Event::Lost(remote, error));
+            }
+            FailedRequest::Pipe(pipe) if pending.pipes.contains_key(&pipe) => {
+                pending.pipes.remove(&pipe).unwrap().feed.push(Err(error));
+            }
+            _ => pending.event(Event::Error(id, error)),
+        }
+        Ok(())
+    }
+}
+
+struct Shared<T: Transport> {
+    endpoint: Mutex<Endpoint<T, Pending>>,
+    root_ready: Mutex<Option<Reply<RemoteInfo>>>,
+    events: Mutex<Option<Incoming<Event>>>,
+}
+
+/// An `Endpoint` for use from async code.  Clones share the same connection.
+pub struct AsyncEndpoint<T: Transport> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Transport> Clone for AsyncEndpoint<T> {
+    fn clone(&self) -> AsyncEndpoint<T> {
+        AsyncEndpoint { shared: self.shared.clone() }
+    }
+}
+
+impl AsyncEndpoint<Outgoing> {
+    /// An `AsyncEndpoint` sending over `trans`; see `outgoing`.
+    pub fn over<A: AsyncTransport>(trans: A) -> (AsyncEndpoint<Outgoing>, impl Future<Output = Result<(), Error>>) {
+        let (trans, sending) = outgoing(trans);
+        (AsyncEndpoint::new(trans), sending)
+    }
+}
+
+impl<T: Transport> AsyncEndpoint<T> {
+    pub fn new(trans: T) -> AsyncEndpoint<T> {
+        let (root, root_ready) = reply();
+        let (events, incoming_events) = incoming();
+        let mut pending = Pending {
+            remotes: HashMap::new(),
+            commands: HashMap::new(),
+            pipes: HashMap::new(),
+            lost: HashSet::new(),
+            events: Some(events),
+            closed: false,
+        };
+        pending.remotes.insert(RemoteId(0), root);
+        AsyncEndpoint {
+            shared: Arc::new(Shared {
+                endpoint: Mutex::new(Endpoint::new(trans, pending)),
+                root_ready: Mutex::new(Some(root_ready)),
+                events: Mutex::new(Some(incoming_events)),
+            }),
+        }
+    }
+
+    /// The underlying `Endpoint`, for requests that don't have an answer to wait for.
+    pub fn lock(&self) -> MutexGuard<'_, Endpoint<T, Pending>> {
+        self.shared.endpoint.lock().unwrap()
+    }
+
+    /// The `Endpoint`, as long as answers to what's sent on it to `remote` can still arrive.
+    fn connected(&self, remote: RemoteId) -> Result<MutexGuard<'_, Endpoint<T, Pending>>, Error> {
+        let endpoint = self.lock();
+        if endpoint.handler.closed {
+            return Err(format_err!("the connection has closed"));
+        }
+        if let Some(&lost) = endpoint.handler.lost.iter().find(|&&lost| endpoint.is_on_path(lost, remote)) {
+            return Err(RequestError::error(ErrorKind::Io, format!("the connection to remote {} was lost", lost.0)));
+        }
+        Ok(endpoint)
+    }
+
+    /// Resolves once the backend this is talking to is ready.  Can only be called once.
+    pub fn root_ready(&self) -> Reply<RemoteInfo> {
+        self.shared.root_ready.lock().unwrap().take().expect("root_ready was already called")
+    }
+
+    /// Edit requests, and errors nothing was waiting on, until `receive_from` runs out.  Can only
+    /// be called once.
+    pub fn events(&self) -> Incoming<Event> {
+        self.shared.events.lock().unwrap().take().expect("events was already called")
+    }
+
+    /// Starts a backend with `command` on `parent`.
+    pub fn remote(&self, parent: RemoteId, command: Command) -> Result<(RemoteId, Reply<RemoteInfo>), Error> {
+        let mut endpoint = self.connected(parent)?;
+        let id = endpoint.remote(parent, command)?;
+        let (answer, reply) = reply();
+        endpoint.handler.remotes.insert(id, answer);
+        Ok((id, reply))
+    }
+
+    pub fn command(&self, remote: RemoteId, command: Command, block_for: HashMap<ProcessId, Condition>, pipes: WritePipes) -> Result<(ProcessId, Reply<Exit>), Error> {
+        let mut endpoint = self.connected(remote)?;
+        let id = endpoint.command(remote, command, block_for, pipes)?;
+        let (answer, reply) = reply();
+        endpoint.handler.commands.insert(id, (remote, answer));
+        Ok((id, reply))
+    }
+
+    pub fn list_directory(&self, remote: RemoteId, path: String) -> Result<Reply<Vec<String>>, Error> {
+        self.call(remote, Query::ListDirectory(path), |answer| match answer {
+            Answer::DirectoryListing(items) => Some(items),
+            _ => None,
+        })
+    }
+
+    /// Resolves once `remote` answers, which it only can if it and every remote between it and
+    /// here list the `ping` capability.
+    pub fn ping(&self, remote: RemoteId) -> Result<Reply<()>, Error> {
+        self.call(remote, Query::Ping, |answer| match answer {
+            Answer::Pong => Some(()),
+            _ => None,
+        })
+    }
+
+    fn call<R: Send + 'static>(&self, remote: RemoteId, query: Query, unpack: fn(Answer) -> Option<R>) -> Result<Reply<R>, Error> {
+        let (answer, reply) = reply();
+        self.connected(remote)?.call(remote, query, None, Box::new(move |_, result| {
+            answer.send(result.and_then(|a| unpack(a).ok_or_else(|| format_err!("the wrong kind of answer came"))));
+            Ok(())
+        }))?;
+        Ok(reply)
+    }
+
+    pub fn pipe(&self) -> (ReadPipe, WritePipe) {
+        self.lock().pipe()
+    }
+
+    /// Reads `pipe` on `remote`, which ends once the pipe's closed.
+    pub fn read_pipe(&self, remote: RemoteId, pipe: ReadPipe) -> Result<Incoming<Result<Vec<u8>, Error>>, Error> {
+        let mut endpoint = self.connected(remote)?;
+        let (feed, incoming) = incoming();
+        endpoint.handler.pipes.insert(pipe.to_generic(), PipeFeed {
+            remote,
+            received: 0,
+            granted: READ_WINDOW,
+            feed,
+        });
+        endpoint.pipe_begin_read(remote, pipe)?;
+        endpoint.pipe_read(remote, pipe, READ_WINDOW)?;
+        Ok(incoming)
+    }
+
+    pub fn receive(&self, response: Response) -> Result<(), Error> {
+        self.lock().receive(response)
+    }
+
+    /// Feeds in responses read from `output` until it ends, as on a thread of its own.  Anything
+    /// still waiting then fails, and the events end.
+    pub fn receive_from<R: BufRead>(&self, mut output: R) -> Result<(), Error> {
+        let mut line = String::new();
+        let result = loop {
+            line.clear();
+            match output.read_line(&mut line) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e.into()),
+            }
+            let response: Response = match serde_json::from_str(&line) {
+                Ok(response) => response,
+                Err(e) => break Err(format_err!("bad response from the backend: {}", e)),
+            };
+            if let Err(e) = self.receive(response) {
+                break Err(e);
+            }
+        };
+
+        let mut endpoint = self.lock();
+        endpoint.handler.closed = true;
+        endpoint.handler.events = None;
+        endpoint.handler.remotes.clear();
+        endpoint.handler.commands.clear();
+        // So readers can tell this from the pipe closing.
+        for (_, pipe) in endpoint.handler.pipes.drain() {
+            pipe.feed.push(Err(format_err!("the connection closed before the pipe did")));
+        }
+        let failed = endpoint.fail_calls("the connection closed before an answer came");
+        result.and(failed)
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` to completion on this thread, for callers without an executor of their own.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RemoteResponse, RemoteResponseEnvelope};
+
+    #[derive(Clone, Default)]
+    struct Sent(Arc<Mutex<Vec<String>>>);
+
+    impl Transport for Sent {
+        fn send(&mut self, msg: &[u8]) -> Result<(), Error> {
+            self.0.lock().unwrap().push(String::from_utf8(msg.to_vec())?);
+            Ok(())
+        }
+    }
+
+    fn response(message: RemoteResponse) -> Response {
+        response_from(RemoteId(0), message)
+    }
+
+    fn response_from(remote: RemoteId, message: RemoteResponse) -> Response {
+        Response { remote_id: remote.0, message: RemoteResponseEnvelope(message) }
+    }
+
+    #[test]
+    fn replies() {
+        let sent = Sent::default();
+        let endpoint = AsyncEndpoint::new(sent.clone());
+        let root = endpoint.lock().root();
+        let ready = endpoint.root_ready();
+        endpoint.receive(response(RemoteResponse::RemoteReady { info: RemoteInfo::default() })).unwrap();
+        assert_eq!(block_on(ready).unwrap(), RemoteInfo::default());
+
+        let first = endpoint.list_directory(root, "/".to_string()).unwrap();
+        let second = endpoint.list_directory(root, "/nonexistent".to_string()).unwrap();
+        assert_eq!(sent.0.lock().unwrap().len(), 2);
+
+        // Answered out of order.
+        endpoint.receive(response(RemoteResponse::Error {
+            request: FailedRequest::ListDirectory(2),
+            kind: ErrorKind::NotFound,
+            message: "/nonexistent: not found".to_string(),
+        })).unwrap();
+        endpoint.receive(response(RemoteResponse::DirectoryListing { id: 1, items: vec!["bin".to_string()] })).unwrap();
+        assert_eq!(block_on(first).unwrap(), vec!["bin".to_string()]);
+        let error = block_on(second).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::NotFound);
+
+        // Errors nothing is waiting for go to the events.
+        let mut events = endpoint.events();
+        endpoint.receive(response(RemoteResponse::Error {
+            request: FailedRequest::Other,
+            kind: ErrorKind::InvalidRequest,
+            message: "huh".to_string(),
+        })).unwrap();
+        match block_on(events.recv()) {
+            Some(Event::Error(id, e)) => assert_eq!((id, e.to_string()), (root, "huh".to_string())),
+            other => panic!("{:?}", other),
+        }
+
+        // Whatever's left fails once the connection's gone, and the events end.
+        let orphan = endpoint.list_directory(root, "/".to_string()).unwrap();
+        endpoint.receive_from(&b""[..]).unwrap();
+        assert!(block_on(orphan).is_err());
+        assert!(block_on(events.recv()).is_none());
+    }
+
+    #[test]
+    fn lost_remote() {
+        let endpoint = AsyncEndpoint::new(Sent::default());
+        let root = endpoint.lock().root();
+        let mut events = endpoint.events();
+        let (nested, ready) = endpoint.remote(root, Command::Unknown("backend".to_string(), vec![])).unwrap();
+        endpoint.receive(response_from(nested, RemoteResponse::RemoteReady { info: RemoteInfo::default() })).unwrap();
+        block_on(ready).unwrap();
+
+        let (stdin, _) = endpoint.pipe();
+        let (stdout_read, stdout) = endpoint.pipe();
+        let (_, stderr) = endpoint.pipe();
+        let command = Command::Unknown("sleep".to_string(), vec!["10".to_string()]);
+        let (_, exit) = endpoint.command(nested, command, HashMap::new(), WritePipes { stdin, stdout, stderr }).unwrap();
+        let mut output = endpoint.read_pipe(nested, stdout_read).unwrap();
+        let ping = endpoint.ping(nested).unwrap();
+
+        endpoint.receive(response(RemoteResponse::Error {
+            request: FailedRequest::Remote(nested),
+            kind: ErrorKind::Io,
+            message: "the connection through `backend` closed".to_string(),
+        })).unwrap();
+        match block_on(events.recv()) {
+            Some(Event::Lost(id, _)) => assert_eq!(id, nested),
+            other => panic!("{:?}", other),
+        }
+        assert!(block_on(exit).is_err());
+        assert!(block_on(output.recv()).unwrap().is_err());
+        assert!(block_on(ping).is_err());
+        assert_eq!(endpoint.lock().pending_calls(), 0);
+
+        // Nothing more goes to it until it's back.
+        assert!(endpoint.ping(nested).is_err());
+        endpoint.ping(root).unwrap();
+        endpoint.receive(response_from(nested, RemoteResponse::RemoteReady { info: RemoteInfo::default() })).unwrap();
+        endpoint.ping(nested).unwrap();
+    }
+
+    #[derive(Clone, Default)]
+    struct AsyncSent(Arc<Mutex<Vec<String>>>);
+
+    impl AsyncTransport for AsyncSent {
+        fn send<'a>(&'a mut self, msg: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+            Box::pin(async move {
+                self.0.lock().unwrap().push(String::from_utf8(msg.to_vec())?);
+                Ok(())
+            })
+        }
+    }
+
+    struct Broken;
+
+    impl AsyncTransport for Broken {
+        fn send<'a>(&'a mut self, _msg: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+            Box::pin(async { Err(format_err!("couldn't send")) })
+        }
+    }
+
+    #[test]
+    fn async_transport() {
+        let sent = AsyncSent::default();
+        let (endpoint, sending) = AsyncEndpoint::over(sent.clone());
+        let root = endpoint.lock().root();
+        endpoint.list_directory(root, "/".to_string()).unwrap();
+        drop(endpoint);
+        block_on(sending).unwrap();
+        assert_eq!(sent.0.lock().unwrap().len(), 1);
+        assert!(sent.0.lock().unwrap()[0].contains("ListDirectory"), "{:?}", sent.0);
+
+        // Once a write fails, so does everything sent after.
+        let (mut backend, sending) = Backend::over(Broken);
+        backend.pong(1).unwrap();
+        assert!(block_on(sending).is_err());
+        assert!(backend.pong(2).is_err());
+    }
+
+    #[test]
+    fn woken_from_another_thread() {
+        let endpoint = AsyncEndpoint::new(Sent::default());
+        let root = endpoint.lock().root();
+        let listing = endpoint.list_directory(root, "/".to_string()).unwrap();
+
+        let receiver = endpoint.clone();
+        let answer = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(20));
+            let line = serde_json::to_string(&response(RemoteResponse::DirectoryListing { id: 1, items: vec![] })).unwrap();
+            receiver.receive_from(format!("{}\n", line).as_bytes())
+        });
+        assert_eq!(block_on(listing).unwrap(), Vec::<String>::new());
+        answer.join().unwrap().unwrap();
+    }
+}
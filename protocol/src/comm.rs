@@ -1,7 +1,7 @@
 
 
 use std::collections::{HashMap, HashSet};
-use serde_json;
+use std::time::{Duration, Instant};
 
 use crate::{
     RemoteId,
@@ -12,6 +12,7 @@ use crate::{
     ReadPipe,
     WritePipes,
     RemoteInfo,
+    ResourceUsage,
     RemoteResponse,
     RemoteRequest,
     RemoteResponseEnvelope,
@@ -26,6 +27,13 @@ use crate::{
     Ids,
     PipeMessage,
     PipeEnvelope,
+    FailedRequest,
+    ErrorKind,
+    Framing,
+    EncodedData,
+    Hello,
+    RequestError,
+    capability,
 };
 
 use failure::Error;
@@ -36,10 +44,39 @@ pub trait Transport {
 
 pub trait EndpointHandler<T: Transport>: Sized {
     fn remote_ready(endpoint: &mut Endpoint<T, Self>, id: RemoteId, remote_info: RemoteInfo) -> Result<(), Error>;
-    fn command_done(endpoint: &mut Endpoint<T, Self>, id: ProcessId, exit_code: i64) -> Result<(), Error>;
-    fn directory_listing(endpoint: &mut Endpoint<T, Self>, id: usize, items: Vec<String>) -> Result<(), Error>;
+    fn command_done(endpoint: &mut Endpoint<T, Self>, id: ProcessId, exit_code: i64, usage: Option<ResourceUsage>) -> Result<(), Error>;
     fn edit_request(endpoint: &mut Endpoint<T, Self>, edit_id: usize, command_id: ProcessId, name: String, data: Vec<u8>) -> Result<(), Error>;
     fn pipe(endpoint: &mut Endpoint<T, Self>, id: GenericPipe, msg: PipeMessage) -> Result<(), Error>;
+    fn error(endpoint: &mut Endpoint<T, Self>, id: RemoteId, request: FailedRequest, kind: ErrorKind, message: String) -> Result<(), Error>;
+}
+
+/// A question for a remote, sent with `Endpoint::call`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    ListDirectory(String),
+    /// Only for remotes listing the `ping` capability, as must every remote between them and
+    /// the frontend.
+    Ping,
+}
+
+/// What a `Query` was answered with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Answer {
+    DirectoryListing(Vec<String>),
+    Pong,
+}
+
+/// Identifies a call made with `Endpoint::call`, for cancelling it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct CallId(usize);
+
+/// Run with the answer to a call, or the error that ended it.
+pub type Callback<T, H> = Box<dyn FnOnce(&mut Endpoint<T, H>, Result<Answer, Error>) -> Result<(), Error> + Send>;
+
+struct PendingCall<T: Transport, H: EndpointHandler<T>> {
+    remote: RemoteId,
+    deadline: Option<Instant>,
+    callback: Callback<T, H>,
 }
 
 pub struct Endpoint<T: Transport, H: EndpointHandler<T>> {
@@ -49,6 +86,7 @@ pub struct Endpoint<T: Transport, H: EndpointHandler<T>> {
     remotes: HashMap<RemoteId, RemoteState>,
     jobs: HashMap<ProcessId, ProcessState>,
     pipes: HashSet<usize>,
+    calls: HashMap<usize, PendingCall<T, H>>,
 }
 
 fn ser_to_endpoint(remote: RemoteId, message: RemoteRequest) -> Vec<u8> {
@@ -70,13 +108,18 @@ impl<T: Transport, H: EndpointHandler<T>> Endpoint<T, H> {
         let mut remotes = HashMap::new();
         remotes.insert(RemoteId(0), RemoteState { parent: None });
 
+        // 0 is the root remote.
+        let mut ids = Ids::new();
+        ids.next();
+
         Endpoint {
             trans,
             handler,
-            ids: Ids::new(),
+            ids,
             remotes,
             jobs: HashMap::new(),
             pipes: HashSet::new(),
+            calls: HashMap::new(),
         }
     }
 
@@ -90,19 +133,31 @@ impl<T: Transport, H: EndpointHandler<T>> Endpoint<T, H> {
             RemoteResponse::RemoteReady { info } => {
                 EndpointHandler::remote_ready(self, RemoteId(message.remote_id), info)
             }
-            RemoteResponse::CommandDone { id, exit_code } => {
-                EndpointHandler::command_done(self, id, exit_code)
+            RemoteResponse::CommandDone { id, exit_code, usage } => {
+                EndpointHandler::command_done(self, id, exit_code, usage)
             }
             RemoteResponse::DirectoryListing { id, items } => {
-                EndpointHandler::directory_listing(self, id, items)
+                self.answer_from(RemoteId(message.remote_id), id, Ok(Answer::DirectoryListing(items)))
             }
             RemoteResponse::EditRequest { edit_id, command_id, name, data } => {
                 EndpointHandler::edit_request(self, edit_id, command_id, name, data)
             }
             RemoteResponse::Pipe(pipe) => {
-                assert!(self.pipes.contains(&pipe.id));
+                if !self.pipes.contains(&pipe.id) {
+                    return Err(format_err!("message for unknown pipe {}", pipe.id));
+                }
                 EndpointHandler::pipe(self, GenericPipe(pipe.id), pipe.msg)
             }
+            RemoteResponse::Pong { id } => {
+                self.answer_from(RemoteId(message.remote_id), id, Ok(Answer::Pong))
+            }
+            RemoteResponse::Error { request: FailedRequest::ListDirectory(id), kind, message: text } |
+            RemoteResponse::Error { request: FailedRequest::Ping(id), kind, message: text } => {
+                self.answer_from(RemoteId(message.remote_id), id, Err(RequestError::error(kind, text)))
+            }
+            RemoteResponse::Error { request, kind, message: text } => {
+                EndpointHandler::error(self, RemoteId(message.remote_id), request, kind, text)
+            }
         }
     }
 
@@ -125,6 +180,99 @@ impl<T: Transport, H: EndpointHandler<T>> Endpoint<T, H> {
         Ok(RemoteId(id))
     }
 
+    /// Asks `remote` a question, running `callback` with the answer.  If there isn't one within
+    /// `timeout`, the callback gets an `ErrorKind::TimedOut` error instead, once `expire_calls`
+    /// notices.  Answers to calls that have timed out or been cancelled are dropped.
+    pub fn call(&mut self, remote: RemoteId, query: Query, timeout: Option<Duration>, callback: Callback<T, H>) -> Result<CallId, Error> {
+        assert!(self.remotes.contains_key(&remote));
+
+        let id = self.ids.next();
+
+        let request = match query {
+            Query::ListDirectory(path) => RemoteRequest::ListDirectory { id, path },
+            Query::Ping => RemoteRequest::Ping { id },
+        };
+        self.trans.send(&ser_to_endpoint(remote, request))?;
+
+        self.calls.insert(id, PendingCall {
+            remote,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            callback,
+        });
+
+        Ok(CallId(id))
+    }
+
+    /// Forgets a call, without running its callback.  Does nothing if it's already finished.
+    pub fn cancel_call(&mut self, id: CallId) {
+        self.calls.remove(&id.0);
+    }
+
+    /// How many calls are still waiting for an answer.
+    pub fn pending_calls(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// When the next call times out, if any can.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.calls.values().filter_map(|call| call.deadline).min()
+    }
+
+    /// Fails the calls whose deadline is before `now`.
+    pub fn expire_calls(&mut self, now: Instant) -> Result<(), Error> {
+        let expired: Vec<usize> = self.calls.iter()
+            .filter(|(_, call)| call.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.answer(id, Err(RequestError::error(ErrorKind::TimedOut, "no answer came in time")))?;
+        }
+        Ok(())
+    }
+
+    /// Fails every call still waiting, as when the connection's gone.
+    pub fn fail_calls(&mut self, message: &str) -> Result<(), Error> {
+        let calls: Vec<usize> = self.calls.keys().cloned().collect();
+        for id in calls {
+            self.answer(id, Err(format_err!("{}", message)))?;
+        }
+        Ok(())
+    }
+
+    /// Answers call `id` with what `from` sent.  Only the remote that was asked can answer, but
+    /// any remote it was started through can say the call failed, as when the link to it is lost.
+    fn answer_from(&mut self, from: RemoteId, id: usize, answer: Result<Answer, Error>) -> Result<(), Error> {
+        if let Some(call) = self.calls.get(&id) {
+            let allowed = match answer {
+                Ok(_) => call.remote == from,
+                Err(_) => self.is_on_path(from, call.remote),
+            };
+            if !allowed {
+                return Err(format_err!("{:?} answered call {}, which was made to {:?}", from, id, call.remote));
+            }
+        }
+        self.answer(id, answer)
+    }
+
+    /// Whether `hop` is `remote`, or one of the remotes it was started through.
+    pub(crate) fn is_on_path(&self, hop: RemoteId, remote: RemoteId) -> bool {
+        let mut at = Some(remote);
+        while let Some(id) = at {
+            if id == hop {
+                return true;
+            }
+            at = self.remotes.get(&id).and_then(|state| state.parent);
+        }
+        false
+    }
+
+    fn answer(&mut self, id: usize, answer: Result<Answer, Error>) -> Result<(), Error> {
+        match self.calls.remove(&id) {
+            Some(call) => (call.callback)(self, answer),
+            None => Ok(()),
+        }
+    }
+
     pub fn pipe(&mut self) -> (ReadPipe, WritePipe) {
         let id = self.ids.next();
         self.pipes.insert(id);
@@ -189,6 +337,7 @@ impl<T: Transport, H: EndpointHandler<T>> Endpoint<T, H> {
         let state = self.remotes.remove(&remote).expect("remote not connected");
 
         // TODO: close jobs?
+        self.fail_calls_to(remote, "the remote was closed")?;
 
         self.trans.send(&ser_to_endpoint(state.parent.expect("closing root remote"), RemoteRequest::EndRemote {
             id: remote.0,
@@ -197,6 +346,24 @@ impl<T: Transport, H: EndpointHandler<T>> Endpoint<T, H> {
         Ok(())
     }
 
+    /// Drops a remote that can't be reached any more, without asking anything to close it.
+    pub fn forget_remote(&mut self, remote: RemoteId) -> Result<(), Error> {
+        self.remotes.remove(&remote).expect("remote not connected");
+        self.fail_calls_to(remote, "the connection to the remote was lost")
+    }
+
+    /// Fails the calls to `remote`, and to remotes started through it.
+    pub(crate) fn fail_calls_to(&mut self, remote: RemoteId, message: &str) -> Result<(), Error> {
+        let calls: Vec<usize> = self.calls.iter()
+            .filter(|(_, call)| self.is_on_path(remote, call.remote))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in calls {
+            self.answer(id, Err(format_err!("{}", message)))?;
+        }
+        Ok(())
+    }
+
     pub fn close_process(&mut self, id: ProcessId) -> Result<(), Error> {
         let process_state = self.jobs.remove(&id).expect("process not running");
 
@@ -254,14 +421,44 @@ pub trait BackendHandler {
     fn list_directory(&mut self, id: usize, path: String) -> Result<(), Error>;
     fn finish_edit(&mut self, id: usize, data: Vec<u8>) -> Result<(), Error>;
     fn pipe(&mut self, id: GenericPipe, msg: PipeMessage) -> Result<(), Error>;
+    fn ping(&mut self, id: usize) -> Result<(), Error>;
 }
 
 #[derive(Default)]
 pub struct Backend<T: Transport> {
     pub trans: T,
+    /// For pipe data sent to the frontend; set once it's said hello.
+    pub framing: Framing,
+    /// Whether the frontend said it can take `Framing::Deflate`.
+    pub can_compress: bool,
+    compressing: bool,
 }
 
 impl Request {
+    /// The id of the remote this request starts, if it's a `BeginRemote`.
+    pub fn new_remote(&self) -> Option<usize> {
+        match self.message.0 {
+            RemoteRequest::BeginRemote { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    /// What to call this request in a `RemoteResponse::Error` about it.
+    pub fn failed_request(&self) -> FailedRequest {
+        match self.message.0 {
+            RemoteRequest::BeginCommand { ref process, .. } => FailedRequest::Command(process.id),
+            RemoteRequest::CancelCommand { id } => FailedRequest::Command(id),
+            RemoteRequest::BeginRemote { id, .. } |
+            RemoteRequest::EndRemote { id } => FailedRequest::Remote(RemoteId(id)),
+            RemoteRequest::OpenOutputFile { id, .. } |
+            RemoteRequest::OpenInputFile { id, .. } => FailedRequest::Pipe(GenericPipe(id)),
+            RemoteRequest::ListDirectory { id, .. } => FailedRequest::ListDirectory(id),
+            RemoteRequest::FinishEdit { id, .. } => FailedRequest::Edit(id),
+            RemoteRequest::Pipe(ref pipe) => FailedRequest::Pipe(GenericPipe(pipe.id)),
+            RemoteRequest::Ping { id } => FailedRequest::Ping(id),
+        }
+    }
+
     pub fn route<H: BackendHandler>(self, handler: &mut H) -> Result<(), Error> {
         // eprintln!("msg: {:?}", self);
         match self.message.0 {
@@ -300,6 +497,9 @@ impl Request {
             RemoteRequest::Pipe(pipe) => {
                 handler.pipe(GenericPipe(pipe.id), pipe.msg)
             }
+            RemoteRequest::Ping { id } => {
+                handler.ping(id)
+            }
         }
     }
 }
@@ -308,9 +508,44 @@ impl<T: Transport> Backend<T> {
     pub fn new(trans: T) -> Backend<T> {
         Backend {
             trans,
+            framing: Framing::Json,
+            can_compress: false,
+            compressing: false,
+        }
+    }
+
+    /// Records what the frontend said in its hello.
+    pub fn hello(&mut self, ours: &Hello, theirs: &Hello) {
+        self.framing = Framing::negotiate(ours, theirs);
+        self.can_compress = ours.supports(capability::COMPRESSION) && theirs.supports(capability::COMPRESSION);
+    }
+
+    /// Starts or stops deflating pipe data, failing if the frontend can't inflate it.
+    pub fn set_compression(&mut self, on: bool) -> Result<(), Error> {
+        if on && !self.can_compress {
+            return Err(RequestError::invalid("the other end of this link can't take compressed data"));
+        }
+        self.compressing = on;
+        Ok(())
+    }
+
+    fn data_framing(&self) -> Framing {
+        if self.compressing {
+            Framing::Deflate
+        } else {
+            self.framing
         }
     }
 
+    /// Passes on a response from a remote started by this backend.  Data that arrived deflated
+    /// and is going out deflated is sent as is.
+    pub fn forward(&mut self, mut response: Response) -> Result<(), Error> {
+        response.set_framing(self.data_framing());
+        self.trans.send(format!("{}\n", serde_json::to_string(&response)?).as_bytes())?;
+
+        Ok(())
+    }
+
     pub fn remote_ready(&mut self, info: RemoteInfo) -> Result<(), Error> {
         self.trans.send(&ser_to_frontend(RemoteId(0), RemoteResponse::RemoteReady {
             info,
@@ -322,7 +557,7 @@ impl<T: Transport> Backend<T> {
     pub fn pipe_data(&mut self, id: WritePipe, data: Vec<u8>, end_offset: u64) -> Result<(), Error> {
         self.trans.send(&ser_to_frontend(RemoteId(0), RemoteResponse::Pipe(PipeEnvelope {
             id: id.0,
-            msg: PipeMessage::Data { data, end_offset, },
+            msg: PipeMessage::Data { data: EncodedData::new(data, self.data_framing()), end_offset, },
         })))?;
 
         Ok(())
@@ -338,10 +573,11 @@ impl<T: Transport> Backend<T> {
         Ok(())
     }
 
-    pub fn command_done(&mut self, id: ProcessId, exit_code: i64) -> Result<(), Error> {
+    pub fn command_done(&mut self, id: ProcessId, exit_code: i64, usage: Option<ResourceUsage>) -> Result<(), Error> {
         self.trans.send(&ser_to_frontend(RemoteId(0), RemoteResponse::CommandDone {
             id,
             exit_code,
+            usage,
         }))?;
 
         Ok(())
@@ -356,6 +592,27 @@ impl<T: Transport> Backend<T> {
         Ok(())
     }
 
+    pub fn pong(&mut self, id: usize) -> Result<(), Error> {
+        self.trans.send(&ser_to_frontend(RemoteId(0), RemoteResponse::Pong { id }))?;
+
+        Ok(())
+    }
+
+    pub fn error(&mut self, request: FailedRequest, error: &Error) -> Result<(), Error> {
+        self.trans.send(&ser_to_frontend(RemoteId(0), RemoteResponse::Error {
+            request,
+            kind: ErrorKind::of(error),
+            message: error.to_string(),
+        }))?;
+
+        Ok(())
+    }
+
+    /// Tells the frontend that remote `id`, started from here, has gone away by itself.
+    pub fn remote_lost(&mut self, id: usize, error: &Error) -> Result<(), Error> {
+        self.error(FailedRequest::Remote(RemoteId(id)), error)
+    }
+
     pub fn edit_request(&mut self, command_id: ProcessId, edit_id: usize, name: String, data: Vec<u8>) -> Result<(), Error> {
         self.trans.send(&ser_to_frontend(RemoteId(0), RemoteResponse::EditRequest {
             command_id,
@@ -366,4 +623,120 @@ impl<T: Transport> Backend<T> {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::RemoteResponseEnvelope;
+
+    struct Discard;
+
+    impl Transport for Discard {
+        fn send(&mut self, _msg: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    type Answered = Vec<(usize, Result<Answer, String>)>;
+
+    /// Records what each call's callback got.
+    #[derive(Default)]
+    struct Answers(Arc<Mutex<Answered>>);
+
+    impl<T: Transport> EndpointHandler<T> for Answers {
+        fn remote_ready(_endpoint: &mut Endpoint<T, Self>, _id: RemoteId, _remote_info: RemoteInfo) -> Result<(), Error> { Ok(()) }
+        fn command_done(_endpoint: &mut Endpoint<T, Self>, _id: ProcessId, _exit_code: i64, _usage: Option<ResourceUsage>) -> Result<(), Error> { Ok(()) }
+        fn edit_request(_endpoint: &mut Endpoint<T, Self>, _edit_id: usize, _command_id: ProcessId, _name: String, _data: Vec<u8>) -> Result<(), Error> { Ok(()) }
+        fn pipe(_endpoint: &mut Endpoint<T, Self>, _id: GenericPipe, _msg: PipeMessage) -> Result<(), Error> { Ok(()) }
+        fn error(_endpoint: &mut Endpoint<T, Self>, _id: RemoteId, _request: FailedRequest, _kind: ErrorKind, message: String) -> Result<(), Error> {
+            Err(format_err!("unexpected error: {}", message))
+        }
+    }
+
+    fn ask(endpoint: &mut Endpoint<Discard, Answers>, n: usize, remote: RemoteId, query: Query, timeout: Option<Duration>) -> CallId {
+        let answers = endpoint.handler.0.clone();
+        endpoint.call(remote, query, timeout, Box::new(move |_, answer| {
+            answers.lock().unwrap().push((n, answer.map_err(|e| e.to_string())));
+            Ok(())
+        })).unwrap()
+    }
+
+    fn response(message: RemoteResponse) -> Response {
+        Response { remote_id: 0, message: RemoteResponseEnvelope(message) }
+    }
+
+    fn list(endpoint: &mut Endpoint<Discard, Answers>, n: usize, timeout: Option<Duration>) -> CallId {
+        let root = endpoint.root();
+        ask(endpoint, n, root, Query::ListDirectory("/".to_string()), timeout)
+    }
+
+    #[test]
+    fn calls() {
+        let mut endpoint = Endpoint::new(Discard, Answers::default());
+        let listing = |id, items: &[&str]| response(RemoteResponse::DirectoryListing {
+            id,
+            items: items.iter().map(|i| i.to_string()).collect(),
+        });
+
+        // Ids come from the same counter as everything else, starting after the root remote.
+        list(&mut endpoint, 1, None);
+        list(&mut endpoint, 2, None);
+        let cancelled = list(&mut endpoint, 3, None);
+        list(&mut endpoint, 4, Some(Duration::from_secs(60)));
+        assert_eq!(endpoint.pending_calls(), 4);
+
+        endpoint.receive(listing(2, &["bin"])).unwrap();
+        endpoint.receive(response(RemoteResponse::Error {
+            request: FailedRequest::ListDirectory(1),
+            kind: ErrorKind::NotFound,
+            message: "gone".to_string(),
+        })).unwrap();
+        endpoint.cancel_call(cancelled);
+        // Late and unknown answers are dropped.
+        endpoint.receive(listing(3, &["late"])).unwrap();
+        endpoint.receive(listing(2, &["again"])).unwrap();
+
+        assert!(endpoint.next_deadline().is_some());
+        endpoint.expire_calls(Instant::now()).unwrap();
+        assert_eq!(endpoint.pending_calls(), 1);
+        endpoint.expire_calls(Instant::now() + Duration::from_secs(61)).unwrap();
+        assert_eq!(endpoint.pending_calls(), 0);
+        assert_eq!(endpoint.next_deadline(), None);
+
+        assert_eq!(*endpoint.handler.0.lock().unwrap(), vec![
+            (2, Ok(Answer::DirectoryListing(vec!["bin".to_string()]))),
+            (1, Err("gone".to_string())),
+            (4, Err("no answer came in time".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn pings() {
+        let mut endpoint = Endpoint::new(Discard, Answers::default());
+        let root = endpoint.root();
+        let remote = endpoint.remote(root, Command::Unknown("ssh".to_string(), vec![])).unwrap();
+
+        ask(&mut endpoint, 1, root, Query::Ping, None);
+        ask(&mut endpoint, 2, remote, Query::Ping, None);
+        endpoint.receive(response(RemoteResponse::Pong { id: 2 })).unwrap();
+        // Only the remote that was asked can answer, though the one in between can fail it.
+        assert!(endpoint.receive(response(RemoteResponse::Pong { id: 3 })).is_err());
+        assert_eq!(endpoint.pending_calls(), 1);
+        ask(&mut endpoint, 3, remote, Query::Ping, None);
+        endpoint.receive(response(RemoteResponse::Error {
+            request: FailedRequest::Ping(4),
+            kind: ErrorKind::Io,
+            message: "no route".to_string(),
+        })).unwrap();
+        // Nothing's sent to a lost remote, but whatever was waiting on it fails.
+        endpoint.forget_remote(remote).unwrap();
+        assert_eq!(endpoint.pending_calls(), 0);
+
+        assert_eq!(*endpoint.handler.0.lock().unwrap(), vec![
+            (1, Ok(Answer::Pong)),
+            (3, Err("no route".to_string())),
+            (2, Err("the connection to the remote was lost".to_string())),
+        ]);
+    }
+}
@@ -0,0 +1,168 @@
+//! The line each side of a link sends before anything else, so mismatched builds find out up
+//! front instead of tripping over each other's messages later.
+//!
+//! The backend writes its `Hello` as soon as it starts; whoever launched it checks that, then
+//! answers with its own.  Builds from before the exchange can't talk to this one either way:
+//! a backend gives up on a first line that isn't a `Hello`, and old frontends reject a backend
+//! that doesn't start with `LEGACY_MAGIC`.
+
+use std::env;
+
+use failure::Error;
+
+/// Bumped whenever a message changes in a way older builds can't read.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest `PROTOCOL_VERSION` this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// What backends sent instead of a `Hello` before versioning.
+pub const LEGACY_MAGIC: &str = "nxQh6wsIiiFomXWE+7HQhQ==";
+
+/// Optional features, as listed in `Hello::capabilities` and `RemoteInfo::capabilities`.  Those
+/// about the link itself are only used when both ends of it list them.
+pub mod capability {
+    /// `Command::SetEnvironment` is understood.
+    pub const SET_ENVIRONMENT: &str = "set_environment";
+    /// Finished commands report their `ResourceUsage`.
+    pub const RUSAGE: &str = "rusage";
+    /// Pipe data can be deflated; see `Command::SetCompression`.
+    pub const COMPRESSION: &str = "compression";
+    /// `RemoteRequest::Ping` is answered.
+    pub const PING: &str = "ping";
+    /// Pipe data can be sent as base64 rather than arrays of numbers; see `Framing`.
+    pub const BASE64_DATA: &str = "base64_data";
+}
+
+/// Every `capability` this build supports.
+pub const CAPABILITIES: &[&str] = &[
+    capability::SET_ENVIRONMENT,
+    capability::RUSAGE,
+    capability::COMPRESSION,
+    capability::PING,
+    capability::BASE64_DATA,
+];
+
+/// Set (to anything) to keep pipe data as plain JSON arrays, for reading the protocol while
+/// debugging.  Inherited by every backend started from there.
+pub const PLAIN_DATA_VAR: &str = "NAK_PLAIN_DATA";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol: u32,
+    pub min_protocol: u32,
+    pub capabilities: Vec<String>,
+    /// The sender's version, for error messages.
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+impl Hello {
+    /// `version` is the sending binary's own version.
+    pub fn ours(version: &str) -> Hello {
+        Hello {
+            protocol: PROTOCOL_VERSION,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter()
+                .filter(|&&c| c != capability::BASE64_DATA || env::var_os(PLAIN_DATA_VAR).is_none())
+                .map(|c| c.to_string())
+                .collect(),
+            version: Some(version.to_string()),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        format!("{}\n", serde_json::to_string(&self).unwrap())
+    }
+
+    /// Reads the other end's first line, returning `None` if it isn't a `Hello` at all.
+    pub fn from_line(line: &str) -> Option<Hello> {
+        serde_json::from_str(line.trim_end()).ok()
+    }
+
+    /// Reads and checks the first line from a backend, which must be a `Hello` we can talk to.
+    pub fn expect(line: &str, peer: &str) -> Result<Hello, Error> {
+        let line = line.trim_end();
+        if line == LEGACY_MAGIC {
+            return Err(format_err!(
+                "{} is too old to talk to (it predates protocol versioning); \
+                 it needs updating to speak protocol {}",
+                peer, PROTOCOL_VERSION));
+        }
+        let hello = match Hello::from_line(line) {
+            Some(hello) => hello,
+            None if line.is_empty() => {
+                return Err(format_err!("{} exited before saying hello", peer));
+            }
+            None => {
+                return Err(format_err!("{} doesn't look like a nak backend; it said {:?}", peer, line));
+            }
+        };
+        hello.check(peer)?;
+        Ok(hello)
+    }
+
+    /// Fails unless each end's version is one the other can talk to.
+    pub fn check(&self, peer: &str) -> Result<(), Error> {
+        if self.protocol < MIN_PROTOCOL_VERSION || self.min_protocol > PROTOCOL_VERSION {
+            let version = match self.version {
+                Some(ref version) => format!(" (version {})", version),
+                None => String::new(),
+            };
+            return Err(format_err!(
+                "{}{} speaks protocol {} (and back to {}), but we speak protocol {} (and back \
+                 to {}); one of them needs updating",
+                peer, version, self.protocol, self.min_protocol,
+                PROTOCOL_VERSION, MIN_PROTOCOL_VERSION));
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// The capabilities both ends support.
+    pub fn shared_capabilities(&self, other: &Hello) -> Vec<String> {
+        self.capabilities.iter().filter(|c| other.supports(c)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        let ours = Hello::ours("0.1.0");
+        assert_eq!(Hello::expect(&ours.to_line(), "backend").unwrap(), ours);
+
+        let err = Hello::expect("nxQh6wsIiiFomXWE+7HQhQ==\n", "backend").unwrap_err();
+        assert!(err.to_string().contains("predates protocol versioning"));
+
+        let err = Hello::expect("Welcome to host!\n", "backend").unwrap_err();
+        assert!(err.to_string().contains("doesn't look like a nak backend"));
+
+        assert!(Hello::expect("", "backend").is_err());
+
+        let newer = Hello {
+            protocol: PROTOCOL_VERSION + 2,
+            min_protocol: PROTOCOL_VERSION + 1,
+            capabilities: vec![],
+            version: Some("9.0.0".to_string()),
+        };
+        let err = Hello::expect(&newer.to_line(), "backend").unwrap_err();
+        assert!(err.to_string().contains("backend (version 9.0.0) speaks protocol"));
+
+        // Newer, but still able to talk down to us.
+        let compatible = Hello { min_protocol: PROTOCOL_VERSION, ..newer };
+        assert!(Hello::expect(&compatible.to_line(), "backend").is_ok());
+
+        // Requests aren't mistaken for a hello.
+        assert_eq!(Hello::from_line(r#"{"remote_id":0,"message":{"EndRemote":{"id":1}}}"#), None);
+
+        let a = Hello { capabilities: vec!["ping".into(), "compression".into()], ..Hello::ours("0.1.0") };
+        let b = Hello { capabilities: vec!["compression".into()], ..Hello::ours("0.1.0") };
+        assert_eq!(a.shared_capabilities(&b), vec!["compression".to_string()]);
+    }
+}
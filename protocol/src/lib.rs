@@ -2,19 +2,61 @@
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
+#[macro_use]
 extern crate failure;
+extern crate base64;
+extern crate flate2;
 
+mod asynchronous;
 mod comm;
+mod hello;
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::{fmt, io};
+use std::io::{Read, Write};
+
+use failure::{Error, Fail};
+use serde::{Serializer, Deserializer};
+use serde::ser::{self, SerializeStruct};
+use serde::de::{self, Visitor, SeqAccess, MapAccess};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 
 pub use crate::comm::{
     EndpointHandler,
     Endpoint,
+    Query,
+    Answer,
+    CallId,
+    Callback,
     BackendHandler,
     Backend,
     Transport,
 };
+pub use crate::asynchronous::{
+    AsyncTransport,
+    AsyncEndpoint,
+    Outgoing,
+    outgoing,
+    Reply,
+    Incoming,
+    Recv,
+    Exit,
+    EditRequest,
+    Event,
+    Pending,
+    block_on,
+};
+pub use crate::hello::{
+    Hello,
+    PLAIN_DATA_VAR,
+    capability,
+    CAPABILITIES,
+    PROTOCOL_VERSION,
+    MIN_PROTOCOL_VERSION,
+};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Command {
@@ -22,6 +64,10 @@ pub enum Command {
     SetDirectory(String),
     GetDirectory,
     Edit(String),
+    SetEnvironment(String, String),
+    /// Deflate pipe data sent back over the link this remote was started on, if the other end
+    /// can take it.
+    SetCompression(bool),
 }
 
 impl Command {
@@ -32,7 +78,9 @@ impl Command {
             }
             &mut Command::SetDirectory(_) |
             &mut Command::GetDirectory |
-            &mut Command::Edit(_) => panic!(),
+            &mut Command::Edit(_) |
+            &mut Command::SetEnvironment(..) |
+            &mut Command::SetCompression(_) => panic!(),
         }
     }
 }
@@ -43,6 +91,15 @@ pub struct Response {
     pub message: RemoteResponseEnvelope,
 }
 
+impl Response {
+    /// For passing a response on over another link, which may have negotiated differently.
+    pub fn set_framing(&mut self, framing: Framing) {
+        if let RemoteResponse::Pipe(PipeEnvelope { msg: PipeMessage::Data { ref mut data, .. }, .. }) = self.message.0 {
+            data.framing = framing;
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Request {
     pub remote_id: usize,
@@ -105,6 +162,12 @@ pub struct Testing {
     ids: Ids,
 }
 
+impl Default for Testing {
+    fn default() -> Testing {
+        Testing::new()
+    }
+}
+
 impl Testing {
     pub fn new() -> Testing {
         Testing {
@@ -163,11 +226,188 @@ pub struct RemoteRequestEnvelope(RemoteRequest);
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RemoteResponseEnvelope(RemoteResponse);
 
+/// How `EncodedData` is written out on a link.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Framing {
+    /// An array of numbers, which every version understands and is easy to read by eye.
+    #[default]
+    Json,
+    /// A base64 string, for links where both ends list `capability::BASE64_DATA`.
+    Base64,
+    /// Deflated, then base64, for links where both ends list `capability::COMPRESSION` and the
+    /// prefs asked for it.  Data that doesn't shrink goes as `Base64` instead.
+    Deflate,
+}
+
+impl Framing {
+    /// The framing to send with, given both ends' capabilities.  `Deflate` is only used once
+    /// asked for, with `Command::SetCompression`.
+    pub fn negotiate(ours: &Hello, theirs: &Hello) -> Framing {
+        if ours.supports(capability::BASE64_DATA) && theirs.supports(capability::BASE64_DATA) {
+            Framing::Base64
+        } else {
+            Framing::Json
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Payload {
+    Plain(Vec<u8>),
+    /// Kept as it arrived, so it can be passed on to a hop that also compresses as is.
+    Deflated {
+        data: Vec<u8>,
+        len: usize,
+    },
+}
+
+/// Bytes carried in a message.  Any `Framing` is accepted when reading; `framing` is only about
+/// how they're written.
+#[derive(Clone, Debug)]
+pub struct EncodedData {
+    payload: Payload,
+    pub framing: Framing,
+}
+
+impl EncodedData {
+    pub fn new(bytes: Vec<u8>, framing: Framing) -> EncodedData {
+        EncodedData { payload: Payload::Plain(bytes), framing }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.payload {
+            Payload::Plain(ref bytes) => bytes.len(),
+            Payload::Deflated { len, .. } => len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>, Error> {
+        match self.payload {
+            Payload::Plain(bytes) => Ok(bytes),
+            Payload::Deflated { data, len } => inflate(&data, len),
+        }
+    }
+
+    /// The bytes themselves, only inflated if they arrived deflated.
+    fn plain(&self) -> Result<Cow<'_, [u8]>, Error> {
+        match self.payload {
+            Payload::Plain(ref bytes) => Ok(Cow::Borrowed(bytes)),
+            Payload::Deflated { ref data, len } => inflate(data, len).map(Cow::Owned),
+        }
+    }
+
+    fn deflated(&self) -> io::Result<Option<Cow<'_, [u8]>>> {
+        match self.payload {
+            Payload::Plain(ref bytes) => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(bytes)?;
+                let data = encoder.finish()?;
+                Ok(if data.len() < bytes.len() { Some(Cow::Owned(data)) } else { None })
+            }
+            Payload::Deflated { ref data, .. } => Ok(Some(Cow::Borrowed(data))),
+        }
+    }
+}
+
+/// `len` comes off the wire, so it's only trusted as a limit: no more than one byte past it is
+/// ever inflated, and nothing is allocated up front.
+fn inflate(data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    DeflateDecoder::new(data).take((len as u64).saturating_add(1)).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(format_err!("deflated data doesn't match its length of {} bytes", len));
+    }
+    Ok(bytes)
+}
+
+impl PartialEq for EncodedData {
+    fn eq(&self, other: &EncodedData) -> bool {
+        match (&self.payload, &other.payload) {
+            (Payload::Plain(a), Payload::Plain(b)) => a == b,
+            _ => self.len() == other.len() && self.plain().ok() == other.plain().ok(),
+        }
+    }
+}
+
+impl serde::Serialize for EncodedData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let plain = || self.plain().map_err(ser::Error::custom);
+        match self.framing {
+            Framing::Json => serializer.collect_seq(plain()?.iter()),
+            Framing::Base64 => serializer.serialize_str(&base64::encode(&plain()?)),
+            Framing::Deflate => match self.deflated().map_err(ser::Error::custom)? {
+                Some(data) => {
+                    let mut map = serializer.serialize_struct("EncodedData", 2)?;
+                    map.serialize_field("deflate", &base64::encode(&data))?;
+                    map.serialize_field("len", &self.len())?;
+                    map.end()
+                }
+                None => serializer.serialize_str(&base64::encode(&plain()?)),
+            },
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for EncodedData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<EncodedData, D::Error> {
+        struct EncodedDataVisitor;
+
+        impl<'de> Visitor<'de> for EncodedDataVisitor {
+            type Value = EncodedData;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array of bytes, a base64 string or deflated data")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<EncodedData, E> {
+                let bytes = base64::decode(value).map_err(E::custom)?;
+                Ok(EncodedData::new(bytes, Framing::Base64))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<EncodedData, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(EncodedData::new(bytes, Framing::Json))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<EncodedData, A::Error> {
+                let mut data = None;
+                let mut len = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "deflate" => {
+                            let encoded: String = map.next_value()?;
+                            data = Some(base64::decode(&encoded).map_err(de::Error::custom)?);
+                        }
+                        "len" => len = Some(map.next_value()?),
+                        _ => return Err(de::Error::unknown_field(&key, &["deflate", "len"])),
+                    }
+                }
+                Ok(EncodedData {
+                    payload: Payload::Deflated {
+                        data: data.ok_or_else(|| de::Error::missing_field("deflate"))?,
+                        len: len.ok_or_else(|| de::Error::missing_field("len"))?,
+                    },
+                    framing: Framing::Deflate,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(EncodedDataVisitor)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PipeMessage {
     BeginRead,
     Data {
-        data: Vec<u8>,
+        data: EncodedData,
         end_offset: u64,
     },
     Read {
@@ -218,6 +458,11 @@ enum RemoteRequest {
         data: Vec<u8>,
     },
     Pipe(PipeEnvelope<usize>),
+    /// Answered with a `Pong` as soon as the remote's request loop sees it, to tell that it's
+    /// still there.
+    Ping {
+        id: usize,
+    },
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -228,6 +473,9 @@ pub enum RemoteResponse {
     CommandDone {
         id: ProcessId,
         exit_code: i64,
+        /// Missing from older backends, and for commands that aren't separate processes.
+        #[serde(default)]
+        usage: Option<ResourceUsage>,
     },
     DirectoryListing {
         id: usize,
@@ -240,9 +488,93 @@ pub enum RemoteResponse {
         data: Vec<u8>,
     },
     Pipe(PipeEnvelope<usize>),
+    Pong {
+        id: usize,
+    },
+    /// Something went wrong with `request`.  The remote carries on.  A `FailedRequest::Remote`
+    /// about a remote that's already running means the connection to it has gone.
+    Error {
+        request: FailedRequest,
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+/// What a `RemoteResponse::Error` is about, so the frontend can stop waiting for it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FailedRequest {
+    Command(ProcessId),
+    Remote(RemoteId),
+    ListDirectory(usize),
+    Ping(usize),
+    Edit(usize),
+    Pipe(GenericPipe),
+    /// A request that couldn't be read, or one not about anything in particular.
+    Other,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    /// The request made no sense, e.g. it named a pipe or remote that doesn't exist.  Usually a
+    /// bug, or a frontend and backend that disagree about the protocol.
+    InvalidRequest,
+    Io,
+    /// Nothing answered in time.  Only ever made locally, by `Endpoint::expire_calls`.
+    TimedOut,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn of(error: &Error) -> ErrorKind {
+        if let Some(e) = error.downcast_ref::<RequestError>() {
+            e.kind
+        } else if let Some(e) = error.downcast_ref::<io::Error>() {
+            ErrorKind::from(e)
+        } else {
+            ErrorKind::Other
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+impl From<&io::Error> for ErrorKind {
+    fn from(error: &io::Error) -> ErrorKind {
+        match error.kind() {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+            _ => ErrorKind::Io,
+        }
+    }
+}
+
+/// An error with a message fit for the user, and the `ErrorKind` to report it as.
+#[derive(Debug)]
+pub struct RequestError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn error(kind: ErrorKind, message: impl Into<String>) -> Error {
+        RequestError { kind, message: message.into() }.into()
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Error {
+        RequestError::error(ErrorKind::InvalidRequest, message)
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Fail for RequestError {}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RemoteId(usize);
 
 #[derive(Clone)]
@@ -272,10 +604,130 @@ struct ProcessState {
     parent: RemoteId,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// What a finished process used, from `wait4`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub wall_ms: u64,
+    pub user_ms: u64,
+    pub sys_ms: u64,
+    pub max_rss_kb: u64,
+    /// Filesystem blocks read and written.
+    pub blocks_in: u64,
+    pub blocks_out: u64,
+}
+
+/// Everything but the first three fields is missing from older backends.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct RemoteInfo {
     pub hostname: String,
     pub username: String,
     pub working_dir: String,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub home_dir: Option<String>,
+    /// As in `std::env::consts::OS`, e.g. `linux` or `macos`.
+    #[serde(default)]
+    pub os: Option<String>,
+    /// The kernel release, e.g. `4.15.0-36-generic`.
+    #[serde(default)]
+    pub kernel: Option<String>,
+    /// As in `std::env::consts::ARCH`, e.g. `x86_64`.
+    #[serde(default)]
+    pub arch: Option<String>,
+    /// The user's login shell.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// The backend's `$PATH`, split up.
+    #[serde(default)]
+    pub path: Vec<String>,
+    /// The backend's version.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Which `capability`s the backend supports.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
 }
 
+impl RemoteInfo {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// e.g. `linux-x86_64`, for picking a backend binary that will run there.
+    pub fn platform(&self) -> Option<String> {
+        match (&self.os, &self.arch) {
+            (Some(os), Some(arch)) => Some(format!("{}-{}", os, arch)),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors() {
+        let missing: Error = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+        assert_eq!(ErrorKind::of(&missing), ErrorKind::NotFound);
+        assert_eq!(ErrorKind::of(&RequestError::invalid("no remote 3")), ErrorKind::InvalidRequest);
+        assert_eq!(ErrorKind::of(&format_err!("oops")), ErrorKind::Other);
+
+        let request: Request = serde_json::from_str(
+            r#"{"remote_id":0,"message":{"ListDirectory":{"id":7,"path":"/nope"}}}"#).unwrap();
+        assert_eq!(request.failed_request(), FailedRequest::ListDirectory(7));
+    }
+
+    #[test]
+    fn framing() {
+        let data = EncodedData::new(b"hi\n".to_vec(), Framing::Json);
+        assert_eq!(serde_json::to_string(&data).unwrap(), "[104,105,10]");
+        let data = EncodedData::new(b"hi\n".to_vec(), Framing::Base64);
+        assert_eq!(serde_json::to_string(&data).unwrap(), r#""aGkK""#);
+
+        for text in &["[104,105,10]", r#""aGkK""#] {
+            let data: EncodedData = serde_json::from_str(text).unwrap();
+            assert_eq!(data.into_bytes().unwrap(), b"hi\n");
+        }
+        assert!(serde_json::from_str::<EncodedData>(r#""not base64!""#).is_err());
+
+        let plain = Hello { capabilities: vec![], ..Hello::ours("0.1.0") };
+        let compact = Hello { capabilities: vec![capability::BASE64_DATA.to_string()], ..plain.clone() };
+        assert_eq!(Framing::negotiate(&compact, &compact), Framing::Base64);
+        assert_eq!(Framing::negotiate(&compact, &plain), Framing::Json);
+    }
+
+    #[test]
+    fn compression() {
+        let log = b"Compiling protocol v0.1.0\n".repeat(100);
+        let text = serde_json::to_string(&EncodedData::new(log.clone(), Framing::Deflate)).unwrap();
+        assert!(text.starts_with(r#"{"deflate":"#) && text.len() < log.len() / 4, "{}", text);
+
+        let mut data: EncodedData = serde_json::from_str(&text).unwrap();
+        assert_eq!(data.framing, Framing::Deflate);
+        assert_eq!(data.len(), log.len());
+        // A hop that also compresses passes it on untouched...
+        assert_eq!(serde_json::to_string(&data).unwrap(), text);
+        // ...and one that doesn't inflates it first.
+        data.framing = Framing::Base64;
+        let inflated: EncodedData = serde_json::from_str(&serde_json::to_string(&data).unwrap()).unwrap();
+        assert_eq!(inflated.into_bytes().unwrap(), log);
+
+        // Data that doesn't shrink goes as base64.
+        let text = serde_json::to_string(&EncodedData::new(b"hi\n".to_vec(), Framing::Deflate)).unwrap();
+        assert_eq!(text, r#""aGkK""#);
+
+        let lying: EncodedData = serde_json::from_str(r#"{"deflate":"yyjkAgA=","len":10}"#).unwrap();
+        assert!(lying.into_bytes().is_err());
+
+        // A length too big to allocate, and a bomb that inflates far past what it claims.
+        let huge = format!(r#"{{"deflate":"yyjkAgA=","len":{}}}"#, usize::MAX);
+        assert!(serde_json::from_str::<EncodedData>(&huge).unwrap().into_bytes().is_err());
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&vec![0; 8 << 20]).unwrap();
+        let bomb = format!(r#"{{"deflate":"{}","len":4}}"#, base64::encode(&encoder.finish().unwrap()));
+        assert!(serde_json::from_str::<EncodedData>(&bomb).unwrap().into_bytes().is_err());
+    }
+}